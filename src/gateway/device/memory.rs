@@ -0,0 +1,50 @@
+use crate::gateway::device::PacketDevice;
+use bytes::Bytes;
+use std::io::{Error, ErrorKind, Result};
+use tokio::sync::{mpsc, Mutex};
+
+/// One end of an in-memory link. Packets written to one end are read from the other.
+#[derive(Debug)]
+pub struct MemoryDevice {
+    tx: mpsc::Sender<Bytes>,
+    rx: Mutex<mpsc::Receiver<Bytes>>,
+}
+
+impl MemoryDevice {
+    pub fn pair(capacity: usize) -> (Self, Self) {
+        let (a_tx, a_rx) = mpsc::channel(capacity);
+        let (b_tx, b_rx) = mpsc::channel(capacity);
+        (
+            Self {
+                tx: a_tx,
+                rx: b_rx.into(),
+            },
+            Self {
+                tx: b_tx,
+                rx: a_rx.into(),
+            },
+        )
+    }
+}
+
+impl PacketDevice for MemoryDevice {
+    async fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        let packet = self
+            .rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(Error::new(ErrorKind::BrokenPipe, "Memory device peer closed"))?;
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    async fn write(&self, packet: &[u8]) -> Result<()> {
+        self.tx
+            .send(Bytes::copy_from_slice(packet))
+            .await
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Memory device peer closed"))
+    }
+}
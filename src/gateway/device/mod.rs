@@ -0,0 +1,19 @@
+mod memory;
+#[cfg(target_os = "linux")]
+mod tun;
+
+pub use memory::*;
+#[cfg(target_os = "linux")]
+pub use tun::*;
+
+use std::future::Future;
+use std::io::Result;
+
+/// A layer-3 device exchanging whole IP packets, such as a TUN interface.
+pub trait PacketDevice: Send + Sync + 'static {
+    /// Reads one packet into `buf`, returning its length.
+    fn read(&self, buf: &mut [u8]) -> impl Future<Output = Result<usize>> + Send;
+
+    /// Writes one complete packet.
+    fn write(&self, packet: &[u8]) -> impl Future<Output = Result<()>> + Send;
+}
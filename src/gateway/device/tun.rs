@@ -0,0 +1,91 @@
+use crate::gateway::device::PacketDevice;
+use std::ffi::CStr;
+use std::io::{Error, ErrorKind, Result};
+use std::mem::zeroed;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+
+/// A Linux TUN interface opened without packet information, so reads and writes
+/// carry bare IP packets. Addresses, MTU and link state are left to the system.
+#[derive(Debug)]
+pub struct TunDevice {
+    fd: AsyncFd<OwnedFd>,
+    name: String,
+}
+
+impl TunDevice {
+    /// Opens or creates the interface `name`. An empty name lets the kernel pick one.
+    pub fn open(name: &str) -> Result<Self> {
+        let mut req: libc::ifreq = unsafe { zeroed() };
+        if name.len() >= req.ifr_name.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("TUN device name too long: {}", name),
+            ));
+        }
+        for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+
+        let fd = unsafe {
+            libc::open(
+                c"/dev/net/tun".as_ptr(),
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut req) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let name = unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            name,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PacketDevice for TunDevice {
+    async fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let res = guard.try_io(|fd| {
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+            if let Ok(res) = res {
+                return res;
+            }
+        }
+    }
+
+    async fn write(&self, packet: &[u8]) -> Result<()> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            let res = guard.try_io(|fd| {
+                let n = unsafe { libc::write(fd.as_raw_fd(), packet.as_ptr().cast(), packet.len()) };
+                if n < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(())
+            });
+            if let Ok(res) = res {
+                return res;
+            }
+        }
+    }
+}
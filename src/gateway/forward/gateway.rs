@@ -0,0 +1,280 @@
+use crate::gateway::device::PacketDevice;
+use crate::gateway::forward::header::{StreamHeader, TargetAddr, DATAGRAM_FLOW, DATAGRAM_PACKET};
+use crate::gateway::forward::tunnel;
+use crate::gateway::forward::tunnel::IpRoutes;
+use crate::gateway::forward::reverse::{serve_control, ControlClient, ReverseAuthorizer};
+use crate::gateway::forward::socks::{serve_associate, serve_connect};
+use crate::gateway::forward::udp::{forward, serve_flow, UdpFlows, DEFAULT_FLOW_IDLE_TIMEOUT};
+use crate::gateway::quic::{
+    PeerIdentity, QuicDatagram, QuicDatagramRx, QuicEndpoint, QuicStream, QuicStreamRx,
+};
+use bytes::Buf;
+use dashmap::DashMap;
+use derive_more::Debug;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, info, trace};
+
+const TUNNEL_QUEUE_CAPACITY: usize = 1024;
+
+/// Decides whether a peer may have this side reach a target, given the peer's address, its
+/// identity over TLS, and the target of a SOCKS5 request, UDP ASSOCIATE datagram or UDP flow.
+pub type TargetAuthorizer =
+    Arc<dyn Fn(SocketAddr, Option<&PeerIdentity>, &TargetAddr) -> bool + Send + Sync>;
+
+/// Dispatches incoming gateway streams by their [`StreamHeader`] and keeps
+/// track of the reverse forwards this side has registered on its peers.
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    endpoint: Arc<QuicEndpoint>,
+    controls: Arc<DashMap<SocketAddr, Arc<Mutex<ControlClient>>>>,
+    reverse: Arc<DashMap<(SocketAddr, u16), SocketAddr>>,
+    #[debug(skip)]
+    reverse_authorizer: Option<ReverseAuthorizer>,
+    #[debug(skip)]
+    target_authorizer: Option<TargetAuthorizer>,
+    flows: Arc<UdpFlows>,
+    flow_idle_timeout: Duration,
+    packets: Arc<parking_lot::Mutex<Option<mpsc::Sender<QuicDatagram>>>>,
+}
+
+impl Gateway {
+    pub fn new(endpoint: Arc<QuicEndpoint>) -> Self {
+        Self {
+            endpoint,
+            controls: DashMap::new().into(),
+            reverse: DashMap::new().into(),
+            reverse_authorizer: None,
+            target_authorizer: None,
+            flows: Default::default(),
+            flow_idle_timeout: DEFAULT_FLOW_IDLE_TIMEOUT,
+            packets: Default::default(),
+        }
+    }
+
+    pub fn endpoint(&self) -> &Arc<QuicEndpoint> {
+        &self.endpoint
+    }
+
+    /// Sets which reverse forwards peers may register on this side. Without an
+    /// authorizer every registration is refused.
+    pub fn set_reverse_authorizer(&mut self, authorizer: Option<ReverseAuthorizer>) {
+        self.reverse_authorizer = authorizer;
+    }
+
+    /// Sets which targets peers may reach through this side, over SOCKS5 CONNECT and UDP
+    /// ASSOCIATE or UDP flows. Without an authorizer every target is refused.
+    pub fn set_target_authorizer(&mut self, authorizer: Option<TargetAuthorizer>) {
+        self.target_authorizer = authorizer;
+    }
+
+    /// Sets how long UDP flows peers opened through this side live without traffic.
+    /// Defaults to [`DEFAULT_FLOW_IDLE_TIMEOUT`].
+    pub fn set_flow_idle_timeout(&mut self, timeout: Duration) {
+        self.flow_idle_timeout = timeout;
+    }
+
+    pub async fn run(&self, mut streams: QuicStreamRx) {
+        while let Some(stream) = streams.recv().await {
+            let gateway = self.clone();
+            tokio::spawn(async move {
+                let peer = stream.remote_address();
+                if let Err(e) = gateway.serve(stream).await {
+                    debug!("Gateway stream from {:?} ended: {:?}", peer, e);
+                }
+            });
+        }
+    }
+
+    pub async fn run_datagrams(&self, mut datagrams: QuicDatagramRx) {
+        while let Some(QuicDatagram { addr, mut payload }) = datagrams.recv().await {
+            if payload.is_empty() {
+                continue;
+            }
+            let res = match payload.get_u8() {
+                DATAGRAM_FLOW => self.flows.dispatch(addr, payload),
+                DATAGRAM_PACKET => match self.packets.lock().as_ref() {
+                    Some(tx) => tx
+                        .try_send(QuicDatagram::new(addr, payload))
+                        .map_err(|e| Error::other(format!("IP tunnel congested: {:?}", e))),
+                    None => Err(Error::new(ErrorKind::NotConnected, "No IP tunnel running")),
+                },
+                kind => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown datagram kind: {:#04x}", kind),
+                )),
+            };
+            if let Err(e) = res {
+                trace!("Dropping datagram from {:?}: {:?}", addr, e);
+            }
+        }
+    }
+
+    async fn serve(&self, mut stream: QuicStream) -> Result<()> {
+        match StreamHeader::read(&mut stream).await? {
+            StreamHeader::Control => {
+                serve_control(self.endpoint.clone(), self.reverse_authorizer.clone(), stream).await
+            }
+            StreamHeader::Reverse { port } => self.serve_reverse(stream, port).await,
+            StreamHeader::Connect(target) => {
+                let allowed = self.allows(&stream, &target);
+                serve_connect(stream, target, allowed).await
+            }
+            StreamHeader::Associate => {
+                let (peer, identity) = (stream.remote_address(), stream.peer_identity());
+                let allows = |target: &TargetAddr| self.allows_peer(peer, identity.as_deref(), target);
+                serve_associate(stream, allows).await
+            }
+            StreamHeader::Flow { id, target } => {
+                let allowed = self.allows(&stream, &target);
+                let (endpoint, flows) = (self.endpoint.clone(), self.flows.clone());
+                serve_flow(endpoint, flows, stream, id, target, allowed, self.flow_idle_timeout).await
+            }
+        }
+    }
+
+    fn allows(&self, stream: &QuicStream, target: &TargetAddr) -> bool {
+        let peer = stream.remote_address();
+        let allowed = self.allows_peer(peer, stream.peer_identity().as_deref(), target);
+        if !allowed {
+            info!("Target {:?} denied for {:?}", target, peer);
+        }
+        allowed
+    }
+
+    fn allows_peer(&self, peer: SocketAddr, identity: Option<&PeerIdentity>, target: &TargetAddr) -> bool {
+        self.target_authorizer
+            .as_ref()
+            .is_some_and(|authorize| authorize(peer, identity, target))
+    }
+
+    async fn serve_reverse(&self, mut stream: QuicStream, port: u16) -> Result<()> {
+        let server = stream.remote_address();
+        let mut target = self.reverse.get(&(server, port)).map(|entry| *entry);
+        if target.is_none() {
+            // The server listens before it answers the registration, so wait for the pending
+            // forward_remote to record the mapping and look again.
+            let control = self.controls.get(&server).map(|control| control.clone());
+            if let Some(control) = control {
+                drop(control.lock().await);
+                target = self.reverse.get(&(server, port)).map(|entry| *entry);
+            }
+        }
+        let target = target.ok_or(Error::new(
+                ErrorKind::NotFound,
+                format!("No reverse forward on port {} of {:?}", port, server),
+            ))?;
+        trace!("Reverse forward {:?}:{} -> {:?}", server, port, target);
+        let mut tcp = TcpStream::connect(target).await?;
+        copy_bidirectional(&mut stream, &mut tcp).await?;
+        Ok(())
+    }
+
+    /// Carries every UDP flow arriving on `socket`, keyed by source address, to `target`
+    /// through `peer`. Flows are dropped after `idle_timeout` without traffic, or earlier
+    /// once `peer` drops them after its own [`Self::set_flow_idle_timeout`].
+    pub async fn forward_udp(
+        &self,
+        socket: UdpSocket,
+        peer: SocketAddr,
+        target: TargetAddr,
+        idle_timeout: Duration,
+    ) -> Result<()> {
+        forward(
+            self.endpoint.clone(),
+            self.flows.clone(),
+            socket,
+            peer,
+            target,
+            idle_timeout,
+        )
+        .await
+    }
+
+    /// Runs an IP tunnel between `device` and the peers in `routes`, carrying packets as
+    /// datagrams. Only one tunnel runs per gateway; starting another while one runs fails
+    /// with [`ErrorKind::AddrInUse`].
+    pub async fn tunnel<D: PacketDevice>(&self, device: D, routes: IpRoutes) -> Result<()> {
+        let (tx, rx) = mpsc::channel(TUNNEL_QUEUE_CAPACITY);
+        let _registration = {
+            let mut packets = self.packets.lock();
+            // A registration whose receiver is gone belongs to a tunnel that ended, replace it.
+            if packets.as_ref().is_some_and(|tx| !tx.is_closed()) {
+                return Err(Error::new(ErrorKind::AddrInUse, "An IP tunnel is already running"));
+            }
+            *packets = Some(tx.clone());
+            TunnelRegistration {
+                packets: self.packets.clone(),
+                tx,
+            }
+        };
+        tunnel::run(&self.endpoint, device, routes, rx).await
+    }
+
+    async fn control(&self, server: SocketAddr) -> Result<Arc<Mutex<ControlClient>>> {
+        if let Some(control) = self.controls.get(&server) {
+            return Ok(control.clone());
+        }
+        let control = Arc::new(Mutex::new(
+            ControlClient::open(&self.endpoint, server).await?,
+        ));
+        Ok(self.controls.entry(server).or_insert(control).clone())
+    }
+
+    /// Makes `server` listen on `bind` and forward every accepted connection back
+    /// to `target` on this side, like `ssh -R`. Returns the port bound on `server`.
+    /// `server` must allow it through [`Self::set_reverse_authorizer`].
+    pub async fn forward_remote(
+        &self,
+        server: SocketAddr,
+        bind: SocketAddr,
+        target: SocketAddr,
+    ) -> Result<u16> {
+        let control = self.control(server).await?;
+        let mut control = control.lock().await;
+        let port = control.register(bind).await.inspect_err(|e| {
+            if e.kind() != ErrorKind::AddrNotAvailable {
+                self.controls.remove(&server);
+            }
+        })?;
+        self.reverse.insert((server, port), target);
+        Ok(port)
+    }
+
+    /// Removes a reverse forward [`Self::forward_remote`] registered on `server`.
+    pub async fn cancel_remote(&self, server: SocketAddr, port: u16) -> Result<()> {
+        self.reverse.remove(&(server, port));
+        // A server drops all forwards of a control stream that broke, so none is reopened.
+        let control = self
+            .controls
+            .get(&server)
+            .map(|control| control.clone())
+            .ok_or(Error::new(
+                ErrorKind::NotFound,
+                format!("No reverse forwards registered on {:?}", server),
+            ))?;
+        let mut control = control.lock().await;
+        control.remove(port).await
+    }
+}
+
+/// Clears the tunnel's sender once the tunnel ends or is dropped, unless a later tunnel
+/// registered its own in the meantime.
+struct TunnelRegistration {
+    packets: Arc<parking_lot::Mutex<Option<mpsc::Sender<QuicDatagram>>>>,
+    tx: mpsc::Sender<QuicDatagram>,
+}
+
+impl Drop for TunnelRegistration {
+    fn drop(&mut self) {
+        let mut packets = self.packets.lock();
+        if packets.as_ref().is_some_and(|tx| tx.same_channel(&self.tx)) {
+            *packets = None;
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use bytes::{BufMut, BytesMut};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{lookup_host, TcpStream};
+
+const HEADER_CONTROL: u8 = 0x01;
+const HEADER_REVERSE: u8 = 0x02;
+const HEADER_CONNECT: u8 = 0x03;
+const HEADER_ASSOCIATE: u8 = 0x04;
+const HEADER_FLOW: u8 = 0x05;
+
+pub(super) const DATAGRAM_FLOW: u8 = 0x01;
+pub(super) const DATAGRAM_PACKET: u8 = 0x02;
+
+// Address kinds share their values with SOCKS5 `ATYP`.
+pub(super) const ADDR_IPV4: u8 = 0x01;
+pub(super) const ADDR_DOMAIN: u8 = 0x03;
+pub(super) const ADDR_IPV6: u8 = 0x04;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    pub fn port(&self) -> u16 {
+        match self {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        }
+    }
+
+    pub(super) fn encode_into(&self, buf: &mut BytesMut) -> Result<()> {
+        match self {
+            TargetAddr::Ip(addr) => put_addr(buf, *addr),
+            TargetAddr::Domain(host, port) => {
+                let len = u8::try_from(host.len()).map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Host name longer than 255 bytes: {}", host),
+                    )
+                })?;
+                buf.put_u8(ADDR_DOMAIN);
+                buf.put_u8(len);
+                buf.put_slice(host.as_bytes());
+                buf.put_u16(*port);
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let ip = match reader.read_u8().await? {
+            ADDR_IPV4 => {
+                let mut octets = [0u8; 4];
+                reader.read_exact(&mut octets).await?;
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            ADDR_IPV6 => {
+                let mut octets = [0u8; 16];
+                reader.read_exact(&mut octets).await?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            ADDR_DOMAIN => {
+                let mut host = vec![0u8; reader.read_u8().await? as usize];
+                reader.read_exact(&mut host).await?;
+                let host = String::from_utf8(host)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                return Ok(TargetAddr::Domain(host, reader.read_u16().await?));
+            }
+            kind => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown address kind: {:#04x}", kind),
+                ));
+            }
+        };
+        Ok(TargetAddr::Ip(SocketAddr::new(ip, reader.read_u16().await?)))
+    }
+
+    pub(super) async fn resolve(&self) -> Result<SocketAddr> {
+        match self {
+            TargetAddr::Ip(addr) => Ok(*addr),
+            TargetAddr::Domain(host, port) => lookup_host((host.as_str(), *port))
+                .await?
+                .next()
+                .ok_or(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Failed to resolve {}", host),
+                )),
+        }
+    }
+
+    pub(super) async fn connect(&self) -> Result<TcpStream> {
+        match self {
+            TargetAddr::Ip(addr) => TcpStream::connect(addr).await,
+            TargetAddr::Domain(host, port) => TcpStream::connect((host.as_str(), *port)).await,
+        }
+    }
+}
+
+impl From<SocketAddr> for TargetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        TargetAddr::Ip(addr)
+    }
+}
+
+/// First bytes written on every gateway stream, telling the accepting side what the stream carries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamHeader {
+    /// Reverse forwarding control stream, opened by the client towards the server.
+    Control,
+    /// Connection accepted by the server on a reverse forward bound at `port`.
+    Reverse { port: u16 },
+    /// TCP connection to `target`, answered with a one byte SOCKS5 reply code.
+    Connect(TargetAddr),
+    /// UDP relay, carrying datagrams framed as `[target][len: u16][payload]`.
+    Associate,
+    /// UDP flow `id` towards `target`. Payloads travel as datagrams, the stream carries
+    /// those that do not fit, framed as `[len: u16][payload]`.
+    Flow { id: u32, target: TargetAddr },
+}
+
+impl StreamHeader {
+    /// Fails with [`ErrorKind::InvalidInput`] if a domain target does not fit the header.
+    pub fn encode(&self) -> Result<BytesMut> {
+        let mut buf = BytesMut::with_capacity(3);
+        match self {
+            StreamHeader::Control => buf.put_u8(HEADER_CONTROL),
+            StreamHeader::Reverse { port } => {
+                buf.put_u8(HEADER_REVERSE);
+                buf.put_u16(*port);
+            }
+            StreamHeader::Connect(target) => {
+                buf.put_u8(HEADER_CONNECT);
+                target.encode_into(&mut buf)?;
+            }
+            StreamHeader::Associate => buf.put_u8(HEADER_ASSOCIATE),
+            StreamHeader::Flow { id, target } => {
+                buf.put_u8(HEADER_FLOW);
+                buf.put_u32(*id);
+                target.encode_into(&mut buf)?;
+            }
+        }
+        Ok(buf)
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        match reader.read_u8().await? {
+            HEADER_CONTROL => Ok(StreamHeader::Control),
+            HEADER_REVERSE => Ok(StreamHeader::Reverse {
+                port: reader.read_u16().await?,
+            }),
+            HEADER_CONNECT => Ok(StreamHeader::Connect(TargetAddr::read(reader).await?)),
+            HEADER_ASSOCIATE => Ok(StreamHeader::Associate),
+            HEADER_FLOW => Ok(StreamHeader::Flow {
+                id: reader.read_u32().await?,
+                target: TargetAddr::read(reader).await?,
+            }),
+            kind => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown stream header kind: {:#04x}", kind),
+            )),
+        }
+    }
+}
+
+/// Length prefix of a `[len: u16][payload]` frame.
+pub(super) fn frame_len(payload: &[u8]) -> Result<u16> {
+    u16::try_from(payload.len()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Frame payload of {} bytes exceeds 65535", payload.len()),
+        )
+    })
+}
+
+pub(super) fn put_addr(buf: &mut BytesMut, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.put_u8(ADDR_IPV4);
+            buf.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.put_u8(ADDR_IPV6);
+            buf.put_slice(&ip.octets());
+        }
+    }
+    buf.put_u16(addr.port());
+}
+
+pub(super) async fn read_addr<R: AsyncRead + Unpin>(reader: &mut R) -> Result<SocketAddr> {
+    match TargetAddr::read(reader).await? {
+        TargetAddr::Ip(addr) => Ok(addr),
+        TargetAddr::Domain(host, _) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Expected an IP address, got domain {}", host),
+        )),
+    }
+}
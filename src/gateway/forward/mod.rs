@@ -0,0 +1,13 @@
+mod header;
+mod reverse;
+mod socks;
+mod udp;
+mod tunnel;
+mod gateway;
+
+pub use header::*;
+pub use gateway::*;
+pub use reverse::ReverseAuthorizer;
+pub use socks::Socks5Server;
+pub use udp::DEFAULT_FLOW_IDLE_TIMEOUT;
+pub use tunnel::{IpPrefix, IpRoutes};
@@ -0,0 +1,184 @@
+use crate::gateway::forward::header::{put_addr, read_addr, StreamHeader};
+use crate::gateway::quic::{PeerIdentity, QuicEndpoint, QuicStream};
+use bytes::{BufMut, BytesMut};
+use derive_more::{Deref, DerefMut};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, trace};
+
+const CONTROL_REGISTER: u8 = 0x01;
+const CONTROL_REMOVE: u8 = 0x02;
+
+const CONTROL_OK: u8 = 0x00;
+const CONTROL_FAILED: u8 = 0x01;
+const CONTROL_DENIED: u8 = 0x02;
+
+/// Decides whether a peer may make this side listen on an address, given the peer's
+/// address, its identity over TLS, and the requested bind address.
+pub type ReverseAuthorizer =
+    Arc<dyn Fn(SocketAddr, Option<&PeerIdentity>, SocketAddr) -> bool + Send + Sync>;
+
+/// Client half of a reverse forwarding control stream.
+#[derive(Debug)]
+pub(super) struct ControlClient {
+    stream: QuicStream,
+}
+
+impl ControlClient {
+    pub(super) async fn open(endpoint: &QuicEndpoint, server: SocketAddr) -> Result<Self> {
+        let stream = endpoint
+            .open(server, Some(StreamHeader::Control.encode()?))
+            .await?;
+        Ok(Self { stream })
+    }
+
+    /// Asks the server to listen on `bind`, returning the port it actually bound.
+    pub(super) async fn register(&mut self, bind: SocketAddr) -> Result<u16> {
+        let mut buf = BytesMut::with_capacity(20);
+        buf.put_u8(CONTROL_REGISTER);
+        put_addr(&mut buf, bind);
+        self.stream.write_all(&buf).await?;
+        self.reply().await
+    }
+
+    pub(super) async fn remove(&mut self, port: u16) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(3);
+        buf.put_u8(CONTROL_REMOVE);
+        buf.put_u16(port);
+        self.stream.write_all(&buf).await?;
+        self.reply().await.map(|_| ())
+    }
+
+    async fn reply(&mut self) -> Result<u16> {
+        let status = self.stream.read_u8().await?;
+        let port = self.stream.read_u16().await?;
+        match status {
+            CONTROL_OK => Ok(port),
+            CONTROL_DENIED => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("Reverse forward on port {} denied by server", port),
+            )),
+            _ => Err(Error::new(
+                ErrorKind::AddrNotAvailable,
+                format!("Reverse forward on port {} rejected by server", port),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deref, DerefMut)]
+struct Listeners(HashMap<u16, JoinHandle<()>>);
+
+impl Drop for Listeners {
+    fn drop(&mut self) {
+        for (_, task) in self.0.drain() {
+            task.abort();
+        }
+    }
+}
+
+/// Server half of a control stream. Every forward registered through it lives
+/// until it is removed or the control stream goes away. Registrations `authorizer`
+/// does not allow, or all of them without one, are refused.
+pub(super) async fn serve_control(
+    endpoint: Arc<QuicEndpoint>,
+    authorizer: Option<ReverseAuthorizer>,
+    mut stream: QuicStream,
+) -> Result<()> {
+    let peer = stream.remote_address();
+    let identity = stream.peer_identity();
+    let mut listeners = Listeners::default();
+
+    loop {
+        let op = match stream.read_u8().await {
+            Ok(op) => op,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+
+        let (status, port) = match op {
+            CONTROL_REGISTER => {
+                let bind = read_addr(&mut stream).await?;
+                let allowed = authorizer
+                    .as_ref()
+                    .is_some_and(|authorize| authorize(peer, identity.as_deref(), bind));
+                if !allowed {
+                    info!("Reverse forward {:?} denied for {:?}", bind, peer);
+                    (CONTROL_DENIED, bind.port())
+                } else {
+                    match TcpListener::bind(bind).await.and_then(|l| Ok((l.local_addr()?.port(), l))) {
+                        Ok((port, listener)) => {
+                            info!("Reverse forward {:?} registered by {:?}", bind, peer);
+                            let task = tokio::spawn(listen(endpoint.clone(), peer, port, listener));
+                            if let Some(old) = listeners.insert(port, task) {
+                                old.abort();
+                            }
+                            (CONTROL_OK, port)
+                        }
+                        Err(e) => {
+                            error!("Failed to bind reverse forward {:?} for {:?}: {:?}", bind, peer, e);
+                            (CONTROL_FAILED, bind.port())
+                        }
+                    }
+                }
+            }
+            CONTROL_REMOVE => {
+                let port = stream.read_u16().await?;
+                match listeners.remove(&port) {
+                    Some(task) => {
+                        task.abort();
+                        info!("Reverse forward on port {} removed by {:?}", port, peer);
+                        (CONTROL_OK, port)
+                    }
+                    None => (CONTROL_FAILED, port),
+                }
+            }
+            op => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown control operation: {:#04x}", op),
+                ));
+            }
+        };
+
+        let mut buf = BytesMut::with_capacity(3);
+        buf.put_u8(status);
+        buf.put_u16(port);
+        stream.write_all(&buf).await?;
+    }
+
+    debug!("Control stream from {:?} closed, dropping {} forwards", peer, listeners.len());
+    Ok(())
+}
+
+async fn listen(endpoint: Arc<QuicEndpoint>, peer: SocketAddr, port: u16, listener: TcpListener) {
+    loop {
+        let (mut tcp, from) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Reverse forward on port {} failed to accept: {:?}", port, e);
+                break;
+            }
+        };
+        trace!("Reverse forward on port {} accepted {:?}", port, from);
+
+        let endpoint = endpoint.clone();
+        tokio::spawn(async move {
+            let res = async {
+                let mut stream = endpoint
+                    .open(peer, Some(StreamHeader::Reverse { port }.encode()?))
+                    .await?;
+                copy_bidirectional(&mut tcp, &mut stream).await
+            }
+            .await;
+            if let Err(e) = res {
+                debug!("Reverse forward on port {} from {:?} ended: {:?}", port, from, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,303 @@
+use crate::gateway::forward::header::{frame_len, put_addr, StreamHeader, TargetAddr};
+use crate::gateway::quic::{QuicEndpoint, QuicStream};
+use bytes::{BufMut, BytesMut};
+use derive_more::Constructor;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use tokio::io::{copy_bidirectional, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::select;
+use tracing::{debug, trace};
+
+const SOCKS_VERSION: u8 = 0x05;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_TTL_EXPIRED: u8 = 0x06;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+const UDP_BUFFER_SIZE: usize = 65536;
+
+fn reply_code(e: &Error) -> u8 {
+    match e.kind() {
+        ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        ErrorKind::HostUnreachable | ErrorKind::NotFound => REPLY_HOST_UNREACHABLE,
+        ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        ErrorKind::TimedOut => REPLY_TTL_EXPIRED,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+/// SOCKS5 front-end: every request is carried to `peer` on its own QUIC stream,
+/// and the gateway on `peer` dials the actual target.
+#[derive(Debug, Clone, Constructor)]
+pub struct Socks5Server {
+    endpoint: Arc<QuicEndpoint>,
+    peer: SocketAddr,
+    udp_associate: bool,
+}
+
+impl Socks5Server {
+    pub async fn run(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (tcp, from) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve(tcp).await {
+                    debug!("SOCKS5 session from {:?} ended: {:?}", from, e);
+                }
+            });
+        }
+    }
+
+    async fn serve(&self, mut tcp: TcpStream) -> Result<()> {
+        if tcp.read_u8().await? != SOCKS_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a SOCKS5 client"));
+        }
+        let mut methods = vec![0u8; tcp.read_u8().await? as usize];
+        tcp.read_exact(&mut methods).await?;
+        if !methods.contains(&METHOD_NO_AUTH) {
+            tcp.write_all(&[SOCKS_VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "SOCKS5 client requires authentication",
+            ));
+        }
+        tcp.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).await?;
+
+        let mut request = [0u8; 3];
+        tcp.read_exact(&mut request).await?;
+        if request[0] != SOCKS_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected SOCKS version in request: {:#04x}", request[0]),
+            ));
+        }
+        let target = TargetAddr::read(&mut tcp).await?;
+        match request[1] {
+            CMD_CONNECT => self.connect(tcp, target).await,
+            CMD_UDP_ASSOCIATE if self.udp_associate => self.associate(tcp, target).await,
+            cmd => {
+                write_reply(&mut tcp, REPLY_COMMAND_NOT_SUPPORTED, None).await?;
+                Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("Unsupported SOCKS5 command: {:#04x}", cmd),
+                ))
+            }
+        }
+    }
+
+    async fn connect(&self, mut tcp: TcpStream, target: TargetAddr) -> Result<()> {
+        trace!("SOCKS5 CONNECT {:?} via {:?}", target, self.peer);
+        let res = async {
+            let mut stream = self
+                .endpoint
+                .open(self.peer, Some(StreamHeader::Connect(target.clone()).encode()?))
+                .await?;
+            let reply = stream.read_u8().await?;
+            Ok::<_, Error>((stream, reply))
+        }
+        .await;
+
+        let mut stream = match res {
+            Ok((stream, REPLY_SUCCEEDED)) => stream,
+            Ok((_, reply)) => {
+                write_reply(&mut tcp, reply, None).await?;
+                return Err(Error::other(format!(
+                    "Gateway failed to connect to {:?}: reply {:#04x}",
+                    target, reply
+                )));
+            }
+            Err(e) => {
+                write_reply(&mut tcp, REPLY_GENERAL_FAILURE, None).await?;
+                return Err(e);
+            }
+        };
+        write_reply(&mut tcp, REPLY_SUCCEEDED, None).await?;
+        copy_bidirectional(&mut tcp, &mut stream).await?;
+        Ok(())
+    }
+
+    /// Relays datagrams of the client at `source`, the address the request names. Zeroes
+    /// in it stand for the address of the TCP connection and for any port.
+    async fn associate(&self, mut tcp: TcpStream, source: TargetAddr) -> Result<()> {
+        let source_ip = match source {
+            TargetAddr::Ip(addr) if !addr.ip().is_unspecified() => addr.ip().to_canonical(),
+            _ => tcp.peer_addr()?.ip().to_canonical(),
+        };
+        let udp = UdpSocket::bind((tcp.local_addr()?.ip(), 0)).await?;
+        let stream = match self
+            .endpoint
+            .open(self.peer, Some(StreamHeader::Associate.encode()?))
+            .await
+        {
+            Ok(stream) => stream,
+            Err(e) => {
+                write_reply(&mut tcp, REPLY_GENERAL_FAILURE, None).await?;
+                return Err(e);
+            }
+        };
+        write_reply(&mut tcp, REPLY_SUCCEEDED, Some(udp.local_addr()?)).await?;
+        trace!("SOCKS5 UDP ASSOCIATE on {:?} via {:?}", udp.local_addr()?, self.peer);
+
+        let (mut rx, mut tx) = split(stream);
+        let client = OnceLock::new();
+
+        let uplink = async {
+            let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+            loop {
+                let (n, from) = udp.recv_from(&mut buf).await?;
+                let expected = from.ip().to_canonical() == source_ip
+                    && (source.port() == 0 || source.port() == from.port());
+                if !expected || *client.get_or_init(|| from) != from {
+                    continue;
+                }
+                // RSV(2) FRAG(1), fragmented datagrams are not supported.
+                if n < 4 || buf[2] != 0 {
+                    continue;
+                }
+                let mut payload = &buf[3..n];
+                let target = match TargetAddr::read(&mut payload).await {
+                    Ok(target) => target,
+                    Err(e) => {
+                        trace!("Dropping malformed SOCKS5 datagram from {:?}: {:?}", from, e);
+                        continue;
+                    }
+                };
+                write_frame(&mut tx, &target, payload).await?;
+            }
+        };
+
+        let downlink = async {
+            loop {
+                let (from, payload) = read_frame(&mut rx).await?;
+                let Some(client) = client.get() else {
+                    continue;
+                };
+                let mut packet = BytesMut::with_capacity(payload.len() + 22);
+                packet.put_slice(&[0, 0, 0]);
+                from.encode_into(&mut packet)?;
+                packet.put_slice(&payload);
+                udp.send_to(&packet, client).await?;
+            }
+        };
+
+        // The association lives as long as the TCP connection that requested it.
+        let control = async {
+            let mut buf = [0u8; 1];
+            while tcp.read(&mut buf).await? != 0 {}
+            Ok::<_, Error>(())
+        };
+
+        select! {
+            res = uplink => res,
+            res = downlink => res,
+            res = control => res,
+        }
+    }
+}
+
+async fn write_reply(tcp: &mut TcpStream, reply: u8, bind: Option<SocketAddr>) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(22);
+    buf.put_slice(&[SOCKS_VERSION, reply, 0]);
+    put_addr(&mut buf, bind.unwrap_or((Ipv4Addr::UNSPECIFIED, 0).into()));
+    tcp.write_all(&buf).await
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, target: &TargetAddr, payload: &[u8]) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(payload.len() + 24);
+    target.encode_into(&mut buf)?;
+    buf.put_u16(frame_len(payload)?);
+    buf.put_slice(payload);
+    writer.write_all(&buf).await
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(TargetAddr, Vec<u8>)> {
+    let target = TargetAddr::read(reader).await?;
+    let mut payload = vec![0u8; reader.read_u16().await? as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok((target, payload))
+}
+
+/// Remote half of [`StreamHeader::Connect`]. Targets the gateway does not allow are
+/// refused with [`REPLY_NOT_ALLOWED`].
+pub(super) async fn serve_connect(mut stream: QuicStream, target: TargetAddr, allowed: bool) -> Result<()> {
+    if !allowed {
+        stream.write_u8(REPLY_NOT_ALLOWED).await?;
+        stream.shutdown().await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Connecting to {:?} is not allowed", target),
+        ));
+    }
+    match target.connect().await {
+        Ok(mut tcp) => {
+            trace!("Gateway connected to {:?}", target);
+            stream.write_u8(REPLY_SUCCEEDED).await?;
+            copy_bidirectional(&mut stream, &mut tcp).await?;
+            Ok(())
+        }
+        Err(e) => {
+            stream.write_u8(reply_code(&e)).await?;
+            stream.shutdown().await?;
+            Err(e)
+        }
+    }
+}
+
+/// Remote half of [`StreamHeader::Associate`]. Datagrams to targets `allows` refuses
+/// are dropped.
+pub(super) async fn serve_associate(stream: QuicStream, allows: impl Fn(&TargetAddr) -> bool) -> Result<()> {
+    let udp = match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await {
+        Ok(udp) => udp,
+        Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+    };
+    let dual_stack = udp.local_addr()?.is_ipv6();
+    let (mut rx, mut tx) = split(stream);
+
+    let uplink = async {
+        loop {
+            let (target, payload) = read_frame(&mut rx).await?;
+            if !allows(&target) {
+                debug!("Dropping UDP datagram to {:?}: not allowed", target);
+                continue;
+            }
+            let mut addr = match target.resolve().await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug!("Dropping UDP datagram to {:?}: {:?}", target, e);
+                    continue;
+                }
+            };
+            if let (true, IpAddr::V4(ip)) = (dual_stack, addr.ip()) {
+                addr.set_ip(ip.to_ipv6_mapped().into());
+            }
+            udp.send_to(&payload, addr).await?;
+        }
+    };
+
+    let downlink = async {
+        let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+        loop {
+            let (n, mut from) = udp.recv_from(&mut buf).await?;
+            from.set_ip(from.ip().to_canonical());
+            write_frame(&mut tx, &from.into(), &buf[..n]).await?;
+        }
+    };
+
+    select! {
+        res = uplink => res,
+        res = downlink => res,
+    }
+}
@@ -0,0 +1,155 @@
+use crate::gateway::device::PacketDevice;
+use crate::gateway::forward::header::DATAGRAM_PACKET;
+use crate::gateway::quic::{QuicDatagram, QuicEndpoint};
+use bytes::{BufMut, BytesMut};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::select;
+use tokio::sync::mpsc;
+use tracing::trace;
+
+const PACKET_BUFFER_SIZE: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    pub fn new(addr: IpAddr, len: u8) -> Result<Self> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if len > max {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Prefix length {} out of range for {}", len, addr),
+            ));
+        }
+        // Clear the host bits, so that 10.0.0.1/8 and 10.0.0.0/8 are the same route.
+        let addr = match addr {
+            IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & mask32(len)).into()),
+            IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & mask128(len)).into()),
+        };
+        Ok(Self { addr, len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => u32::from(net) == u32::from(ip) & mask32(self.len),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => u128::from(net) == u128::from(ip) & mask128(self.len),
+            _ => false,
+        }
+    }
+}
+
+fn mask32(len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - len as u32).unwrap_or(0)
+}
+
+fn mask128(len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - len as u32).unwrap_or(0)
+}
+
+impl FromStr for IpPrefix {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid IP prefix: {}", s));
+        let (addr, len) = s.split_once('/').ok_or_else(invalid)?;
+        let addr = addr.parse().map_err(|_| invalid())?;
+        let len = len.parse().map_err(|_| invalid())?;
+        Self::new(addr, len)
+    }
+}
+
+/// Maps destination prefixes to the peers packets are tunnelled to. The longest
+/// matching prefix wins.
+#[derive(Debug, Clone, Default)]
+pub struct IpRoutes {
+    routes: Vec<(IpPrefix, SocketAddr)>,
+}
+
+impl IpRoutes {
+    pub fn add(&mut self, prefix: IpPrefix, peer: SocketAddr) {
+        self.routes.retain(|(p, _)| *p != prefix);
+        let at = self.routes.partition_point(|(p, _)| p.len >= prefix.len);
+        self.routes.insert(at, (prefix, peer));
+    }
+
+    pub fn remove(&mut self, prefix: IpPrefix) {
+        self.routes.retain(|(p, _)| *p != prefix);
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<SocketAddr> {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| prefix.contains(ip))
+            .map(|(_, peer)| *peer)
+    }
+}
+
+fn ip_at(packet: &[u8], v4: usize, v6: usize) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => <[u8; 4]>::try_from(packet.get(v4..v4 + 4)?).ok().map(IpAddr::from),
+        6 => <[u8; 16]>::try_from(packet.get(v6..v6 + 16)?).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+fn source(packet: &[u8]) -> Option<IpAddr> {
+    ip_at(packet, 12, 8)
+}
+
+fn destination(packet: &[u8]) -> Option<IpAddr> {
+    ip_at(packet, 16, 24)
+}
+
+/// Moves packets between `device` and the peers in `routes`. Packets arriving from a
+/// peer are only written to the device if their source routes back to that peer.
+pub(super) async fn run<D: PacketDevice>(
+    endpoint: &QuicEndpoint,
+    device: D,
+    routes: IpRoutes,
+    mut ingress: mpsc::Receiver<QuicDatagram>,
+) -> Result<()> {
+    let egress = async {
+        let mut buf = vec![0u8; PACKET_BUFFER_SIZE];
+        loop {
+            let n = device.read(&mut buf).await?;
+            let packet = &buf[..n];
+            let Some(dst) = destination(packet) else {
+                trace!("Dropping non-IP packet of {} bytes", n);
+                continue;
+            };
+            let Some(peer) = routes.lookup(dst) else {
+                trace!("Dropping packet to {:?}: no route", dst);
+                continue;
+            };
+            let mut datagram = BytesMut::with_capacity(1 + n);
+            datagram.put_u8(DATAGRAM_PACKET);
+            datagram.put_slice(packet);
+            if let Err(e) = endpoint.send_datagram(peer, datagram.freeze()) {
+                trace!("Dropping packet to {:?} via {:?}: {:?}", dst, peer, e);
+            }
+        }
+    };
+
+    let ingress = async {
+        while let Some(QuicDatagram { addr, payload }) = ingress.recv().await {
+            match source(&payload) {
+                Some(src) if routes.lookup(src) == Some(addr) => device.write(&payload).await?,
+                src => trace!("Dropping packet from {:?} via {:?}: source not routed", src, addr),
+            }
+        }
+        Ok(())
+    };
+
+    select! {
+        res = egress => res,
+        res = ingress => res,
+    }
+}
@@ -0,0 +1,301 @@
+use crate::gateway::forward::header::{frame_len, StreamHeader, TargetAddr, DATAGRAM_FLOW};
+use crate::gateway::quic::{QuicEndpoint, QuicStream};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, trace};
+
+/// Set on flow IDs travelling from the side serving a flow back to the side that opened it,
+/// so both sides can open flows over the same connection.
+const FLOW_REPLY: u32 = 1 << 31;
+
+const UDP_BUFFER_SIZE: usize = 65536;
+
+/// Datagrams a local flow queues while it opens or waits to transmit; the rest are dropped.
+const FLOW_QUEUE_CAPACITY: usize = 256;
+
+pub const DEFAULT_FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Flow {
+    id: u32,
+    peer: SocketAddr,
+    socket: Arc<UdpSocket>,
+    /// Source of a local flow. Remote flows own a connected socket instead.
+    src: Option<SocketAddr>,
+    tx: tokio::sync::Mutex<WriteHalf<QuicStream>>,
+    last: Mutex<Instant>,
+}
+
+impl Flow {
+    fn touch(&self) {
+        *self.last.lock() = Instant::now();
+    }
+
+    async fn deliver(&self, payload: &[u8]) -> Result<()> {
+        self.touch();
+        match self.src {
+            Some(src) => self.socket.send_to(payload, src).await.map(|_| ()),
+            None => self.socket.send(payload).await.map(|_| ()),
+        }
+    }
+
+    /// Like [`Self::deliver`], dropping the payload instead of waiting for a full socket so
+    /// one flow cannot hold up the datagrams of others.
+    fn try_deliver(&self, payload: &[u8]) -> Result<()> {
+        self.touch();
+        match self.src {
+            Some(src) => self.socket.try_send_to(payload, src).map(|_| ()),
+            None => self.socket.try_send(payload).map(|_| ()),
+        }
+    }
+
+    /// Sends `payload` to the peer as a datagram, falling back to the flow stream
+    /// when it does not fit into one or the peer does not take datagrams.
+    async fn transmit(&self, endpoint: &QuicEndpoint, payload: &[u8]) -> Result<()> {
+        self.touch();
+        let id = match self.src {
+            Some(_) => self.id,
+            None => self.id | FLOW_REPLY,
+        };
+        let mut buf = BytesMut::with_capacity(5 + payload.len());
+        buf.put_u8(DATAGRAM_FLOW);
+        buf.put_u32(id);
+        buf.put_slice(payload);
+        match endpoint.send_datagram(self.peer, buf.freeze()) {
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::Unsupported) => {
+                self.transmit_stream(payload).await
+            }
+            res => res,
+        }
+    }
+
+    async fn transmit_stream(&self, payload: &[u8]) -> Result<()> {
+        let mut tx = self.tx.lock().await;
+        write_frame(&mut *tx, payload).await
+    }
+
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let deadline = *self.last.lock() + timeout;
+            if deadline <= Instant::now() {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    }
+
+    /// Carries the flow until either side finishes it or it idles out. Local flows take
+    /// their uplink from `queue`, filled by `forward`; remote flows read their own socket.
+    async fn run(
+        &self,
+        endpoint: &QuicEndpoint,
+        mut rx: ReadHalf<QuicStream>,
+        queue: Option<mpsc::Receiver<Bytes>>,
+        idle_timeout: Duration,
+    ) -> Result<()> {
+        let downlink = async {
+            loop {
+                let payload = read_frame(&mut rx).await?;
+                self.deliver(&payload).await?;
+            }
+        };
+
+        let uplink = async {
+            match queue {
+                // Local flows share the forwarder socket, which `forward` reads into the queue.
+                Some(mut queue) => {
+                    while let Some(payload) = queue.recv().await {
+                        self.transmit(endpoint, &payload).await?;
+                    }
+                    Ok(())
+                }
+                None => {
+                    let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+                    loop {
+                        let n = self.socket.recv(&mut buf).await?;
+                        self.transmit(endpoint, &buf[..n]).await?;
+                    }
+                }
+            }
+        };
+
+        let res = select! {
+            res = downlink => res,
+            res = uplink => res,
+            _ = self.idle(idle_timeout) => Ok(()),
+        };
+        let _ = self.tx.lock().await.shutdown().await;
+        res
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct UdpFlows {
+    next: AtomicU32,
+    local: DashMap<u32, Arc<Flow>>,
+    remote: DashMap<(SocketAddr, u32), Arc<Flow>>,
+}
+
+impl UdpFlows {
+    /// Handles the payload of a [`DATAGRAM_FLOW`] datagram received from `peer`.
+    pub(super) fn dispatch(&self, peer: SocketAddr, mut payload: Bytes) -> Result<()> {
+        if payload.len() < 4 {
+            return Err(Error::new(ErrorKind::InvalidData, "Truncated flow datagram"));
+        }
+        let id = payload.get_u32();
+        let flow = match id & FLOW_REPLY {
+            0 => self.remote.get(&(peer, id)).map(|flow| flow.clone()),
+            _ => self.local.get(&(id & !FLOW_REPLY)).map(|flow| flow.clone()),
+        };
+        match flow {
+            // Reply IDs are ours, so only the peer the flow was opened to may use them.
+            Some(flow) if flow.peer == peer => flow.try_deliver(&payload),
+            _ => {
+                trace!("Dropping datagram for unknown flow {:#010x} from {:?}", id, peer);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Carries every UDP flow arriving on `socket` to `target` through `peer`. Each source gets
+/// its own task and a queue of [`FLOW_QUEUE_CAPACITY`] datagrams, so a flow that is still
+/// opening or congested drops its own datagrams without holding up the others.
+pub(super) async fn forward(
+    endpoint: Arc<QuicEndpoint>,
+    flows: Arc<UdpFlows>,
+    socket: UdpSocket,
+    peer: SocketAddr,
+    target: TargetAddr,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+    let by_src: Arc<DashMap<SocketAddr, mpsc::Sender<Bytes>>> = Arc::new(DashMap::new());
+    let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+
+    loop {
+        let (n, src) = socket.recv_from(&mut buf).await?;
+        let payload = Bytes::copy_from_slice(&buf[..n]);
+        if let Some(queue) = by_src.get(&src) {
+            if let Err(e) = queue.try_send(payload) {
+                trace!("Dropping datagram from {:?}: {:?}", src, e);
+            }
+            continue;
+        }
+
+        let (tx, rx) = mpsc::channel(FLOW_QUEUE_CAPACITY);
+        by_src.insert(src, tx);
+        let (endpoint, flows, socket) = (endpoint.clone(), flows.clone(), socket.clone());
+        let (by_src, target) = (by_src.clone(), target.clone());
+        tokio::spawn(async move {
+            let opened = open_flow(&endpoint, &flows, socket, peer, src, target, payload).await;
+            let res = match opened {
+                Ok((flow, stream)) => {
+                    let res = flow.run(&endpoint, stream, Some(rx), idle_timeout).await;
+                    flows.local.remove(&flow.id);
+                    res
+                }
+                Err(e) => Err(e),
+            };
+            by_src.remove(&src);
+            debug!("UDP flow from {:?} closed: {:?}", src, res);
+        });
+    }
+}
+
+/// Opens the flow for datagrams from `src` and sends `first` over its stream, so the peer has
+/// the flow set up before any datagram arrives.
+async fn open_flow(
+    endpoint: &QuicEndpoint,
+    flows: &UdpFlows,
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    src: SocketAddr,
+    target: TargetAddr,
+    first: Bytes,
+) -> Result<(Arc<Flow>, ReadHalf<QuicStream>)> {
+    let id = flows.next.fetch_add(1, Ordering::Relaxed) & !FLOW_REPLY;
+    let header = StreamHeader::Flow { id, target: target.clone() }.encode()?;
+    let (rx, tx) = split(endpoint.open(peer, Some(header)).await?);
+    let flow = Arc::new(Flow {
+        id,
+        peer,
+        socket,
+        src: Some(src),
+        tx: tx.into(),
+        last: Instant::now().into(),
+    });
+    trace!("UDP flow {} opened from {:?} to {:?}", id, src, target);
+
+    flow.transmit_stream(&first).await?;
+    flows.local.insert(id, flow.clone());
+    Ok((flow, rx))
+}
+
+/// Remote half of [`StreamHeader::Flow`]. The flow is dropped after `idle_timeout` without
+/// traffic, in case the side that opened it goes away without finishing the stream. Flows
+/// to targets the gateway does not allow are finished right away.
+pub(super) async fn serve_flow(
+    endpoint: Arc<QuicEndpoint>,
+    flows: Arc<UdpFlows>,
+    mut stream: QuicStream,
+    id: u32,
+    target: TargetAddr,
+    allowed: bool,
+    idle_timeout: Duration,
+) -> Result<()> {
+    if !allowed {
+        stream.shutdown().await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("UDP flow to {:?} is not allowed", target),
+        ));
+    }
+    let peer = stream.remote_address();
+    let addr = target.resolve().await?;
+    let socket = match addr {
+        SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+        SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+    };
+    socket.connect(addr).await?;
+
+    let (rx, tx) = split(stream);
+    let flow = Arc::new(Flow {
+        id,
+        peer,
+        socket: socket.into(),
+        src: None,
+        tx: tx.into(),
+        last: Instant::now().into(),
+    });
+    trace!("UDP flow {} from {:?} serving {:?}", id, peer, addr);
+
+    flows.remote.insert((peer, id), flow.clone());
+    let res = flow.run(&endpoint, rx, None, idle_timeout).await;
+    flows.remote.remove(&(peer, id));
+    res
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(2 + payload.len());
+    buf.put_u16(frame_len(payload)?);
+    buf.put_slice(payload);
+    writer.write_all(&buf).await
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut payload = vec![0u8; reader.read_u16().await? as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok(payload)
+}
@@ -1 +1,3 @@
-pub(crate) mod quic;
\ No newline at end of file
+pub mod quic;
+pub mod forward;
+pub mod device;
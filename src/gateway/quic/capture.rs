@@ -0,0 +1,273 @@
+use parking_lot::Mutex;
+use std::fs::File;
+use std::io::{BufWriter, Error, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+/// LINKTYPE_RAW: each packet starts with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u16 = 101;
+const SHB_LEN: usize = 28;
+const IDB_LEN: usize = 20;
+/// Enhanced packet block without its data: header, direction option and trailer.
+const EPB_OVERHEAD: usize = 44;
+
+/// Packet capture of an endpoint, see [`QuicEndpoint::start_capture`](super::QuicEndpoint::start_capture).
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureConfig {
+    /// Address the endpoint appears with in synthetic IP and UDP headers. Mapped to the
+    /// peer's address family where needed.
+    pub local: SocketAddr,
+    /// Size the file may grow to, capturing stops before a packet would exceed it.
+    pub max_size: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            local: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 443)),
+            max_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Switchable pcapng writer shared by everything handling the endpoint's packets.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Capture {
+    inner: Arc<CaptureInner>,
+}
+
+#[derive(Debug, Default)]
+struct CaptureInner {
+    /// Checked before taking the lock, so packets pass untouched while not capturing.
+    on: AtomicBool,
+    writer: Mutex<Option<PcapWriter>>,
+}
+
+#[derive(Debug)]
+struct PcapWriter {
+    out: BufWriter<File>,
+    config: CaptureConfig,
+    written: u64,
+}
+
+impl Capture {
+    /// Starts writing to a new file at `path`, finishing the previous one.
+    pub(super) fn start(&self, path: &Path, config: CaptureConfig) -> Result<()> {
+        let file = File::create(path)
+            .map_err(|e| Error::new(e.kind(), format!("Failed to create capture file {:?}: {}", path, e)))?;
+        let mut writer = PcapWriter {
+            out: BufWriter::new(file),
+            config,
+            written: 0,
+        };
+        writer.header().map_err(|e| Error::new(e.kind(), format!("Failed to write capture file {:?}: {}", path, e)))?;
+
+        let mut slot = self.inner.writer.lock();
+        if let Some(mut old) = slot.replace(writer) {
+            let _ = old.out.flush();
+        }
+        self.inner.on.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    pub(super) fn stop(&self) {
+        let mut slot = self.inner.writer.lock();
+        self.inner.on.store(false, Ordering::Release);
+        if let Some(Err(e)) = slot.take().map(|mut old| old.out.flush()) {
+            error!("Failed to finish packet capture: {:?}", e);
+        }
+    }
+
+    pub(super) fn is_on(&self) -> bool {
+        self.inner.on.load(Ordering::Acquire)
+    }
+
+    /// Records a bare QUIC datagram exchanged with `peer`.
+    pub(super) fn record(&self, peer: SocketAddr, datagram: &[u8], dir: Direction) {
+        if !self.is_on() {
+            return;
+        }
+        let mut slot = self.inner.writer.lock();
+        let Some(writer) = slot.as_mut() else {
+            return;
+        };
+        let res = match writer.packet(peer, datagram, dir) {
+            Ok(true) => return,
+            Ok(false) => {
+                info!("Packet capture reached {} bytes, stopped", writer.written);
+                writer.out.flush()
+            }
+            Err(e) => {
+                error!("Failed to write packet capture: {:?}", e);
+                Ok(())
+            }
+        };
+        if let Err(e) = res {
+            error!("Failed to finish packet capture: {:?}", e);
+        }
+        self.inner.on.store(false, Ordering::Release);
+        *slot = None;
+    }
+}
+
+impl PcapWriter {
+    /// Section header and the single interface all packets are recorded on.
+    fn header(&mut self) -> Result<()> {
+        let mut block = Vec::with_capacity(SHB_LEN + IDB_LEN);
+        block.extend_from_slice(&0x0a0d_0d0au32.to_le_bytes());
+        block.extend_from_slice(&(SHB_LEN as u32).to_le_bytes());
+        block.extend_from_slice(&0x1a2b_3c4du32.to_le_bytes());
+        block.extend_from_slice(&1u16.to_le_bytes());
+        block.extend_from_slice(&0u16.to_le_bytes());
+        // 段长度未知
+        block.extend_from_slice(&(-1i64).to_le_bytes());
+        block.extend_from_slice(&(SHB_LEN as u32).to_le_bytes());
+
+        block.extend_from_slice(&1u32.to_le_bytes());
+        block.extend_from_slice(&(IDB_LEN as u32).to_le_bytes());
+        block.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        block.extend_from_slice(&0u16.to_le_bytes());
+        block.extend_from_slice(&0u32.to_le_bytes());
+        block.extend_from_slice(&(IDB_LEN as u32).to_le_bytes());
+
+        self.out.write_all(&block)?;
+        self.written = block.len() as u64;
+        Ok(())
+    }
+
+    /// Writes one enhanced packet block, or returns false if it would exceed the size limit.
+    fn packet(&mut self, peer: SocketAddr, datagram: &[u8], dir: Direction) -> Result<bool> {
+        let local = match (self.config.local, peer.ip()) {
+            (SocketAddr::V4(local), IpAddr::V6(_)) => {
+                SocketAddr::new(local.ip().to_ipv6_mapped().into(), local.port())
+            }
+            (SocketAddr::V6(local), IpAddr::V4(_)) => SocketAddr::new(
+                local.ip().to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED).into(),
+                local.port(),
+            ),
+            (local, _) => local,
+        };
+        let (src, dst) = match dir {
+            Direction::Inbound => (peer, local),
+            Direction::Outbound => (local, peer),
+        };
+        let packet = ip_udp(src, dst, datagram);
+        let padded = packet.len().next_multiple_of(4);
+        let len = EPB_OVERHEAD + padded;
+        if self.written + len as u64 > self.config.max_size {
+            return Ok(false);
+        }
+
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut block = Vec::with_capacity(len);
+        block.extend_from_slice(&6u32.to_le_bytes());
+        block.extend_from_slice(&(len as u32).to_le_bytes());
+        block.extend_from_slice(&0u32.to_le_bytes());
+        block.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        block.extend_from_slice(&(micros as u32).to_le_bytes());
+        block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        block.extend_from_slice(&packet);
+        block.resize(block.len() + padded - packet.len(), 0);
+        // epb_flags：低两位为方向，1 入站，2 出站
+        block.extend_from_slice(&2u16.to_le_bytes());
+        block.extend_from_slice(&4u16.to_le_bytes());
+        let flags: u32 = match dir {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        };
+        block.extend_from_slice(&flags.to_le_bytes());
+        block.extend_from_slice(&0u32.to_le_bytes());
+        block.extend_from_slice(&(len as u32).to_le_bytes());
+
+        self.out.write_all(&block)?;
+        self.written += len as u64;
+        Ok(true)
+    }
+}
+
+impl Drop for PcapWriter {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// Wraps `datagram` in IP and UDP headers from `src` to `dst`, both of one address family.
+fn ip_udp(src: SocketAddr, dst: SocketAddr, datagram: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + datagram.len();
+    let mut packet = Vec::with_capacity(40 + udp_len);
+    let pseudo = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            // DF，出站包从不分片
+            header[6] = 0x40;
+            header[8] = 64;
+            header[9] = 17;
+            header[12..16].copy_from_slice(&s.octets());
+            header[16..20].copy_from_slice(&d.octets());
+            let checksum = !fold(sum(&header));
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&header);
+            sum(&s.octets()) + sum(&d.octets()) + 17 + udp_len as u32
+        }
+        (s, d) => {
+            let s = ipv6(s);
+            let d = ipv6(d);
+            packet.extend_from_slice(&0x6000_0000u32.to_be_bytes());
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[17, 64]);
+            packet.extend_from_slice(&s);
+            packet.extend_from_slice(&d);
+            sum(&s) + sum(&d) + 17 + udp_len as u32
+        }
+    };
+
+    let mut udp = [0u8; 8];
+    udp[0..2].copy_from_slice(&src.port().to_be_bytes());
+    udp[2..4].copy_from_slice(&dst.port().to_be_bytes());
+    udp[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    let checksum = match !fold(pseudo + sum(&udp) + sum(datagram)) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&udp);
+    packet.extend_from_slice(datagram);
+    packet
+}
+
+fn ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Sum of big-endian 16-bit words, the last byte padded with zero.
+fn sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], w.get(1).copied().unwrap_or(0)]) as u32)
+        .fold(0, u32::wrapping_add)
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
@@ -0,0 +1,196 @@
+use crate::gateway::quic::tls::Identity;
+use parking_lot::RwLock;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{error, info};
+
+/// Server certificates picked by the SNI of each handshake, falling back to a default one
+/// for clients sending no or an unknown name. Clones share the certificates. Replacing one
+/// only affects handshakes from then on, live connections are kept.
+#[derive(Debug, Clone, Default)]
+pub struct ServerCerts {
+    inner: Arc<RwLock<Certs>>,
+}
+
+#[derive(Debug, Default)]
+struct Certs {
+    default: Option<Arc<CertifiedKey>>,
+    names: HashMap<String, Arc<CertifiedKey>>,
+    /// Where file-backed certificates were loaded from, by server name.
+    files: HashMap<Option<String>, PemFiles>,
+}
+
+#[derive(Debug, Clone)]
+struct PemFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    modified: Option<(SystemTime, SystemTime)>,
+}
+
+impl PemFiles {
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = fs::metadata(&self.cert).and_then(|m| m.modified()).ok()?;
+        let key = fs::metadata(&self.key).and_then(|m| m.modified()).ok()?;
+        Some((cert, key))
+    }
+}
+
+impl ServerCerts {
+    /// Serves `identity` to clients asking for `name`, or by default with `None`. Names
+    /// match case-insensitively, `*.example.com` matches one label in place of the `*`.
+    pub fn insert(&self, name: Option<&str>, identity: &Identity) -> Result<()> {
+        let key = certified_key(identity)?;
+        let name = name.map(str::to_ascii_lowercase);
+        let mut certs = self.inner.write();
+        certs.files.remove(&name);
+        certs.set(name, key);
+        Ok(())
+    }
+
+    pub fn remove(&self, name: Option<&str>) {
+        let name = name.map(str::to_ascii_lowercase);
+        let mut certs = self.inner.write();
+        certs.files.remove(&name);
+        match name {
+            Some(name) => drop(certs.names.remove(&name)),
+            None => certs.default = None,
+        }
+    }
+
+    /// Like [`Self::insert`] with a PEM certificate chain and private key, which
+    /// [`Self::reload`] and [`Self::watch`] read again later.
+    pub fn load(&self, name: Option<&str>, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<()> {
+        let mut files = PemFiles {
+            cert: cert.as_ref().to_path_buf(),
+            key: key.as_ref().to_path_buf(),
+            modified: None,
+        };
+        files.modified = files.modified();
+        let key = certified_key(&Identity::from_pem_files(&files.cert, &files.key)?)?;
+        let name = name.map(str::to_ascii_lowercase);
+        let mut certs = self.inner.write();
+        certs.files.insert(name.clone(), files);
+        certs.set(name, key);
+        Ok(())
+    }
+
+    /// Reads every file-backed certificate again. One failing to load keeps being served
+    /// as before, the first such error is returned after trying the others.
+    pub fn reload(&self) -> Result<()> {
+        self.reload_if(|_| true)
+    }
+
+    /// Reloads file-backed certificates whose files changed, checking every `interval`
+    /// until all clones are dropped or the task is aborted.
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        let certs = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+                let certs = match Weak::upgrade(&certs) {
+                    Some(inner) => ServerCerts { inner },
+                    None => break,
+                };
+                if let Err(e) = certs.reload_if(|files| files.modified() != files.modified) {
+                    error!("Failed to reload server certificates: {:?}", e);
+                }
+            }
+        })
+    }
+
+    fn reload_if(&self, changed: impl Fn(&PemFiles) -> bool) -> Result<()> {
+        // The files are read without the lock held, so handshakes keep using the old
+        // certificates meanwhile.
+        let files: Vec<_> = self
+            .inner
+            .read()
+            .files
+            .iter()
+            .filter(|(_, files)| changed(files))
+            .map(|(name, files)| (name.clone(), files.clone()))
+            .collect();
+
+        let mut res = Ok(());
+        for (name, mut files) in files {
+            files.modified = files.modified();
+            let key = Identity::from_pem_files(&files.cert, &files.key).and_then(|id| certified_key(&id));
+            match key {
+                Ok(key) => {
+                    info!("Reloaded server certificate for {:?} from {:?}", name, files.cert);
+                    let mut certs = self.inner.write();
+                    // A certificate replaced or removed during the reload no longer follows its
+                    // files.
+                    if certs.files.get(&name).is_some_and(|old| old.cert == files.cert && old.key == files.key) {
+                        certs.files.insert(name.clone(), files);
+                        certs.set(name, key);
+                    }
+                }
+                Err(e) => {
+                    // Remember this modification time, so that the error is not repeated until the
+                    // files change again.
+                    if let Some(old) = self.inner.write().files.get_mut(&name) {
+                        old.modified = files.modified;
+                    }
+                    if res.is_ok() {
+                        res = Err(Error::new(
+                            e.kind(),
+                            format!("Failed to reload certificate for {:?}: {}", name, e),
+                        ));
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    /// Whether no certificate, not even a default one, is set.
+    pub fn is_empty(&self) -> bool {
+        let certs = self.inner.read();
+        certs.default.is_none() && certs.names.is_empty()
+    }
+}
+
+impl Certs {
+    fn set(&mut self, name: Option<String>, key: Arc<CertifiedKey>) {
+        match name {
+            Some(name) => drop(self.names.insert(name, key)),
+            None => self.default = Some(key),
+        }
+    }
+}
+
+impl ResolvesServerCert for ServerCerts {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.inner.read();
+        client_hello
+            .server_name()
+            .and_then(|name| lookup(&certs.names, name))
+            .or(certs.default.as_ref())
+            .cloned()
+    }
+}
+
+/// Entry for server name `name`, trying an exact match before a wildcard one. Keys are
+/// expected in lowercase.
+pub(super) fn lookup<'a, T>(map: &'a HashMap<String, T>, name: &str) -> Option<&'a T> {
+    let name = name.to_ascii_lowercase();
+    map.get(&name).or_else(|| {
+        let (_, parent) = name.split_once('.')?;
+        map.get(&format!("*.{}", parent))
+    })
+}
+
+fn certified_key(identity: &Identity) -> Result<Arc<CertifiedKey>> {
+    let provider = rustls::crypto::ring::default_provider();
+    CertifiedKey::from_der(identity.cert_chain.clone(), identity.key.clone_key(), &provider)
+        .map(Arc::new)
+        .map_err(|e| Error::other(format!("Invalid certificate or key: {:?}", e)))
+}
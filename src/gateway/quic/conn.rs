@@ -1,62 +1,395 @@
+use crate::gateway::quic::error::QuicError;
+use crate::gateway::quic::packet::{QuicPacket, QuicPacketMargins, QuicPacketTx};
+use crate::gateway::quic::qlog::{Qlog, Received};
+use crate::gateway::quic::shaper::{RateLimit, Shaper, WRITE_GRANULE};
+use crate::gateway::quic::staging::StreamStaging;
 use crate::gateway::quic::stream::QuicStream;
+use crate::gateway::quic::tls::{alpn, authorize, profile, server_name, Authorizer, EarlyData, PeerIdentity};
+use crate::gateway::quic::transport::TransportProfile;
+use crate::gateway::quic::utils::BufAcc;
 use crossbeam::queue::{ArrayQueue, SegQueue};
+use derive_more::Debug;
+use derive_more::{Deref, DerefMut};
 use parking_lot::Mutex;
-use quinn_proto::{Connection, ConnectionEvent, Dir, StreamId, VarInt};
-use std::collections::HashMap;
-use std::io::{Error, Result};
-use std::iter::chain;
-use std::sync::atomic::AtomicBool;
+use bytes::{Bytes, BytesMut};
+use quinn_proto::{Connection, ConnectionError, ConnectionEvent, Dir, SendDatagramError, StreamId, Transmit, VarInt};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::task::Waker;
 use std::time::Instant;
 use tokio::sync::{oneshot, Notify};
+use tracing::{debug, trace};
 
+/// Size of each buffer transmits are collected into, so that a burst of small packets
+/// shares one allocation.
+const TRANSMIT_CHUNK: usize = 256 * 1200;
+pub(super) const MAX_UDP_PAYLOAD: usize = 65527;
+
+/// The connection and what the endpoint hands down to it, alike in the threaded and the
+/// local state.
 #[derive(Debug)]
-pub(super) struct ConnState {
+pub(super) struct ConnBase {
     pub(super) conn: Connection,
-    pub(super) readers: HashMap<StreamId, Waker>,
-    pub(super) writers: HashMap<StreamId, Waker>,
+    /// Shapes the stream data handed to the connection. Packets without stream data, such
+    /// as ACKs, probes and CONNECTION_CLOSE, are never held back.
+    pub(super) egress: Shaper,
+    /// Streams were opened with 0-RTT keys and the handshake has not told yet whether the
+    /// server took them.
+    early: bool,
+    /// The handshake completed.
+    pub(super) established: bool,
+    /// Parsed once the peer's certificate is known.
+    identity: Option<Arc<PeerIdentity>>,
+    /// Decides which of the streams the peer opens are handed out.
+    #[debug(skip)]
+    pub(super) authorizer: Option<Authorizer>,
+    /// Limits picked by the SNI of incoming connections.
+    pub(super) profiles: Option<Arc<HashMap<String, TransportProfile>>>,
+    pub(super) qlog: Option<Qlog>,
+    /// Why the connection stopped being driven, reported to every stream afterwards.
+    pub(super) closed: Option<QuicError>,
+}
+
+impl ConnBase {
+    pub(super) fn new(conn: Connection) -> Self {
+        Self {
+            early: conn.side().is_client() && conn.has_0rtt(),
+            conn,
+            egress: Shaper::default(),
+            established: false,
+            identity: None,
+            authorizer: None,
+            profiles: None,
+            qlog: None,
+            closed: None,
+        }
+    }
+
+    pub(super) fn peer_identity(&mut self) -> Option<Arc<PeerIdentity>> {
+        if self.identity.is_none() {
+            self.identity = PeerIdentity::of(&self.conn).map(Arc::new);
+        }
+        self.identity.clone()
+    }
+
+    /// SNI the client asked for, on incoming TLS connections.
+    pub(super) fn server_name(&self) -> Option<String> {
+        server_name(&self.conn)
+    }
+
+    pub(super) fn alpn(&self) -> Option<Vec<u8>> {
+        alpn(&self.conn)
+    }
+
+    /// Applies the profile the client's SNI picked, once it is known.
+    pub(super) fn apply_profile(&mut self) {
+        let profile = match &self.profiles {
+            Some(profiles) => profile(profiles, &self.conn),
+            None => None,
+        };
+        if let Some(profile) = profile {
+            self.set_profile(profile);
+        }
+    }
+
+    /// Applies the fields `profile` sets, leaving the others as they are.
+    pub(super) fn set_profile(&mut self, profile: TransportProfile) {
+        profile.apply(&mut self.conn);
+        if profile.rate_limit.is_some() {
+            self.egress.set(profile.rate_limit);
+        }
+    }
+
+    /// Whether a stream the peer opened may be handed out. Denied ones are stopped and reset.
+    pub(super) fn authorize(&mut self, id: StreamId) -> bool {
+        let authorizer = match &self.authorizer {
+            Some(authorizer) => authorizer.clone(),
+            None => return true,
+        };
+        let identity = self.peer_identity();
+        authorize(&authorizer, &mut self.conn, identity.as_deref(), id)
+    }
+
+    /// Whether a stream opened now sends 0-RTT data.
+    pub(super) fn early(&self) -> bool {
+        self.early
+    }
+
+    /// Settles 0-RTT once the handshake completed, returning true the one time it finds the
+    /// server rejected it. quinn-proto then forgets the streams opened before the handshake
+    /// and reuses their IDs.
+    pub(super) fn early_data_rejected(&mut self) -> bool {
+        if !self.early || self.conn.is_handshaking() || self.conn.is_closed() {
+            return false;
+        }
+        self.early = false;
+        !self.conn.accepted_0rtt()
+    }
+
+    /// Why the connection ended, [`QuicError::ConnectionClosed`] if not told.
+    pub(super) fn reason(&self) -> QuicError {
+        self.closed.clone().unwrap_or(QuicError::ConnectionClosed)
+    }
+
+    /// Logs a packet passed to the connection, if it keeps a qlog.
+    pub(super) fn qlog_received(&mut self, packet: Received) {
+        if let Some(qlog) = &mut self.qlog {
+            qlog.packet_received(packet);
+        }
+    }
+
+    /// The error a lost connection ends with, logged to its qlog. A connection closed over
+    /// an error found on this side still has to tell the peer, which would otherwise wait for
+    /// its idle timeout, so that is handed to `tx` as far as it has room.
+    pub(super) fn lost(&mut self, reason: ConnectionError, tx: &QuicPacketTx) -> QuicError {
+        let (mut transmits, mut chunks) = (VecDeque::new(), VecDeque::new());
+        self.poll_transmits(tx.margins, &mut transmits, &mut chunks);
+        while let Some(packet) = cut_packet(&mut transmits, &mut chunks, tx) {
+            let _ = tx.try_send(packet);
+        }
+        if let Some(qlog) = &mut self.qlog {
+            qlog.closed(&reason);
+        }
+        QuicError::lost(reason, !self.established)
+    }
+
+    /// Collects the packets the connection has to send into `chunks`, with their
+    /// destinations and sizes in `transmits`, see [`cut_packet`].
+    pub(super) fn poll_transmits(
+        &mut self,
+        margins: QuicPacketMargins,
+        transmits: &mut VecDeque<Transmit>,
+        chunks: &mut VecDeque<BytesMut>,
+    ) {
+        if let Some(qlog) = &mut self.qlog {
+            qlog.update(&self.conn);
+        }
+        let mut chunk = BufAcc::new(TRANSMIT_CHUNK);
+        loop {
+            // MTU 探测包会按探测大小 reserve，超过预留容量就会重新分配不属于分配器的指针
+            let capacity = (self.conn.current_mtu() as usize).max(MAX_UDP_PAYLOAD);
+            let mut buf = match chunk.buf(capacity, margins) {
+                Some(buf) => buf,
+                None => {
+                    chunks.push_back(chunk.renew());
+                    chunk.buf(capacity, margins).unwrap()
+                }
+            };
+            let Some(transmit) = self.conn.poll_transmit(Instant::now(), 1, &mut buf) else {
+                if !chunk.is_empty() {
+                    chunks.push_back(chunk.take());
+                }
+                break;
+            };
+            if let Some(qlog) = &mut self.qlog {
+                qlog.packet_sent(&self.conn, &buf[..transmit.size]);
+            }
+            buf.commit();
+            transmits.push_back(transmit);
+        }
+    }
+
+    /// The earliest deadline of the connection's timers, or of the egress bucket refilling
+    /// a granule if `throttled` streams wait for it.
+    pub(super) fn timeout(&mut self, throttled: bool) -> Option<Instant> {
+        let ready = match throttled {
+            true => self.egress.ready_for(WRITE_GRANULE),
+            false => None,
+        };
+        match (self.conn.poll_timeout(), ready) {
+            (Some(timeout), Some(ready)) => Some(timeout.min(ready)),
+            (timeout, ready) => timeout.or(ready),
+        }
+    }
+}
+
+/// Cuts the next packet [`ConnBase::poll_transmits`] collected out of its chunk.
+pub(super) fn cut_packet(
+    transmits: &mut VecDeque<Transmit>,
+    chunks: &mut VecDeque<BytesMut>,
+    tx: &QuicPacketTx,
+) -> Option<QuicPacket> {
+    let (header, trailer) = tx.margins.into();
+    let transmit = transmits.pop_front()?;
+    let chunk = chunks.front_mut().unwrap();
+    let data = chunk.split_to(header + transmit.size + trailer);
+    if chunk.is_empty() {
+        chunks.pop_front();
+    }
+    let mut packet = QuicPacket::new(transmit.destination, data);
+    tx.frame(&mut packet);
+    Some(packet)
+}
+
+/// Requests for streams waiting for the peer to grant credit, in request order per
+/// direction. Requests whose receiver is gone are skipped.
+#[derive(Debug)]
+pub(super) struct WaitingOpens<S> {
+    bi: VecDeque<StreamOpenTx<S>>,
+    uni: VecDeque<StreamOpenTx<S>>,
+}
+
+impl<S> Default for WaitingOpens<S> {
+    fn default() -> Self {
+        Self {
+            bi: VecDeque::new(),
+            uni: VecDeque::new(),
+        }
+    }
+}
+
+impl<S> WaitingOpens<S> {
+    pub(super) fn queue(&mut self, dir: Dir) -> &mut VecDeque<StreamOpenTx<S>> {
+        match dir {
+            Dir::Bi => &mut self.bi,
+            Dir::Uni => &mut self.uni,
+        }
+    }
+
+    /// Answers the waiting requests in `dir` with the streams `open` returns, oldest first,
+    /// until it returns `None` for lack of credit.
+    pub(super) fn grant(&mut self, dir: Dir, mut open: impl FnMut(Dir) -> Option<S>) {
+        let queue = self.queue(dir);
+        while let Some(tx) = queue.front() {
+            // 调用方已超时或放弃等待，不再为其开流
+            if tx.is_closed() {
+                queue.pop_front();
+                continue;
+            }
+            let Some(stream) = open(dir) else {
+                break;
+            };
+            let tx = queue.pop_front().unwrap();
+            // 检查后才超时的调用方拿不到流，丢弃的流随即关闭，属正常情况
+            if tx.send(Ok(stream)).is_err() {
+                debug!("Stream opener went away before taking its stream");
+            }
+        }
+    }
+
+    pub(super) fn refuse(&mut self, reason: QuicError) {
+        for tx in self.bi.drain(..).chain(self.uni.drain(..)) {
+            let _ = tx.send(Err(reason.clone().into()));
+        }
+    }
+}
+
+#[derive(Debug, Deref, DerefMut)]
+pub(super) struct ConnState {
+    #[deref]
+    #[deref_mut]
+    base: ConnBase,
+    pub(super) streams: HashMap<StreamId, Arc<StreamStaging>>,
+    /// Streams left with staged data because the egress bucket ran dry, flushed again
+    /// once it refills.
+    throttled: VecDeque<StreamId>,
 }
 
 impl ConnState {
     fn new(conn: Connection) -> Self {
         Self {
-            conn,
-            readers: HashMap::new(),
-            writers: HashMap::new(),
+            base: ConnBase::new(conn),
+            streams: HashMap::new(),
+            throttled: VecDeque::new(),
         }
     }
 
-    pub(super) fn open(&mut self, dir: Dir) -> Result<StreamId> {
-        self.conn.streams().open(dir).ok_or(Error::other(format!(
-            "Failed to open new QUIC stream in direction {:?}: exhausted",
-            dir
-        )))
+    /// Opens and stages a stream, `None` while the peer grants no credit for one.
+    pub(super) fn open(&mut self, dir: Dir) -> Option<(StreamId, Arc<StreamStaging>)> {
+        let id = self.base.conn.streams().open(dir)?;
+        Some((id, self.stage(id)))
     }
 
-    pub(super) fn accept(&mut self, dir: Dir) -> Result<StreamId> {
-        self.conn.streams().accept(dir).ok_or(Error::other(format!(
-            "Failed to accept new QUIC stream in direction {:?}: no incoming",
-            dir
-        )))
+    pub(super) fn stage(&mut self, id: StreamId) -> Arc<StreamStaging> {
+        self.streams.entry(id).or_default().clone()
+    }
+
+    pub(super) fn flush(&mut self, id: StreamId) {
+        let Some(staging) = self.streams.get(&id) else {
+            return;
+        };
+        let base = &mut self.base;
+        let quota = base.egress.quota(Instant::now());
+        let mut budget = quota.unwrap_or(usize::MAX);
+        let flushed = staging.flush(&mut base.conn, id, &mut budget);
+        if let Some(quota) = quota {
+            base.egress.consume(quota - budget);
+        }
+        if flushed.throttled && !self.throttled.contains(&id) {
+            self.throttled.push_back(id);
+        }
+        if flushed.forget {
+            self.streams.remove(&id);
+        }
+    }
+
+    /// Records that the peer stopped stream `id` and lets go of its staging if nothing else
+    /// holds it.
+    pub(super) fn stopped(&mut self, id: StreamId, code: VarInt) {
+        if let Some(staging) = self.streams.get(&id) {
+            staging.stopped(code);
+        }
+        self.flush(id);
+    }
+
+    /// Flushes the throttled streams again once the egress bucket refilled a granule.
+    pub(super) fn flush_throttled(&mut self) {
+        if self.throttled.is_empty() || !self.egress.allows(WRITE_GRANULE, Instant::now()) {
+            return;
+        }
+        for id in std::mem::take(&mut self.throttled) {
+            self.flush(id);
+        }
+    }
+
+    /// See [`ConnBase::timeout`].
+    pub(super) fn timeout(&mut self) -> Option<Instant> {
+        let throttled = !self.throttled.is_empty();
+        self.base.timeout(throttled)
+    }
+
+    pub(super) fn pump(&mut self, id: StreamId) {
+        if let Some(staging) = self.streams.get(&id) {
+            staging.pump(&mut self.base.conn, id);
+        }
     }
 
     pub(super) fn close(&mut self, id: StreamId, reset: bool) {
         let _ = self.conn.recv_stream(id).stop(VarInt::from_u32(0));
-        if let Some(waker) = self.readers.remove(&id) {
-            waker.wake();
-        }
         if reset {
             let _ = self.conn.send_stream(id).reset(VarInt::from_u32(0));
         }
-        if let Some(waker) = self.writers.remove(&id) {
-            waker.wake();
+        let forget = match self.streams.get(&id) {
+            Some(staging) => reset || staging.close(),
+            None => return,
+        };
+        if forget {
+            self.streams.remove(&id);
+        } else {
+            self.flush(id);
         }
     }
 
-    pub(crate) fn clear(&mut self) {
-        for (_, waker) in chain(self.readers.drain(), self.writers.drain()) {
-            waker.wake();
+    /// Settles 0-RTT once the handshake completed. The stagings of the streams a rejected
+    /// handshake made quinn-proto forget are failed and dropped before any new stream is
+    /// staged under a reused ID.
+    pub(super) fn settle_early_data(&mut self) {
+        if !self.early_data_rejected() {
+            return;
+        }
+        for (_, staging) in self.streams.drain() {
+            staging.abandon(QuicError::EarlyDataRejected);
+        }
+    }
+
+    /// Fails every staged stream with the first reason the connection ended for.
+    pub(crate) fn clear(&mut self, reason: QuicError) {
+        let reason = self.base.closed.get_or_insert(reason).clone();
+        for (_, staging) in self.streams.drain() {
+            staging.fail(reason.clone());
         }
     }
 
@@ -66,7 +399,7 @@ impl ConnState {
             VarInt::from_u32(1),
             "QUIC connection destroyed".into(),
         );
-        self.clear();
+        self.clear(QuicError::ConnectionClosed);
     }
 }
 
@@ -85,53 +418,236 @@ impl From<ConnState> for SharedConnState {
 }
 
 type ConnEvtQueue = Arc<ArrayQueue<ConnectionEvent>>;
-type StreamOpenQueue = Arc<SegQueue<(Dir, oneshot::Sender<Result<StreamId>>)>>;
-type StreamCloseQueue = Arc<SegQueue<StreamId>>;
+pub(super) type StreamOpenTx<S> = oneshot::Sender<Result<S>>;
+type StreamOpenQueue = Arc<SegQueue<(Dir, StreamOpenTx<QuicStream>)>>;
+type StreamIdQueue = Arc<SegQueue<StreamId>>;
+type ProfileQueue = Arc<SegQueue<TransportProfile>>;
 
 const QUIC_CONN_EVT_QUEUE_CAPACITY: usize = 1024;
 
-#[derive(Debug, Clone)]
+/// What happens to a packet arriving while its connection's inbox is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InboxOverflow {
+    /// Drop the packet and count it in [`QuicEndpointStats::inbox_dropped`](super::QuicEndpointStats).
+    #[default]
+    Drop,
+    /// Make [`QuicEndpoint::send`](super::QuicEndpoint::send) wait until the runner has
+    /// drained the inbox, pushing back on the packet source. A source feeding all
+    /// connections from one loop, like a single socket, then stalls them all behind the
+    /// slowest one. Only pick this when each source feeds one connection, or when stalling
+    /// is preferable to losing packets.
+    Backpressure,
+}
+
+/// Per-connection queue of received packets waiting for the runner.
+#[derive(Debug, Clone, Copy)]
+pub struct InboxConfig {
+    pub capacity: usize,
+    pub overflow: InboxOverflow,
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        Self {
+            capacity: QUIC_CONN_EVT_QUEUE_CAPACITY,
+            overflow: InboxOverflow::Drop,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct InboxCounters {
+    pub(super) dropped: AtomicU64,
+    pub(super) waits: AtomicU64,
+}
+
+#[derive(Debug)]
 pub(super) struct ConnCtrl {
+    pub(super) addr: SocketAddr,
     pub(super) state: SharedConnState,
     pub(super) inbox: ConnEvtQueue,
+    overflow: InboxOverflow,
+    counters: Arc<InboxCounters>,
+    /// Signalled by the runner after it drained the inbox.
+    pub(super) drained: Arc<Notify>,
+    /// Signalled by the runner once the handshake completed or the connection is gone.
+    pub(super) connected: Arc<Notify>,
+    /// Set once the runner has exited and will never drain the inbox again.
+    pub(super) closed: Arc<AtomicBool>,
     pub(super) open: StreamOpenQueue,
-    pub(super) close: StreamCloseQueue,
+    pub(super) close: StreamIdQueue,
+    /// Streams with staged writes for the runner to flush.
+    pub(super) flush: StreamIdQueue,
+    /// Streams whose receive staging drained enough for the runner to pull more.
+    pub(super) pump: StreamIdQueue,
+    /// Limits changed on the live connection, applied by the runner in order.
+    pub(super) profile: ProfileQueue,
     pub(super) notify: Arc<Notify>,
     pub(super) shutdown: Arc<AtomicBool>,
 }
 
 impl ConnCtrl {
-    pub(super) fn new(conn: Connection) -> Self {
+    pub(super) fn new(conn: Connection, inbox: InboxConfig, counters: Arc<InboxCounters>) -> Self {
         Self {
+            addr: conn.remote_address(),
             state: ConnState::new(conn).into(),
-            inbox: ArrayQueue::new(QUIC_CONN_EVT_QUEUE_CAPACITY).into(),
+            inbox: ArrayQueue::new(inbox.capacity.max(1)).into(),
+            overflow: inbox.overflow,
+            counters,
+            drained: Arc::new(Notify::new()),
+            connected: Arc::new(Notify::new()),
+            closed: Arc::new(AtomicBool::new(false)),
             open: SegQueue::new().into(),
             close: SegQueue::new().into(),
+            flush: SegQueue::new().into(),
+            pump: SegQueue::new().into(),
+            profile: SegQueue::new().into(),
             notify: Arc::new(Notify::new()),
             shutdown: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub(super) fn send(&self, evt: ConnectionEvent) {
-        let _ = self.inbox.push(evt);
+    pub(super) fn wake(&self) {
         self.notify.notify_one();
     }
 
+    pub(super) async fn send(&self, mut evt: ConnectionEvent) {
+        loop {
+            evt = match self.inbox.push(evt) {
+                Ok(()) => break,
+                Err(evt) => evt,
+            };
+            if self.overflow == InboxOverflow::Drop {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                trace!("Inbox of connection to {:?} is full, packet dropped", self.addr);
+                break;
+            }
+
+            // 先登记等待再重试，避免 runner 在两者之间清空队列导致错过通知
+            let drained = self.drained.notified();
+            let mut drained = pin!(drained);
+            drained.as_mut().enable();
+            evt = match self.inbox.push(evt) {
+                Ok(()) => break,
+                Err(evt) => evt,
+            };
+            if self.closed.load(Ordering::Acquire) {
+                break;
+            }
+            self.counters.waits.fetch_add(1, Ordering::Relaxed);
+            self.wake();
+            drained.await;
+        }
+        self.wake();
+    }
+
     pub(super) async fn open(&self, dir: Dir) -> Result<QuicStream> {
         let (tx, rx) = oneshot::channel();
         self.open.push((dir, tx));
-        self.notify.notify_one();
-        let id = rx.await.map_err(|e| Error::other(format!("Failed to receive stream ID from runner: {:?}", e)))??;
-        Ok(QuicStream::new(id, self.clone()))
+        // runner 已退出时不会再处理队列，由这里代为应答
+        if self.closed.load(Ordering::Acquire) {
+            self.refuse_opens();
+        }
+        self.wake();
+        rx.await.map_err(|_| Error::from(self.state.lock().reason()))?
+    }
+
+    /// Fails every queued open with the reason the connection ended.
+    pub(super) fn refuse_opens(&self) {
+        let reason = self.state.lock().reason();
+        while let Some((_, tx)) = self.open.pop() {
+            let _ = tx.send(Err(reason.clone().into()));
+        }
+    }
+
+    pub(super) fn peer_identity(&self) -> Option<Arc<PeerIdentity>> {
+        self.state.lock().peer_identity()
+    }
+
+    pub(super) fn server_name(&self) -> Option<String> {
+        self.state.lock().server_name()
+    }
+
+    pub(super) fn alpn(&self) -> Option<Vec<u8>> {
+        self.state.lock().alpn()
+    }
+
+    pub(super) async fn early_data(&self) -> Result<EarlyData> {
+        loop {
+            // 先登记等待再检查，避免错过 runner 的通知
+            let connected = self.connected.notified();
+            let mut connected = pin!(connected);
+            connected.as_mut().enable();
+            {
+                let state = self.state.lock();
+                if let Some(reason) = &state.closed {
+                    return Err(reason.clone().into());
+                }
+                if self.closed.load(Ordering::Acquire) || state.conn.is_closed() {
+                    return Err(QuicError::ConnectionClosed.into());
+                }
+                if !state.conn.is_handshaking() {
+                    return Ok(EarlyData::of(&state.conn));
+                }
+            }
+            connected.await;
+        }
+    }
+
+    pub(super) fn send_datagram(&self, data: Bytes) -> Result<()> {
+        let res = self.state.lock().conn.datagrams().send(data, true);
+        match res {
+            Ok(()) => {
+                self.wake();
+                Ok(())
+            }
+            Err(SendDatagramError::TooLarge) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "QUIC datagram exceeds the maximum datagram size",
+            )),
+            Err(e) => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("Failed to send QUIC datagram: {:?}", e),
+            )),
+        }
+    }
+
+    pub(super) fn max_datagram_size(&self) -> Option<usize> {
+        self.state.lock().conn.datagrams().max_size()
     }
 
     pub(super) fn close(&self, id: StreamId) {
         self.close.push(id);
-        self.notify.notify_one();
+        self.wake();
+    }
+
+    pub(super) fn flush(&self, id: StreamId) {
+        self.flush.push(id);
+        self.wake();
+    }
+
+    pub(super) fn pump(&self, id: StreamId) {
+        self.pump.push(id);
+        self.wake();
+    }
+
+    /// Logs a packet passed to the connection, if it keeps a qlog.
+    pub(super) fn qlog_received(&self, packet: Received) {
+        self.state.lock().qlog_received(packet);
+    }
+
+    pub(super) fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        self.state.lock().egress.set(limit);
+        self.wake();
+    }
+
+    pub(super) fn set_profile(&self, profile: TransportProfile) {
+        self.profile.push(profile);
+        self.wake();
     }
 
     pub(super) fn shutdown(&self) {
-        self.shutdown.store(true, std::sync::atomic::Ordering::SeqCst);
-        self.notify.notify_one();
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.wake();
     }
 }
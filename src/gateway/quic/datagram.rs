@@ -0,0 +1,13 @@
+use bytes::Bytes;
+use derive_more::Constructor;
+use std::net::SocketAddr;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Constructor)]
+pub struct QuicDatagram {
+    pub addr: SocketAddr,
+    pub payload: Bytes,
+}
+
+pub(super) type QuicDatagramTx = mpsc::Sender<QuicDatagram>;
+pub type QuicDatagramRx = mpsc::Receiver<QuicDatagram>;
@@ -0,0 +1,87 @@
+use crate::gateway::quic::conn::ConnCtrl;
+use crate::gateway::quic::error::QuicError;
+use crate::gateway::quic::runner::Runner;
+use dashmap::DashMap;
+use derive_more::Debug;
+use derive_more::Constructor;
+use quinn_proto::ConnectionHandle;
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{error, info};
+
+/// Connection handles are only unique within the shard that issued them.
+pub(super) type ShardHandle = (usize, ConnectionHandle);
+
+type RunnerTx = mpsc::UnboundedSender<RunnerGuard>;
+type RunnerRx = mpsc::UnboundedReceiver<RunnerGuard>;
+
+#[derive(Debug, Constructor)]
+pub(super) struct RunnerGuard {
+    hdl: ShardHandle,
+    #[debug(skip)]
+    ctrls: Arc<DashMap<ShardHandle, Arc<ConnCtrl>>>,
+    addr: SocketAddr,
+    #[debug(skip)]
+    conns: Arc<DashMap<SocketAddr, ShardHandle>>,
+    runner: Runner,
+}
+
+impl Drop for RunnerGuard {
+    fn drop(&mut self) {
+        self.ctrls.remove(&self.hdl);
+        self.conns.remove(&self.addr);
+    }
+}
+
+/// Hands new connections to the driver task, which runs each in a task of its own.
+#[derive(Debug)]
+pub(super) struct DriverTx(RunnerTx);
+
+impl DriverTx {
+    pub(super) fn spawn() -> Self {
+        let (tx, mut driver) = Driver::new();
+        tokio::spawn(async move { driver.run().await });
+        DriverTx(tx)
+    }
+
+    pub(super) fn dispatch(&self, guard: RunnerGuard) -> Result<()> {
+        // 驱动任务已退出，端点不再可用
+        self.0.send(guard).map_err(|_| QuicError::EndpointClosed.into())
+    }
+}
+
+#[derive(Debug)]
+struct Driver {
+    tasks: JoinSet<()>,
+    rx: RunnerRx,
+}
+
+impl Driver {
+    fn new() -> (RunnerTx, Self) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (
+            tx,
+            Self {
+                tasks: JoinSet::new(),
+                rx,
+            },
+        )
+    }
+    async fn run(&mut self) {
+        while let Some(mut guard) = self.rx.recv().await {
+            self.tasks.spawn(async move {
+                let res = guard.runner.run().await;
+                if let Err(e) = res
+                    && !guard.runner.shutdown.load(Ordering::Relaxed)
+                {
+                    error!("Runner exited with error: {:?}", e);
+                }
+            });
+        }
+        info!("Driver exited.");
+    }
+}
@@ -1,93 +1,55 @@
-use crate::gateway::quic::conn::ConnCtrl;
+use crate::gateway::quic::capture::CaptureConfig;
+use crate::gateway::quic::conn::{ConnBase, ConnCtrl, InboxConfig, InboxCounters};
+use crate::gateway::quic::datagram::{QuicDatagramRx, QuicDatagramTx};
+use crate::gateway::quic::driver::{DriverTx, RunnerGuard, ShardHandle};
+use crate::gateway::quic::error::QuicError;
+use crate::gateway::quic::framer::PacketFramer;
 use crate::gateway::quic::packet::{PacketPool, QuicPacketMargins, QuicPacketRx, QuicPacketTx};
+use crate::gateway::quic::qlog::{Qlog, Received};
 use crate::gateway::quic::runner::Runner;
+use crate::gateway::quic::shaper::RateLimit;
+use crate::gateway::quic::shard::{route, Coordinator, ShardedCidGenerator};
 use crate::gateway::quic::stream::{QuicStream, QuicStreamRx, QuicStreamTx};
+use crate::gateway::quic::tls::{Authorizer, EarlyData, PeerIdentity, TlsConfig};
+use crate::gateway::quic::transport::{CongestionControl, DefaultTransport, MtuConfig, PeerTransport, TransportProfile};
 use crate::gateway::quic::utils::switched_channel;
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use dashmap::DashMap;
 use derive_more::Debug;
-use derive_more::{Constructor, Deref, DerefMut};
+use derive_more::{Deref, DerefMut};
 use parking_lot::Mutex;
-use quinn_plaintext::{client_config, server_config};
-use quinn_proto::congestion::BbrConfig;
 use quinn_proto::{
-    AcceptError, ClientConfig, Connection, ConnectionHandle, DatagramEvent, Dir, Endpoint,
-    EndpointConfig, Incoming, TransportConfig, VarInt,
+    AcceptError, ClientConfig, Connection, ConnectionStats, DatagramEvent, Dir, Endpoint,
+    EndpointConfig, Incoming, ServerConfig,
 };
 use std::cell::RefCell;
+use std::future::Future;
 use std::io::{Error, ErrorKind, Result};
 use std::mem::take;
 use std::net::SocketAddr;
-use std::sync::atomic::Ordering;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc;
-use tokio::task::JoinSet;
-use tracing::{error, info, trace};
-
-type RunnerTx = mpsc::UnboundedSender<RunnerGuard>;
-type RunnerRx = mpsc::UnboundedReceiver<RunnerGuard>;
-
-#[derive(Debug, Constructor)]
-struct RunnerGuard {
-    hdl: ConnectionHandle,
-    #[debug(skip)]
-    ctrls: Arc<DashMap<ConnectionHandle, ConnCtrl>>,
-    addr: SocketAddr,
-    #[debug(skip)]
-    conns: Arc<DashMap<SocketAddr, ConnectionHandle>>,
-    runner: Runner,
-}
-
-impl Drop for RunnerGuard {
-    fn drop(&mut self) {
-        self.ctrls.remove(&self.hdl);
-        self.conns.remove(&self.addr);
-    }
-}
-
-#[derive(Debug)]
-struct Driver {
-    tasks: JoinSet<()>,
-    rx: RunnerRx,
-}
-
-impl Driver {
-    fn new() -> (RunnerTx, Self) {
-        let (tx, rx) = mpsc::unbounded_channel();
-        (
-            tx,
-            Self {
-                tasks: JoinSet::new(),
-                rx,
-            },
-        )
-    }
-    async fn run(&mut self) {
-        while let Some(mut guard) = self.rx.recv().await {
-            self.tasks.spawn(async move {
-                let res = guard.runner.run().await;
-                if let Err(e) = res
-                    && !guard.runner.shutdown.load(Ordering::Relaxed)
-                {
-                    error!("Runner exited with error: {:?}", e);
-                }
-            });
-        }
-        info!("Driver exited.");
-    }
-}
+use tokio::time::timeout;
+use tracing::{error, trace, warn};
 
 #[derive(Debug)]
 pub struct QuicOutputRx {
     pub packet: QuicPacketRx,
     pub stream: QuicStreamRx,
+    pub datagram: QuicDatagramRx,
 }
 
 #[derive(Debug, Clone)]
 pub(super) struct QuicOutputTx {
     pub(super) packet: QuicPacketTx,
     pub(super) stream: QuicStreamTx,
+    pub(super) datagram: QuicDatagramTx,
+    /// Stream channels by ALPN protocol, in place of `stream`.
+    pub(super) routes: Arc<DashMap<Vec<u8>, QuicStreamTx>>,
 }
 
 thread_local! {
@@ -112,49 +74,174 @@ impl Drop for BufferGuard {
     }
 }
 
+/// What [`QuicEndpoint`] and [`LocalQuicEndpoint`](super::local::LocalQuicEndpoint) hand
+/// down to the connections they establish.
+#[derive(Debug)]
+pub(super) struct EndpointSettings {
+    pub(super) client_config: ClientConfig,
+    pub(super) transport: DefaultTransport,
+    /// Built from configs the caller passed in, which setters must not replace.
+    pub(super) custom: bool,
+    pub(super) rate_limit: Option<RateLimit>,
+    #[debug(skip)]
+    pub(super) authorizer: Option<Authorizer>,
+    /// Directory connections write their qlog to.
+    pub(super) qlog: Option<PathBuf>,
+    /// How long opening a stream waits for stream credit.
+    pub(super) open_timeout: Option<Duration>,
+}
+
+impl EndpointSettings {
+    pub(super) fn new(client_config: ClientConfig, custom: bool) -> Self {
+        Self {
+            client_config,
+            transport: DefaultTransport::default(),
+            custom,
+            rate_limit: None,
+            authorizer: None,
+            qlog: None,
+            open_timeout: None,
+        }
+    }
+
+    /// Switches the default configs to `tls`, leaving it to [`Self::reconfigure`] to
+    /// rebuild them.
+    pub(super) fn set_tls(&mut self, tls: Option<TlsConfig>) -> Result<()> {
+        if self.custom {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "TLS of an endpoint with custom configs is set through those configs",
+            ));
+        }
+        self.transport.tls = tls.as_ref().map(TlsConfig::build).transpose()?;
+        Ok(())
+    }
+
+    /// Rebuilds the default configs from `transport` and passes the server config to
+    /// `apply`, for each quinn-proto endpoint to take. Custom configs are left alone.
+    pub(super) fn reconfigure(&mut self, mut apply: impl FnMut(Option<Arc<ServerConfig>>)) {
+        if self.custom {
+            warn!("Endpoint uses custom configs, leaving them unchanged");
+            return;
+        }
+        apply(self.transport.server_config().map(Arc::new));
+        self.client_config = self.transport.client_config();
+    }
+
+    /// Config to connect to a peer with, `peer`'s if it has its own.
+    pub(super) fn client_config(&self, peer: Option<&PeerTransport>) -> ClientConfig {
+        match peer {
+            Some(peer) => peer.client.clone(),
+            None => self.client_config.clone(),
+        }
+    }
+
+    /// Applies the settings to a connection being established, with the qlog from
+    /// [`Self::open_qlog`].
+    pub(super) fn configure(&self, base: &mut ConnBase, qlog: Option<Qlog>) {
+        base.egress.set(self.rate_limit);
+        base.authorizer = self.authorizer.clone();
+        base.profiles = self.transport.tls.as_ref().map(|tls| tls.profiles.clone());
+        base.qlog = qlog;
+    }
+
+    /// Creates the qlog file of `conn` in `dir`, the [`Self::qlog`] taken out of the
+    /// settings. Done with neither the settings nor the connection's state locked, so
+    /// that file I/O does not hold them up.
+    pub(super) fn open_qlog(dir: Option<&Path>, conn: &Connection) -> Option<Qlog> {
+        let dir = dir?;
+        Qlog::create(dir, conn)
+            .inspect_err(|e| error!("Connection to {:?} goes without qlog: {:?}", conn.remote_address(), e))
+            .ok()
+    }
+
+    /// Waits for `open` to get a stream, failing with [`QuicError::StreamsExhausted`] once
+    /// `limit`, the [`Self::open_timeout`] taken out of the settings, passes.
+    pub(super) async fn open<S>(limit: Option<Duration>, open: impl Future<Output = Result<S>>) -> Result<S> {
+        // 超时后丢弃等待中的请求，runner 不会再为其开流
+        match limit {
+            Some(limit) => timeout(limit, open)
+                .await
+                .map_err(|_| QuicError::StreamsExhausted)?,
+            None => open.await,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuicEndpointStats {
+    /// Packets dropped because their connection's inbox was full.
+    pub inbox_dropped: u64,
+    /// Times [`QuicEndpoint::send`] waited for a full inbox to drain.
+    pub inbox_waits: u64,
+}
+
 #[derive(Debug)]
 pub struct QuicEndpoint {
-    endpoint: Mutex<Endpoint>,
-    client_config: ClientConfig,
-    driver: RunnerTx,
-    ctrls: Arc<DashMap<ConnectionHandle, ConnCtrl>>,
-    conns: Arc<DashMap<SocketAddr, ConnectionHandle>>,
+    /// Independent quinn-proto endpoints, each owning the connections whose IDs it issued.
+    shards: Box<[Mutex<Endpoint>]>,
+    /// Round-robin cursor picking the shard of outgoing connections.
+    next_shard: AtomicUsize,
+    /// Picks the shard of incoming connections.
+    coordinator: Mutex<Coordinator>,
+    settings: Mutex<EndpointSettings>,
+    /// Whether `settings` has qlog on, so that [`Self::send`] need not lock them for
+    /// every packet.
+    qlog: AtomicBool,
+    /// Per-peer configs overriding the endpoint's.
+    peers: DashMap<SocketAddr, PeerTransport>,
+    driver: DriverTx,
+    ctrls: Arc<DashMap<ShardHandle, Arc<ConnCtrl>>>,
+    conns: Arc<DashMap<SocketAddr, ShardHandle>>,
     output: QuicOutputTx,
+    inbox: Mutex<InboxConfig>,
+    counters: Arc<InboxCounters>,
 }
 
 impl QuicEndpoint {
+    /// Creates an endpoint with a single quinn-proto endpoint. [`Self::sharded`] spreads
+    /// ingress over several.
     pub fn new(packet_margins: QuicPacketMargins) -> (Self, QuicOutputRx) {
-        let mut server_config = server_config();
-        server_config.transport = {
-            let mut config = TransportConfig::default();
-
-            config.initial_mtu(65535);
-            config.min_mtu(65535);
+        Self::sharded(1, packet_margins)
+    }
 
-            config.stream_receive_window(VarInt::from_u32(10 * 1024 * 1024));
-            config.receive_window(VarInt::from_u32(15 * 1024 * 1024));
+    /// Like [`Self::new`] with the margins of `framer`, set through [`Self::set_framer`].
+    pub fn with_framer(framer: Arc<dyn PacketFramer>) -> (Self, QuicOutputRx) {
+        let (endpoint, output) = Self::new(framer.margins());
+        endpoint
+            .set_framer(framer)
+            .expect("A new endpoint takes a framer with its own margins");
+        (endpoint, output)
+    }
 
-            config.max_concurrent_bidi_streams(VarInt::from_u32(1024));
-            config.max_concurrent_uni_streams(VarInt::from_u32(1024));
+    /// Creates an endpoint whose ingress is spread over `shards` quinn-proto endpoints, e.g.
+    /// one per core, instead of going through a single one. New connections are assigned
+    /// to the shards in turn.
+    pub fn sharded(shards: usize, packet_margins: QuicPacketMargins) -> (Self, QuicOutputRx) {
+        let client_config = DefaultTransport::default().client_config();
+        let endpoints = Self::default_endpoints(shards);
+        Self::build(endpoints, client_config, packet_margins, false)
+    }
 
-            config.congestion_controller_factory(Arc::new(BbrConfig::default()));
+    fn default_endpoints(shards: usize) -> Vec<Endpoint> {
+        (0..shards)
+            .map(|shard| Self::default_endpoint(shard, shards))
+            .collect()
+    }
 
-            config.keep_alive_interval(Some(Duration::from_secs(5)));
-            config.max_idle_timeout(Some(VarInt::from_u32(30_000).into()));
+    pub(super) fn default_endpoint(shard: usize, shards: usize) -> Endpoint {
+        let server_config = DefaultTransport::default().server_config().map(Arc::new);
 
-            Arc::new(config)
-        };
+        let mut endpoint_config = EndpointConfig::default();
+        endpoint_config.cid_generator(move || Box::new(ShardedCidGenerator::new(shard, shards)));
 
-        let endpoint_config = EndpointConfig::default();
-
-        let endpoint = Endpoint::new(
+        Endpoint::new(
             Arc::from(endpoint_config),
-            Some(Arc::from(server_config)),
-            false,
+            server_config,
+            // 出站包从不分片，允许 PLPMTUD
+            true,
             None,
-        );
-
-        Self::with_endpoint(endpoint, client_config(), packet_margins)
+        )
     }
 
     #[inline]
@@ -163,81 +250,306 @@ impl QuicEndpoint {
         client_config: ClientConfig,
         packet_margins: QuicPacketMargins,
     ) -> (Self, QuicOutputRx) {
+        Self::with_endpoints(vec![endpoint], client_config, packet_margins)
+    }
+
+    /// Shards ingress over `endpoints`. With more than one, endpoint `i` must issue
+    /// connection IDs through [`ShardedCidGenerator::new(i, endpoints.len())`](ShardedCidGenerator::new).
+    #[inline]
+    pub fn with_endpoints(
+        endpoints: Vec<Endpoint>,
+        client_config: ClientConfig,
+        packet_margins: QuicPacketMargins,
+    ) -> (Self, QuicOutputRx) {
+        Self::build(endpoints, client_config, packet_margins, true)
+    }
+
+    fn build(
+        endpoints: Vec<Endpoint>,
+        client_config: ClientConfig,
+        packet_margins: QuicPacketMargins,
+        custom: bool,
+    ) -> (Self, QuicOutputRx) {
+        assert!(!endpoints.is_empty(), "QuicEndpoint needs at least one endpoint");
         let (packet_tx, packet_rx) = mpsc::channel(1024);
         let (stream_tx, stream_rx) = switched_channel(512);
+        let (datagram_tx, datagram_rx) = mpsc::channel(1024);
         let output_tx = QuicOutputTx {
             packet: QuicPacketTx::new(packet_tx, packet_margins),
             stream: stream_tx,
+            datagram: datagram_tx,
+            routes: Arc::default(),
         };
         let output_rx = QuicOutputRx {
             packet: packet_rx,
             stream: stream_rx,
+            datagram: datagram_rx,
         };
 
-        let (runner_tx, mut driver) = Driver::new();
-        tokio::spawn(async move { driver.run().await });
-
         (
             Self {
-                endpoint: endpoint.into(),
-                client_config,
-                driver: runner_tx,
+                shards: endpoints.into_iter().map(Mutex::new).collect(),
+                next_shard: AtomicUsize::new(0),
+                coordinator: Mutex::default(),
+                settings: Mutex::new(EndpointSettings::new(client_config, custom)),
+                qlog: AtomicBool::new(false),
+                peers: DashMap::new(),
+                driver: DriverTx::spawn(),
                 ctrls: DashMap::new().into(),
                 conns: DashMap::new().into(),
                 output: output_tx,
+                inbox: Mutex::new(InboxConfig::default()),
+                counters: Arc::default(),
             },
             output_rx,
         )
     }
 
-    fn establish(&self, hdl: ConnectionHandle, conn: Connection) -> Result<ConnCtrl> {
-        let addr = conn.remote_address();
-        let (ctrl, runner) = Runner::new(conn, self.output.clone());
-        self.driver
-            .send(RunnerGuard::new(
-                hdl,
-                self.ctrls.clone(),
-                addr,
-                self.conns.clone(),
-                runner,
+    /// Sets the inbox of connections established from now on.
+    pub fn set_inbox(&self, inbox: InboxConfig) {
+        *self.inbox.lock() = inbox;
+    }
+
+    /// Sets the egress rate limit of connections established from now on. Only stream data
+    /// is metered, ACKs and other control frames always go out.
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        self.settings.lock().rate_limit = limit;
+    }
+
+    /// Sets the authorizer of connections established from now on. Streams it denies are
+    /// stopped and reset with [`STREAM_UNAUTHORIZED`](super::STREAM_UNAUTHORIZED) and never
+    /// reach [`QuicOutputRx::stream`].
+    pub fn set_authorizer(&self, authorizer: Option<Authorizer>) {
+        self.settings.lock().authorizer = authorizer;
+    }
+
+    /// Makes connections established from now on write a qlog trace to a file of their
+    /// own in `dir`, or stops with `None`. Connections whose file cannot be created go on
+    /// without one.
+    pub fn set_qlog(&self, dir: Option<PathBuf>) {
+        let mut settings = self.settings.lock();
+        self.qlog.store(dir.is_some(), Ordering::Relaxed);
+        settings.qlog = dir;
+    }
+
+    /// Bounds how long [`Self::open`] waits for the peer to allow another stream. It fails
+    /// with [`QuicError::StreamsExhausted`] once `timeout` passes, `None` waits as long as
+    /// the connection lives.
+    pub fn set_open_timeout(&self, timeout: Option<Duration>) {
+        self.settings.lock().open_timeout = timeout;
+    }
+
+    /// Makes `framer` fill the packet margins on egress and check and strip them from every
+    /// packet passed to [`Self::send`]. Its margins must be the endpoint's, and it can be
+    /// set once, before packets are exchanged, since it changes what goes on the wire.
+    pub fn set_framer(&self, framer: Arc<dyn PacketFramer>) -> Result<()> {
+        self.output.packet.set_framer(framer)
+    }
+
+    /// Records the packets passed to [`Self::send`] and handed to [`QuicOutputRx::packet`]
+    /// to a pcapng file at `path`, as bare QUIC datagrams in synthetic IP and UDP headers.
+    /// Replaces a running capture. Stops by itself once the file reaches `config.max_size`.
+    pub fn start_capture(&self, path: impl AsRef<Path>, config: CaptureConfig) -> Result<()> {
+        self.output.packet.capture.start(path.as_ref(), config)
+    }
+
+    /// Stops the running capture, flushing its file.
+    pub fn stop_capture(&self) {
+        self.output.packet.capture.stop();
+    }
+
+    /// Whether a capture is running, i.e. started and not stopped or full.
+    pub fn is_capturing(&self) -> bool {
+        self.output.packet.capture.is_on()
+    }
+
+    /// Changes the egress rate limit of the live connection to `addr`.
+    pub fn set_conn_rate_limit(&self, addr: SocketAddr, limit: Option<RateLimit>) -> Result<()> {
+        self.ctrl(addr)?.set_rate_limit(limit);
+        Ok(())
+    }
+
+    /// Applies the fields `profile` sets to the live connection to `addr`, e.g. to raise
+    /// or lower how many streams the peer may open or how much it may send ahead. The
+    /// runner applies it in its loop. Lowered limits do not take back credit the peer
+    /// was already granted.
+    pub fn set_conn_profile(&self, addr: SocketAddr, profile: TransportProfile) -> Result<()> {
+        self.ctrl(addr)?.set_profile(profile);
+        Ok(())
+    }
+
+    fn ctrl(&self, addr: SocketAddr) -> Result<Arc<ConnCtrl>> {
+        self.conns
+            .get(&addr)
+            .and_then(|hdl| self.ctrls.get(&*hdl).map(|ctrl| ctrl.clone()))
+            .ok_or(Error::new(
+                ErrorKind::NotFound,
+                format!("No connection to {:?}", addr),
             ))
-            .map_err(|e| Error::other(format!("Failed to send runner to driver: {:?}", e)))?;
+    }
+
+    /// Switches connections established from now on to TLS, or back to plaintext with
+    /// `None`. Fails if `tls` cannot be turned into a QUIC-compatible rustls config, or
+    /// with [`ErrorKind::Unsupported`] on an endpoint built with custom configs.
+    pub fn set_tls(&self, tls: Option<TlsConfig>) -> Result<()> {
+        let mut settings = self.settings.lock();
+        settings.set_tls(tls)?;
+        self.reconfigure(&mut settings);
+        Ok(())
+    }
+
+    /// Waits for the handshake with `addr` to complete and reports whether the data sent
+    /// before it, on streams [`Self::open`]ed with a resumed session, was accepted.
+    pub async fn early_data(&self, addr: SocketAddr) -> Result<EarlyData> {
+        self.ctrl(addr)?.early_data().await
+    }
+
+    /// Rebuilds the default server and client configs around `mtu` for connections
+    /// established from now on. Configs passed to [`Self::with_endpoints`] are left alone,
+    /// those should go through [`MtuConfig::apply`] instead.
+    pub fn set_mtu(&self, mtu: MtuConfig) {
+        let mut settings = self.settings.lock();
+        settings.transport.mtu = mtu;
+        self.reconfigure(&mut settings);
+    }
+
+    /// Like [`Self::set_mtu`], switching the congestion controller. Custom configs should
+    /// go through [`CongestionControl::apply`] instead.
+    pub fn set_congestion(&self, congestion: CongestionControl) {
+        let mut settings = self.settings.lock();
+        settings.transport.congestion = congestion;
+        self.reconfigure(&mut settings);
+    }
+
+    /// Like [`Self::set_mtu`], limiting how many bytes the peer may send ahead on each
+    /// stream. This is what quinn-proto buffers for a stream besides the window of
+    /// [`QuicStream::set_receive_window`], and cannot change once a connection is up.
+    pub fn set_stream_receive_window(&self, window: u32) {
+        let mut settings = self.settings.lock();
+        settings.transport.stream_receive_window = Some(window);
+        self.reconfigure(&mut settings);
+    }
+
+    /// Uses `congestion` for connections with `addr` established from now on, on top of
+    /// the current default settings. `None` reverts to the endpoint's controller.
+    ///
+    /// The override is kept, also for reconnections, until it is reverted, not dropped when
+    /// connections with `addr` close. Revert overrides for peers that come and go, or they
+    /// pile up.
+    pub fn set_peer_congestion(&self, addr: SocketAddr, congestion: Option<CongestionControl>) {
+        match congestion {
+            Some(congestion) => {
+                let peer = self.settings.lock().transport.peer(congestion);
+                self.peers.insert(addr, peer)
+            }
+            None => self.peers.remove(&addr).map(|(_, peer)| peer),
+        };
+    }
+
+    /// Rebuilds the configs from the locked `settings`, which stay locked until every
+    /// shard took the server config, so that concurrent setters apply in order.
+    fn reconfigure(&self, settings: &mut EndpointSettings) {
+        let shards = &self.shards;
+        settings.reconfigure(|server_config| {
+            for shard in shards {
+                shard.lock().set_server_config(server_config.clone());
+            }
+        });
+    }
+
+    pub fn stats(&self) -> QuicEndpointStats {
+        QuicEndpointStats {
+            inbox_dropped: self.counters.dropped.load(Ordering::Relaxed),
+            inbox_waits: self.counters.waits.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Statistics of the connection to `addr`, including its current MTU.
+    pub fn conn_stats(&self, addr: SocketAddr) -> Option<ConnectionStats> {
+        let hdl = *self.conns.get(&addr)?;
+        let ctrl = self.ctrls.get(&hdl)?.clone();
+        let stats = ctrl.state.lock().conn.stats();
+        Some(stats)
+    }
+
+    /// Certificate the peer at `addr` authenticated with, once verified. Servers only know
+    /// one with [`ClientAuth`](super::ClientAuth) on.
+    pub fn peer_identity(&self, addr: SocketAddr) -> Option<Arc<PeerIdentity>> {
+        self.ctrl(addr).ok()?.peer_identity()
+    }
+
+    /// Hands streams of connections negotiating `protocol` with ALPN to the returned
+    /// receiver instead of [`QuicOutputRx::stream`], from connections whose handshake gets
+    /// that far from now on. Routing a protocol again replaces its receiver.
+    pub fn route(&self, protocol: impl Into<Vec<u8>>) -> QuicStreamRx {
+        let (tx, rx) = switched_channel(512);
+        self.output.routes.insert(protocol.into(), tx);
+        rx
+    }
+
+    /// Sends streams of `protocol` to [`QuicOutputRx::stream`] again.
+    pub fn unroute(&self, protocol: &[u8]) {
+        self.output.routes.remove(protocol);
+    }
+
+    /// Application protocol negotiated with `addr`, once the handshake got that far.
+    pub fn alpn(&self, addr: SocketAddr) -> Option<Vec<u8>> {
+        self.ctrl(addr).ok()?.alpn()
+    }
+
+    /// SNI the client at `addr` asked for, on incoming TLS connections.
+    pub fn server_name(&self, addr: SocketAddr) -> Option<String> {
+        self.ctrl(addr).ok()?.server_name()
+    }
+
+    fn establish(&self, hdl: ShardHandle, conn: Connection) -> Result<Arc<ConnCtrl>> {
+        let addr = conn.remote_address();
+        let dir = self.settings.lock().qlog.clone();
+        let qlog = EndpointSettings::open_qlog(dir.as_deref(), &conn);
+        let (ctrl, runner) = Runner::new(conn, self.output.clone(), *self.inbox.lock(), self.counters.clone());
+        self.settings.lock().configure(&mut ctrl.state.lock(), qlog);
+        // 必须先注册，否则对端的首个响应包可能在 runner 启动后、注册前到达
         self.ctrls.insert(hdl, ctrl.clone());
         self.conns.insert(addr, hdl);
+        self.driver.dispatch(RunnerGuard::new(
+            hdl,
+            self.ctrls.clone(),
+            addr,
+            self.conns.clone(),
+            runner,
+        ))?;
         Ok(ctrl)
     }
 
-    fn accept(&self, incoming: Incoming) -> Result<()> {
+    fn accept(&self, shard: usize, incoming: Incoming) -> Result<()> {
         let addr = incoming.remote_address();
-        trace!("Incoming connection from {:?}", addr);
+        trace!("Incoming connection from {:?} on shard {}", addr, shard);
+        let server_config = self.peers.get(&addr).and_then(|peer| peer.server.clone());
         let mut buf = BufferGuard::new();
-        let accept = self
-            .endpoint
+        let accept = self.shards[shard]
             .lock()
-            .accept(incoming, Instant::now(), &mut buf, None);
+            .accept(incoming, Instant::now(), &mut buf, server_config);
         match accept {
             Ok((hdl, conn)) => {
                 trace!("Accepted new connection({:?}) from {:?}", hdl, addr);
-                self.establish(hdl, conn)?;
+                self.establish((shard, hdl), conn)?;
                 Ok(())
             }
             Err(AcceptError { cause, response }) => {
                 if let Some(transmit) = response {
-                    let packet = PACKET_POOL.with(|pool| {
+                    let mut packet = PACKET_POOL.with(|pool| {
                         pool.borrow_mut()
                             .pack_transmit(transmit, &buf, self.output.packet.margins)
                     });
+                    self.output.packet.frame(&mut packet);
                     let _ = self.output.packet.try_send(packet);
                 }
-                Err(Error::other(format!(
-                    "Failed to accept incoming connection: {:?}",
-                    cause
-                )))
+                Err(QuicError::AcceptFailed(cause).into())
             }
         }
     }
 
-    fn connect(&self, addr: SocketAddr, server_name: &str) -> Result<ConnCtrl> {
+    fn connect(&self, addr: SocketAddr) -> Result<Arc<ConnCtrl>> {
         if let Some(entry) = self.conns.get(&addr) {
             let hdl = *entry;
             drop(entry);
@@ -246,49 +558,89 @@ impl QuicEndpoint {
             }
         }
 
-        let (hdl, conn) = self
-            .endpoint
+        let (client_config, server_name) = {
+            let settings = self.settings.lock();
+            let client_config = settings.client_config(self.peers.get(&addr).as_deref());
+            (client_config, settings.transport.server_name(addr))
+        };
+        let shard = self.next_shard.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        let (hdl, conn) = self.shards[shard]
             .lock()
             .connect(
                 Instant::now(),
-                self.client_config.clone(),
+                client_config,
                 addr,
-                server_name,
+                &server_name,
             )
-            .map_err(|e| Error::other(format!("Failed to connect to {:?}: {:?}", addr, e)))?;
+            .map_err(QuicError::ConnectFailed)?;
 
-        self.establish(hdl, conn)
+        self.establish((shard, hdl), conn)
     }
 
+    /// Opens a bidirectional stream to `addr`, connecting first if needed. While the peer
+    /// allows no more concurrent streams, waits in line behind earlier opens.
     pub async fn open(&self, addr: SocketAddr, header: Option<BytesMut>) -> Result<QuicStream> {
-        let ctrl = self.connect(addr, "")?;
-        let stream = ctrl.open(Dir::Bi).await?;
+        let ctrl = self.connect(addr)?;
+        let limit = self.settings.lock().open_timeout;
+        let mut stream = EndpointSettings::open(limit, ctrl.open(Dir::Bi)).await?;
         if let Some(header) = header {
-            self.send(addr, header).await?;
+            stream.write_all(&header).await?;
         }
         Ok(stream)
     }
 
-    pub async fn send(&self, addr: SocketAddr, payload: BytesMut) -> Result<()> {
+    /// Queues an unreliable datagram to `addr`. Fails with [`ErrorKind::InvalidInput`]
+    /// when `payload` exceeds [`Self::max_datagram_size`].
+    pub fn send_datagram(&self, addr: SocketAddr, payload: Bytes) -> Result<()> {
+        self.connect(addr)?.send_datagram(payload)
+    }
+
+    pub fn max_datagram_size(&self, addr: SocketAddr) -> Result<Option<usize>> {
+        Ok(self.connect(addr)?.max_datagram_size())
+    }
+
+    /// Passes a packet received from `addr` to its connection. With
+    /// [`InboxOverflow::Backpressure`](super::InboxOverflow::Backpressure) this waits while
+    /// the connection's inbox is full, holding up every packet the caller would pass next,
+    /// whichever connection it belongs to.
+    pub async fn send(&self, addr: SocketAddr, mut payload: BytesMut) -> Result<()> {
+        self.output.packet.unframe(addr, &mut payload)?;
         let now = Instant::now();
         let mut buf = BufferGuard::new();
-        let event = self
-            .endpoint
-            .lock()
-            .handle(now, addr, None, None, payload, &mut buf);
+        // 数据包交给状态机后不再可见，先取出 qlog 需要的信息
+        let received = self.qlog.load(Ordering::Relaxed).then(|| Received::of(&payload));
+        let (shard, event) = match route(&payload, self.shards.len()) {
+            Some(shard) => (shard, self.shards[shard].lock().handle(now, addr, None, None, payload, &mut buf)),
+            None => {
+                // 新连接的包经协调者分配分片，持锁处理以免同一握手的包落到不同分片
+                let mut coordinator = self.coordinator.lock();
+                let shard = coordinator.shard(&payload, self.shards.len(), now);
+                let event = self.shards[shard].lock().handle(now, addr, None, None, payload, &mut buf);
+                if let Some(DatagramEvent::NewConnection(incoming)) = &event {
+                    coordinator.started(*incoming.orig_dst_cid(), shard, now);
+                }
+                (shard, event)
+            }
+        };
         match event {
             Some(DatagramEvent::NewConnection(incoming)) => {
-                if !self.output.stream.switch().load(Ordering::Relaxed) {
+                if !self.output.stream.switch().load(Ordering::Relaxed) && self.output.routes.is_empty() {
                     trace!("Incoming stream channel is closed. Connection dropped.");
                     return Ok(());
                 }
-                self.accept(incoming)
-                    .map_err(|e| Error::other(format!("Failed to accept connection: {:?}", e)))
+                self.accept(shard, incoming)?;
+                if let (Some(packet), Ok(ctrl)) = (received, self.ctrl(addr)) {
+                    ctrl.qlog_received(packet);
+                }
+                Ok(())
             }
 
             Some(DatagramEvent::ConnectionEvent(hdl, evt)) => {
-                if let Some(ctrl) = self.ctrls.get(&hdl).map(|ctrl| ctrl.clone()) {
-                    ctrl.send(evt);
+                if let Some(ctrl) = self.ctrls.get(&(shard, hdl)).map(|ctrl| ctrl.clone()) {
+                    if let Some(packet) = received {
+                        ctrl.qlog_received(packet);
+                    }
+                    ctrl.send(evt).await;
                     Ok(())
                 } else {
                     Err(Error::new(
@@ -299,15 +651,16 @@ impl QuicEndpoint {
             }
 
             Some(DatagramEvent::Response(transmit)) => {
-                let packet = PACKET_POOL.with(|pool| {
+                let mut packet = PACKET_POOL.with(|pool| {
                     pool.borrow_mut()
                         .pack_transmit(transmit, &buf, self.output.packet.margins)
                 });
+                self.output.packet.frame(&mut packet);
                 self.output
                     .packet
                     .send(packet)
                     .await
-                    .map_err(|e| Error::other(format!("Failed to send QUIC response: {:?}", e)))
+                    .map_err(|_| QuicError::EndpointClosed.into())
             }
 
             None => Ok(()),
@@ -0,0 +1,110 @@
+use quinn_proto::{ConnectError, ConnectionError, VarInt};
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind};
+
+/// Why a QUIC operation failed.
+///
+/// Endpoints and streams return [`std::io::Error`]s so they fit `AsyncRead`/`AsyncWrite`,
+/// with a `QuicError` inside whenever the failure is QUIC-specific. Get it back with
+/// [`QuicError::of`] or [`Error::downcast`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuicError {
+    /// The connection was closed by either side, reset or timed out, as quinn-proto reports.
+    ConnectionLost(ConnectionError),
+    /// The connection stopped being driven without a reason from quinn-proto, e.g. it was
+    /// dropped locally.
+    ConnectionClosed,
+    /// The peer reset the stream with this error code.
+    StreamReset(VarInt),
+    /// The peer stopped the stream with this error code.
+    StreamStopped(VarInt),
+    /// The stream is already closed for writing.
+    StreamClosed,
+    /// The peer allowed no more concurrent streams in that direction within the open timeout.
+    StreamsExhausted,
+    /// The server refused the 0-RTT data sent on the stream, it has to be sent again on a
+    /// new stream.
+    EarlyDataRejected,
+    /// The handshake did not complete within the idle timeout.
+    HandshakeTimeout,
+    /// The endpoint is gone or its output channels were closed.
+    EndpointClosed,
+    /// quinn-proto refused to start a connection.
+    ConnectFailed(ConnectError),
+    /// An incoming connection was refused.
+    AcceptFailed(ConnectionError),
+}
+
+impl QuicError {
+    /// The `QuicError` carried by `e`, if any.
+    pub fn of(e: &Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    /// Why a connection whose runner exited with `e` ended.
+    pub(super) fn closed_by(e: &Error) -> Self {
+        Self::of(e).cloned().unwrap_or(Self::ConnectionClosed)
+    }
+
+    /// Maps a loss reported by quinn-proto, `handshaking` if the handshake never completed.
+    pub(super) fn lost(reason: ConnectionError, handshaking: bool) -> Self {
+        match reason {
+            ConnectionError::TimedOut if handshaking => Self::HandshakeTimeout,
+            reason => Self::ConnectionLost(reason),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::ConnectionLost(reason) => match reason {
+                ConnectionError::TimedOut => ErrorKind::TimedOut,
+                ConnectionError::Reset => ErrorKind::ConnectionReset,
+                ConnectionError::LocallyClosed => ErrorKind::NotConnected,
+                ConnectionError::VersionMismatch => ErrorKind::ConnectionRefused,
+                ConnectionError::CidsExhausted => ErrorKind::Other,
+                _ => ErrorKind::ConnectionAborted,
+            },
+            Self::ConnectionClosed => ErrorKind::NotConnected,
+            Self::StreamReset(_) => ErrorKind::ConnectionReset,
+            Self::StreamStopped(_) | Self::StreamClosed | Self::EndpointClosed => ErrorKind::BrokenPipe,
+            Self::StreamsExhausted => ErrorKind::QuotaExceeded,
+            Self::ConnectFailed(_) => ErrorKind::Other,
+            Self::EarlyDataRejected | Self::AcceptFailed(_) => ErrorKind::ConnectionRefused,
+            Self::HandshakeTimeout => ErrorKind::TimedOut,
+        }
+    }
+}
+
+impl Display for QuicError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConnectionLost(reason) => write!(f, "QUIC connection lost: {}", reason),
+            Self::ConnectionClosed => write!(f, "QUIC connection closed"),
+            Self::StreamReset(code) => write!(f, "QUIC stream reset by peer: {}", code),
+            Self::StreamStopped(code) => write!(f, "QUIC stream stopped by peer: {}", code),
+            Self::StreamClosed => write!(f, "QUIC stream closed"),
+            Self::StreamsExhausted => write!(f, "QUIC streams exhausted"),
+            Self::EarlyDataRejected => write!(f, "QUIC 0-RTT data rejected by peer, resend on a new stream"),
+            Self::HandshakeTimeout => write!(f, "QUIC handshake timed out"),
+            Self::EndpointClosed => write!(f, "QUIC endpoint closed"),
+            Self::ConnectFailed(e) => write!(f, "Failed to start QUIC connection: {}", e),
+            Self::AcceptFailed(e) => write!(f, "Failed to accept QUIC connection: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for QuicError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ConnectionLost(e) | Self::AcceptFailed(e) => Some(e),
+            Self::ConnectFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<QuicError> for Error {
+    fn from(e: QuicError) -> Self {
+        Error::new(e.kind(), e)
+    }
+}
@@ -0,0 +1,160 @@
+use crate::gateway::quic::packet::QuicPacketMargins;
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+/// Encapsulation written into the margins reserved around every outgoing QUIC datagram
+/// and checked on every incoming one.
+///
+/// Both methods see the whole packet: `margins().header` bytes of header, the QUIC
+/// datagram, then `margins().trailer` bytes of trailer.
+pub trait PacketFramer: Debug + Send + Sync + 'static {
+    fn margins(&self) -> QuicPacketMargins;
+
+    /// Fills the header and trailer of an outgoing packet.
+    fn frame(&self, packet: &mut [u8]);
+
+    /// Validates the header and trailer of an incoming packet. The endpoint strips them
+    /// afterwards.
+    fn check(&self, packet: &[u8]) -> Result<()>;
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Prefixes the QUIC datagram with its length as a big-endian `u16`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LengthPrefixFramer;
+
+impl PacketFramer for LengthPrefixFramer {
+    fn margins(&self) -> QuicPacketMargins {
+        (2, 0).into()
+    }
+
+    fn frame(&self, packet: &mut [u8]) {
+        let len = (packet.len() - 2) as u16;
+        packet[..2].copy_from_slice(&len.to_be_bytes());
+    }
+
+    fn check(&self, packet: &[u8]) -> Result<()> {
+        let len = u16::from_be_bytes([packet[0], packet[1]]) as usize;
+        if len != packet.len() - 2 {
+            return Err(invalid(format!(
+                "Length prefix {} does not match payload length {}",
+                len,
+                packet.len() - 2
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Prefixes the QUIC datagram with a big-endian `u32` tunnel ID and drops packets
+/// carrying any other ID.
+#[derive(Debug, Clone, Copy)]
+pub struct TunnelIdFramer {
+    pub id: u32,
+}
+
+impl PacketFramer for TunnelIdFramer {
+    fn margins(&self) -> QuicPacketMargins {
+        (4, 0).into()
+    }
+
+    fn frame(&self, packet: &mut [u8]) {
+        packet[..4].copy_from_slice(&self.id.to_be_bytes());
+    }
+
+    fn check(&self, packet: &[u8]) -> Result<()> {
+        let id = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        if id != self.id {
+            return Err(invalid(format!(
+                "Tunnel ID {:#010x} does not match {:#010x}",
+                id, self.id
+            )));
+        }
+        Ok(())
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Appends a big-endian CRC-32 (IEEE) of the QUIC datagram.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChecksumFramer;
+
+impl PacketFramer for ChecksumFramer {
+    fn margins(&self) -> QuicPacketMargins {
+        (0, 4).into()
+    }
+
+    fn frame(&self, packet: &mut [u8]) {
+        let (data, trailer) = packet.split_at_mut(packet.len() - 4);
+        trailer.copy_from_slice(&crc32(data).to_be_bytes());
+    }
+
+    fn check(&self, packet: &[u8]) -> Result<()> {
+        let (data, trailer) = packet.split_at(packet.len() - 4);
+        let expected = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let actual = crc32(data);
+        if actual != expected {
+            return Err(invalid(format!(
+                "Checksum {:#010x} does not match {:#010x}",
+                actual, expected
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Nests `inner` inside `outer`: `outer` frames the packet `inner` has already framed.
+#[derive(Debug, Clone)]
+pub struct StackedFramer {
+    pub outer: Arc<dyn PacketFramer>,
+    pub inner: Arc<dyn PacketFramer>,
+}
+
+impl PacketFramer for StackedFramer {
+    fn margins(&self) -> QuicPacketMargins {
+        let (outer, inner) = (self.outer.margins(), self.inner.margins());
+        (outer.header + inner.header, outer.trailer + inner.trailer).into()
+    }
+
+    fn frame(&self, packet: &mut [u8]) {
+        let (header, trailer) = self.outer.margins().into();
+        let len = packet.len();
+        self.inner.frame(&mut packet[header..len - trailer]);
+        self.outer.frame(packet);
+    }
+
+    fn check(&self, packet: &[u8]) -> Result<()> {
+        let (header, trailer) = self.outer.margins().into();
+        self.outer.check(packet)?;
+        self.inner.check(&packet[header..packet.len() - trailer])
+    }
+}
@@ -0,0 +1,342 @@
+use crate::gateway::quic::conn::{ConnBase, InboxConfig, InboxCounters, InboxOverflow, WaitingOpens};
+use crate::gateway::quic::error::QuicError;
+use crate::gateway::quic::shaper::WRITE_GRANULE;
+use crate::gateway::quic::tls::EarlyData;
+use derive_more::{Deref, DerefMut};
+use bytes::Bytes;
+use quinn_proto::{Connection, ConnectionEvent, Dir, ReadError, ReadableError, StreamId, VarInt};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::Result;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+use tokio::sync::oneshot;
+use tracing::trace;
+
+/// Chunks read out of quinn-proto ahead of a stream's reader, see
+/// [`LocalQuicStream::set_receive_window`](super::LocalQuicStream::set_receive_window).
+#[derive(Debug, Default)]
+struct ReadAhead {
+    window: usize,
+    chunks: VecDeque<Bytes>,
+    len: usize,
+    /// How the stream ended after the buffered chunks.
+    end: Option<std::result::Result<(), QuicError>>,
+}
+
+#[derive(Debug, Deref, DerefMut)]
+pub(super) struct LocalConnState {
+    #[deref]
+    #[deref_mut]
+    base: ConnBase,
+    pub(super) inbox: VecDeque<ConnectionEvent>,
+    pub(super) inbox_config: InboxConfig,
+    pub(super) counters: Rc<InboxCounters>,
+    /// Senders parked on a full inbox, woken once the runner drained it.
+    drained: Vec<Waker>,
+    pub(super) readers: HashMap<StreamId, Waker>,
+    pub(super) writers: HashMap<StreamId, Waker>,
+    read_ahead: HashMap<StreamId, ReadAhead>,
+    runner: Option<Waker>,
+    /// Waiting for the handshake to complete.
+    handshake: Vec<Waker>,
+    /// Opens waiting for stream credit.
+    opens: WaitingOpens<StreamId>,
+    /// The server rejected 0-RTT, quinn-proto forgot the streams opened before the handshake.
+    rejected: bool,
+    /// Writers parked while the egress bucket is dry.
+    throttled: Vec<Waker>,
+    /// Whether anything happened since the runner last polled the connection.
+    pub(super) dirty: bool,
+}
+
+impl LocalConnState {
+    fn new(conn: Connection) -> Self {
+        Self {
+            base: ConnBase::new(conn),
+            inbox: VecDeque::new(),
+            inbox_config: InboxConfig::default(),
+            counters: Rc::default(),
+            drained: Vec::new(),
+            readers: HashMap::new(),
+            writers: HashMap::new(),
+            read_ahead: HashMap::new(),
+            runner: None,
+            handshake: Vec::new(),
+            opens: WaitingOpens::default(),
+            rejected: false,
+            throttled: Vec::new(),
+            dirty: true,
+        }
+    }
+
+    /// Queues `evt` for the runner, or handles a full inbox as its config says: drops the
+    /// packet, or parks the sender until the runner drained the inbox.
+    pub(super) fn poll_push(&mut self, cx: &mut Context<'_>, evt: &mut Option<ConnectionEvent>) -> Poll<()> {
+        if self.inbox.len() < self.inbox_config.capacity.max(1) {
+            self.inbox.extend(evt.take());
+        } else if self.inbox_config.overflow == InboxOverflow::Drop {
+            evt.take();
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            trace!("Inbox of connection to {:?} is full, packet dropped", self.conn.remote_address());
+        } else if self.closed.is_none() {
+            self.counters.waits.fetch_add(1, Ordering::Relaxed);
+            self.drained.push(cx.waker().clone());
+            self.wake();
+            return Poll::Pending;
+        }
+        self.wake();
+        Poll::Ready(())
+    }
+
+    /// Wakes the senders parked on the inbox after the runner drained it.
+    pub(super) fn drained(&mut self) {
+        for waker in self.drained.drain(..) {
+            waker.wake();
+        }
+    }
+
+    pub(super) fn wake(&mut self) {
+        self.dirty = true;
+        if let Some(waker) = self.runner.take() {
+            waker.wake();
+        }
+    }
+
+    /// Parks the runner until [`Self::wake`]. Returns false if there is work already.
+    pub(super) fn park(&mut self, waker: &Waker) -> bool {
+        if self.dirty {
+            return false;
+        }
+        self.runner = Some(waker.clone());
+        true
+    }
+
+    pub(super) fn check(&self) -> Result<()> {
+        match &self.closed {
+            Some(e) => Err(e.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Bytes of stream data that may be written now, at most `max`. Parks the writer while
+    /// the egress bucket is dry.
+    pub(super) fn poll_egress(&mut self, cx: &mut Context<'_>, max: usize) -> Poll<usize> {
+        let Some(quota) = self.egress.quota(Instant::now()) else {
+            return Poll::Ready(max);
+        };
+        // 与 Throttle 一样，令牌攒够一块再放行，避免逐字节写入
+        match self.egress.ready_for(max.min(WRITE_GRANULE)) {
+            None => Poll::Ready(quota.min(max)),
+            Some(_) => {
+                self.throttled.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Wakes the writers parked on the egress bucket once it refilled a granule.
+    pub(super) fn wake_throttled(&mut self) {
+        if self.throttled.is_empty() || !self.egress.allows(WRITE_GRANULE, Instant::now()) {
+            return;
+        }
+        for waker in self.throttled.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// See [`ConnBase::timeout`].
+    pub(super) fn timeout(&mut self) -> Option<Instant> {
+        let throttled = !self.throttled.is_empty();
+        self.base.timeout(throttled)
+    }
+
+    /// Like [`Self::check`] for a stream, `early` if it was opened before the handshake.
+    pub(super) fn check_stream(&self, early: bool) -> Result<()> {
+        self.check()?;
+        match early && self.rejected {
+            true => Err(QuicError::EarlyDataRejected.into()),
+            false => Ok(()),
+        }
+    }
+
+    /// Whether a stream opened before the handshake no longer exists in quinn-proto.
+    pub(super) fn forgotten(&self, early: bool) -> bool {
+        early && self.rejected
+    }
+
+    /// Settles 0-RTT once the handshake completed. The owners of the streams a rejected
+    /// handshake made quinn-proto forget are failed.
+    pub(super) fn settle_early_data(&mut self) {
+        if !self.early_data_rejected() {
+            return;
+        }
+        self.rejected = true;
+        self.read_ahead.clear();
+        for (_, waker) in self.readers.drain().chain(self.writers.drain()) {
+            waker.wake();
+        }
+    }
+
+    pub(super) fn poll_early_data(&mut self, cx: &mut Context<'_>) -> Poll<Result<EarlyData>> {
+        self.check()?;
+        if self.conn.is_handshaking() {
+            self.handshake.push(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(EarlyData::of(&self.conn)))
+    }
+
+    pub(super) fn connected(&mut self) {
+        for waker in self.handshake.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Asks for a bidirectional stream, granted in line with earlier requests once the
+    /// peer allows one more.
+    pub(super) fn open(&mut self) -> oneshot::Receiver<Result<StreamId>> {
+        let (tx, rx) = oneshot::channel();
+        self.opens.queue(Dir::Bi).push_back(tx);
+        match &self.base.closed {
+            Some(reason) => self.opens.refuse(reason.clone()),
+            None => self.grant(Dir::Bi),
+        }
+        self.wake();
+        rx
+    }
+
+    /// Opens streams for the requests waiting in `dir`, as far as the peer's credit goes.
+    pub(super) fn grant(&mut self, dir: Dir) {
+        let conn = &mut self.base.conn;
+        self.opens.grant(dir, |dir| conn.streams().open(dir));
+    }
+
+    pub(super) fn close(&mut self, id: StreamId) {
+        let _ = self.conn.recv_stream(id).stop(VarInt::from_u32(0));
+        let _ = self.conn.send_stream(id).finish();
+        self.readers.remove(&id);
+        self.writers.remove(&id);
+        self.read_ahead.remove(&id);
+        self.wake();
+    }
+
+    /// Reads up to `window` bytes of stream `id` ahead of its reader from now on.
+    pub(super) fn set_read_ahead(&mut self, id: StreamId, window: usize) {
+        let ahead = self.read_ahead.entry(id).or_default();
+        ahead.window = window;
+        if window == 0 && ahead.chunks.is_empty() {
+            self.read_ahead.remove(&id);
+        }
+        self.read_ahead_fill(id);
+    }
+
+    /// Tops the read-ahead of stream `id` up to its window, if it has one.
+    pub(super) fn read_ahead_fill(&mut self, id: StreamId) {
+        let Some(ahead) = self.read_ahead.get_mut(&id) else {
+            return;
+        };
+        if ahead.end.is_some() || ahead.len >= ahead.window {
+            return;
+        }
+        let mut recv = self.base.conn.recv_stream(id);
+        let mut chunks = match recv.read(true) {
+            Ok(chunks) => chunks,
+            Err(ReadableError::ClosedStream) => {
+                ahead.end = Some(Ok(()));
+                return;
+            }
+            Err(ReadableError::IllegalOrderedRead) => unreachable!("Reads are always ordered"),
+        };
+        while ahead.len < ahead.window {
+            match chunks.next(ahead.window - ahead.len) {
+                Ok(Some(chunk)) => {
+                    ahead.len += chunk.bytes.len();
+                    ahead.chunks.push_back(chunk.bytes);
+                }
+                Ok(None) => {
+                    ahead.end = Some(Ok(()));
+                    break;
+                }
+                Err(ReadError::Blocked) => break,
+                Err(ReadError::Reset(code)) => {
+                    ahead.end = Some(Err(QuicError::StreamReset(code)));
+                    break;
+                }
+            }
+        }
+        // 读出数据后可能需要发送 MAX_STREAM_DATA，交给 runner
+        if chunks.finalize().should_transmit() {
+            self.wake();
+        }
+    }
+
+    /// Takes the next chunk of at most `max` bytes out of the read-ahead of stream `id`,
+    /// parking the reader while it is empty. `None` if the stream reads straight from
+    /// quinn-proto.
+    pub(super) fn poll_read_ahead(
+        &mut self,
+        cx: &mut Context<'_>,
+        id: StreamId,
+        max: usize,
+    ) -> Option<Poll<Result<Option<Bytes>>>> {
+        self.read_ahead_fill(id);
+        let ahead = self.read_ahead.get_mut(&id)?;
+        let res = match ahead.chunks.front_mut() {
+            Some(chunk) if chunk.len() > max => Ok(Some(chunk.split_to(max))),
+            Some(_) => Ok(ahead.chunks.pop_front()),
+            None => match &ahead.end {
+                Some(Ok(())) => Ok(None),
+                Some(Err(e)) => Err(e.clone().into()),
+                None if ahead.window == 0 => {
+                    // 窗口已关且缓存读空，此后直接读 quinn-proto
+                    self.read_ahead.remove(&id);
+                    return None;
+                }
+                None => {
+                    self.readers.insert(id, cx.waker().clone());
+                    return Some(Poll::Pending);
+                }
+            },
+        };
+        if let Ok(Some(chunk)) = &res {
+            ahead.len -= chunk.len();
+            self.read_ahead_fill(id);
+        }
+        Some(Poll::Ready(res))
+    }
+
+    pub(super) fn fail(&mut self, error: QuicError) {
+        self.opens.refuse(error.clone());
+        self.closed = Some(error);
+        for (_, waker) in self.readers.drain().chain(self.writers.drain()) {
+            waker.wake();
+        }
+        self.drained();
+        self.connected();
+    }
+}
+
+impl Drop for LocalConnState {
+    fn drop(&mut self) {
+        self.conn.close(
+            Instant::now(),
+            VarInt::from_u32(1),
+            "QUIC connection destroyed".into(),
+        );
+    }
+}
+
+pub(super) type LocalConn = Rc<RefCell<LocalConnState>>;
+
+impl From<LocalConnState> for LocalConn {
+    fn from(state: LocalConnState) -> Self {
+        Rc::new(RefCell::new(state))
+    }
+}
+
+pub(super) fn local_conn(conn: Connection) -> LocalConn {
+    LocalConnState::new(conn).into()
+}
@@ -0,0 +1,466 @@
+use crate::gateway::quic::capture::CaptureConfig;
+use crate::gateway::quic::conn::{InboxConfig, InboxCounters};
+use crate::gateway::quic::datagram::{QuicDatagramRx, QuicDatagramTx};
+use crate::gateway::quic::endpoint::EndpointSettings;
+use crate::gateway::quic::error::QuicError;
+use crate::gateway::quic::framer::PacketFramer;
+use crate::gateway::quic::local::conn::{local_conn, LocalConn};
+use crate::gateway::quic::local::runner::{LocalAddrs, LocalConns, LocalRunner};
+use crate::gateway::quic::local::stream::{LocalQuicStream, LocalQuicStreamRx, LocalQuicStreamTx};
+use crate::gateway::quic::packet::{PacketPool, QuicPacketMargins, QuicPacketRx, QuicPacketTx};
+use crate::gateway::quic::qlog::Received;
+use crate::gateway::quic::shaper::RateLimit;
+use crate::gateway::quic::tls::{Authorizer, EarlyData, PeerIdentity, TlsConfig};
+use crate::gateway::quic::transport::{CongestionControl, DefaultTransport, MtuConfig, PeerTransport, TransportProfile};
+use crate::gateway::quic::{QuicEndpoint, QuicEndpointStats};
+use bytes::{Bytes, BytesMut};
+use derive_more::Debug;
+use quinn_proto::{
+    AcceptError, ClientConfig, Connection, ConnectionHandle, ConnectionStats, DatagramEvent,
+    Endpoint, Incoming, SendDatagramError,
+};
+use std::sync::Arc;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{error, trace};
+
+#[derive(Debug)]
+pub struct LocalQuicOutputRx {
+    pub packet: QuicPacketRx,
+    pub stream: LocalQuicStreamRx,
+    pub datagram: QuicDatagramRx,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct LocalQuicOutputTx {
+    pub(super) packet: QuicPacketTx,
+    pub(super) stream: LocalQuicStreamTx,
+    pub(super) datagram: QuicDatagramTx,
+    /// Stream channels by ALPN protocol, in place of `stream`.
+    pub(super) routes: Rc<RefCell<HashMap<Vec<u8>, LocalQuicStreamTx>>>,
+}
+
+/// `!Send` endpoint for a `current_thread` runtime or [`LocalSet`](tokio::task::LocalSet).
+/// Connections live in `Rc<RefCell<..>>` and their runners are spawned with
+/// [`spawn_local`](tokio::task::spawn_local), so one independent endpoint can run per core,
+/// e.g. behind sockets bound with `SO_REUSEPORT`.
+#[derive(Debug)]
+pub struct LocalQuicEndpoint {
+    endpoint: RefCell<Endpoint>,
+    settings: RefCell<EndpointSettings>,
+    peers: RefCell<HashMap<SocketAddr, PeerTransport>>,
+    inbox: Cell<InboxConfig>,
+    counters: Rc<InboxCounters>,
+    conns: LocalConns,
+    addrs: LocalAddrs,
+    output: LocalQuicOutputTx,
+    buf: RefCell<Vec<u8>>,
+    pool: RefCell<PacketPool>,
+}
+
+impl LocalQuicEndpoint {
+    pub fn new(packet_margins: QuicPacketMargins) -> (Self, LocalQuicOutputRx) {
+        let client_config = DefaultTransport::default().client_config();
+        Self::build(QuicEndpoint::default_endpoint(0, 1), client_config, packet_margins, false)
+    }
+
+    pub fn with_endpoint(
+        endpoint: Endpoint,
+        client_config: ClientConfig,
+        packet_margins: QuicPacketMargins,
+    ) -> (Self, LocalQuicOutputRx) {
+        Self::build(endpoint, client_config, packet_margins, true)
+    }
+
+    fn build(
+        endpoint: Endpoint,
+        client_config: ClientConfig,
+        packet_margins: QuicPacketMargins,
+        custom: bool,
+    ) -> (Self, LocalQuicOutputRx) {
+        let (packet_tx, packet_rx) = mpsc::channel(1024);
+        let (stream_tx, stream_rx) = mpsc::channel(512);
+        let (datagram_tx, datagram_rx) = mpsc::channel(1024);
+        (
+            Self {
+                endpoint: RefCell::new(endpoint),
+                settings: RefCell::new(EndpointSettings::new(client_config, custom)),
+                peers: Default::default(),
+                inbox: Cell::new(InboxConfig::default()),
+                counters: Rc::default(),
+                conns: Default::default(),
+                addrs: Default::default(),
+                output: LocalQuicOutputTx {
+                    packet: QuicPacketTx::new(packet_tx, packet_margins),
+                    stream: stream_tx,
+                    datagram: datagram_tx,
+                    routes: Rc::default(),
+                },
+                buf: RefCell::new(Vec::with_capacity(65535)),
+                pool: RefCell::new(PacketPool::new()),
+            },
+            LocalQuicOutputRx {
+                packet: packet_rx,
+                stream: stream_rx,
+                datagram: datagram_rx,
+            },
+        )
+    }
+
+    /// See [`QuicEndpoint::set_mtu`].
+    pub fn set_mtu(&self, mtu: MtuConfig) {
+        self.settings.borrow_mut().transport.mtu = mtu;
+        self.reconfigure();
+    }
+
+    /// See [`QuicEndpoint::set_congestion`].
+    pub fn set_congestion(&self, congestion: CongestionControl) {
+        self.settings.borrow_mut().transport.congestion = congestion;
+        self.reconfigure();
+    }
+
+    /// See [`QuicEndpoint::set_stream_receive_window`].
+    pub fn set_stream_receive_window(&self, window: u32) {
+        self.settings.borrow_mut().transport.stream_receive_window = Some(window);
+        self.reconfigure();
+    }
+
+    /// See [`QuicEndpoint::set_peer_congestion`].
+    pub fn set_peer_congestion(&self, addr: SocketAddr, congestion: Option<CongestionControl>) {
+        let mut peers = self.peers.borrow_mut();
+        match congestion {
+            Some(congestion) => peers.insert(addr, self.settings.borrow().transport.peer(congestion)),
+            None => peers.remove(&addr),
+        };
+    }
+
+    /// See [`QuicEndpoint::set_inbox`].
+    pub fn set_inbox(&self, inbox: InboxConfig) {
+        self.inbox.set(inbox);
+    }
+
+    /// See [`QuicEndpoint::stats`].
+    pub fn stats(&self) -> QuicEndpointStats {
+        QuicEndpointStats {
+            inbox_dropped: self.counters.dropped.load(Ordering::Relaxed),
+            inbox_waits: self.counters.waits.load(Ordering::Relaxed),
+        }
+    }
+
+    /// See [`QuicEndpoint::set_rate_limit`].
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        self.settings.borrow_mut().rate_limit = limit;
+    }
+
+    /// See [`QuicEndpoint::set_authorizer`].
+    pub fn set_authorizer(&self, authorizer: Option<Authorizer>) {
+        self.settings.borrow_mut().authorizer = authorizer;
+    }
+
+    /// See [`QuicEndpoint::set_qlog`].
+    pub fn set_qlog(&self, dir: Option<PathBuf>) {
+        self.settings.borrow_mut().qlog = dir;
+    }
+
+    /// See [`QuicEndpoint::set_open_timeout`].
+    pub fn set_open_timeout(&self, timeout: Option<Duration>) {
+        self.settings.borrow_mut().open_timeout = timeout;
+    }
+
+    /// See [`QuicEndpoint::set_framer`].
+    pub fn set_framer(&self, framer: Arc<dyn PacketFramer>) -> Result<()> {
+        self.output.packet.set_framer(framer)
+    }
+
+    /// See [`QuicEndpoint::start_capture`].
+    pub fn start_capture(&self, path: impl AsRef<Path>, config: CaptureConfig) -> Result<()> {
+        self.output.packet.capture.start(path.as_ref(), config)
+    }
+
+    /// See [`QuicEndpoint::stop_capture`].
+    pub fn stop_capture(&self) {
+        self.output.packet.capture.stop();
+    }
+
+    /// See [`QuicEndpoint::is_capturing`].
+    pub fn is_capturing(&self) -> bool {
+        self.output.packet.capture.is_on()
+    }
+
+    /// See [`QuicEndpoint::set_conn_rate_limit`].
+    pub fn set_conn_rate_limit(&self, addr: SocketAddr, limit: Option<RateLimit>) -> Result<()> {
+        let conn = self.conn(addr)?;
+        let mut state = conn.borrow_mut();
+        state.egress.set(limit);
+        state.wake();
+        Ok(())
+    }
+
+    /// See [`QuicEndpoint::set_conn_profile`].
+    pub fn set_conn_profile(&self, addr: SocketAddr, profile: TransportProfile) -> Result<()> {
+        let conn = self.conn(addr)?;
+        let mut state = conn.borrow_mut();
+        state.set_profile(profile);
+        state.wake();
+        Ok(())
+    }
+
+    fn conn(&self, addr: SocketAddr) -> Result<LocalConn> {
+        let hdl = self.addrs.borrow().get(&addr).copied();
+        hdl.and_then(|hdl| self.conns.borrow().get(&hdl).cloned())
+            .ok_or(Error::new(
+                ErrorKind::NotFound,
+                format!("No connection to {:?}", addr),
+            ))
+    }
+
+    /// See [`QuicEndpoint::set_tls`].
+    pub fn set_tls(&self, tls: Option<TlsConfig>) -> Result<()> {
+        self.settings.borrow_mut().set_tls(tls)?;
+        self.reconfigure();
+        Ok(())
+    }
+
+    /// See [`QuicEndpoint::early_data`].
+    pub async fn early_data(&self, addr: SocketAddr) -> Result<EarlyData> {
+        let conn = self.conn(addr)?;
+        poll_fn(|cx| conn.borrow_mut().poll_early_data(cx)).await
+    }
+
+    fn reconfigure(&self) {
+        let mut endpoint = self.endpoint.borrow_mut();
+        self.settings
+            .borrow_mut()
+            .reconfigure(|server_config| endpoint.set_server_config(server_config));
+    }
+
+    /// Statistics of the connection to `addr`, including its current MTU.
+    pub fn conn_stats(&self, addr: SocketAddr) -> Option<ConnectionStats> {
+        let hdl = *self.addrs.borrow().get(&addr)?;
+        let conn = self.conns.borrow().get(&hdl).cloned()?;
+        let stats = conn.borrow().conn.stats();
+        Some(stats)
+    }
+
+    /// See [`QuicEndpoint::peer_identity`].
+    pub fn peer_identity(&self, addr: SocketAddr) -> Option<Arc<PeerIdentity>> {
+        self.conn(addr).ok()?.borrow_mut().peer_identity()
+    }
+
+    /// See [`QuicEndpoint::route`].
+    pub fn route(&self, protocol: impl Into<Vec<u8>>) -> LocalQuicStreamRx {
+        let (tx, rx) = mpsc::channel(512);
+        self.output.routes.borrow_mut().insert(protocol.into(), tx);
+        rx
+    }
+
+    /// See [`QuicEndpoint::unroute`].
+    pub fn unroute(&self, protocol: &[u8]) {
+        self.output.routes.borrow_mut().remove(protocol);
+    }
+
+    /// See [`QuicEndpoint::alpn`].
+    pub fn alpn(&self, addr: SocketAddr) -> Option<Vec<u8>> {
+        self.conn(addr).ok()?.borrow().alpn()
+    }
+
+    /// See [`QuicEndpoint::server_name`].
+    pub fn server_name(&self, addr: SocketAddr) -> Option<String> {
+        self.conn(addr).ok()?.borrow().server_name()
+    }
+
+    fn establish(&self, hdl: ConnectionHandle, conn: Connection) -> LocalConn {
+        let addr = conn.remote_address();
+        let qlog = EndpointSettings::open_qlog(self.settings.borrow().qlog.as_deref(), &conn);
+        let conn = local_conn(conn);
+        {
+            let mut state = conn.borrow_mut();
+            self.settings.borrow().configure(&mut state, qlog);
+            state.inbox_config = self.inbox.get();
+            state.counters = self.counters.clone();
+        }
+        self.conns.borrow_mut().insert(hdl, conn.clone());
+        self.addrs.borrow_mut().insert(addr, hdl);
+        let runner = LocalRunner::new(
+            hdl,
+            addr,
+            conn.clone(),
+            self.conns.clone(),
+            self.addrs.clone(),
+            self.output.clone(),
+        );
+        tokio::task::spawn_local(async move {
+            if let Err(e) = runner.run().await {
+                error!("Local runner exited with error: {:?}", e);
+            }
+        });
+        conn
+    }
+
+    fn accept(&self, incoming: Incoming) -> Result<()> {
+        let addr = incoming.remote_address();
+        let server_config = self.peers.borrow().get(&addr).and_then(|peer| peer.server.clone());
+        let mut buf = self.buf.borrow_mut();
+        buf.clear();
+        let accept = self
+            .endpoint
+            .borrow_mut()
+            .accept(incoming, Instant::now(), &mut buf, server_config);
+        match accept {
+            Ok((hdl, conn)) => {
+                trace!("Accepted new local connection({:?}) from {:?}", hdl, addr);
+                self.establish(hdl, conn);
+                Ok(())
+            }
+            Err(AcceptError { cause, response }) => {
+                if let Some(transmit) = response {
+                    let mut packet = self.pool.borrow_mut().pack_transmit(
+                        transmit,
+                        &buf,
+                        self.output.packet.margins,
+                    );
+                    self.output.packet.frame(&mut packet);
+                    let _ = self.output.packet.try_send(packet);
+                }
+                Err(QuicError::AcceptFailed(cause).into())
+            }
+        }
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Result<LocalConn> {
+        let hdl = self.addrs.borrow().get(&addr).copied();
+        if let Some(conn) = hdl.and_then(|hdl| self.conns.borrow().get(&hdl).cloned()) {
+            return Ok(conn);
+        }
+
+        let settings = self.settings.borrow();
+        let client_config = settings.client_config(self.peers.borrow().get(&addr));
+        let (hdl, conn) = self
+            .endpoint
+            .borrow_mut()
+            .connect(
+                Instant::now(),
+                client_config,
+                addr,
+                &settings.transport.server_name(addr),
+            )
+            .map_err(QuicError::ConnectFailed)?;
+        drop(settings);
+        Ok(self.establish(hdl, conn))
+    }
+
+    /// See [`QuicEndpoint::open`].
+    pub async fn open(&self, addr: SocketAddr, header: Option<BytesMut>) -> Result<LocalQuicStream> {
+        let conn = self.connect(addr)?;
+        let open = conn.borrow_mut().open();
+        let limit = self.settings.borrow().open_timeout;
+        let id = EndpointSettings::open(limit, async {
+            open.await.map_err(|_| Error::from(conn.borrow().reason()))?
+        })
+        .await?;
+        let early = conn.borrow().early();
+        let mut stream = LocalQuicStream::new(id, conn, early);
+        if let Some(header) = header {
+            stream.write_all(&header).await?;
+        }
+        Ok(stream)
+    }
+
+    /// Queues an unreliable datagram to `addr`. Fails with [`ErrorKind::InvalidInput`]
+    /// when `payload` exceeds [`Self::max_datagram_size`].
+    pub fn send_datagram(&self, addr: SocketAddr, payload: Bytes) -> Result<()> {
+        let conn = self.connect(addr)?;
+        let mut state = conn.borrow_mut();
+        match state.conn.datagrams().send(payload, true) {
+            Ok(()) => {
+                state.wake();
+                Ok(())
+            }
+            Err(SendDatagramError::TooLarge) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "QUIC datagram exceeds the maximum datagram size",
+            )),
+            Err(e) => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("Failed to send QUIC datagram: {:?}", e),
+            )),
+        }
+    }
+
+    pub fn max_datagram_size(&self, addr: SocketAddr) -> Result<Option<usize>> {
+        Ok(self.connect(addr)?.borrow_mut().conn.datagrams().max_size())
+    }
+
+    pub async fn send(&self, addr: SocketAddr, mut payload: BytesMut) -> Result<()> {
+        self.output.packet.unframe(addr, &mut payload)?;
+        let received = self.settings.borrow().qlog.is_some().then(|| Received::of(&payload));
+        let (event, response) = {
+            let mut buf = self.buf.borrow_mut();
+            buf.clear();
+            let event = self
+                .endpoint
+                .borrow_mut()
+                .handle(Instant::now(), addr, None, None, payload, &mut buf);
+            match event {
+                Some(DatagramEvent::Response(transmit)) => {
+                    let mut packet = self.pool.borrow_mut().pack_transmit(
+                        transmit,
+                        &buf,
+                        self.output.packet.margins,
+                    );
+                    self.output.packet.frame(&mut packet);
+                    (None, Some(packet))
+                }
+                event => (event, None),
+            }
+        };
+        if let Some(response) = response {
+            return self
+                .output
+                .packet
+                .send(response)
+                .await
+                .map_err(|_| QuicError::EndpointClosed.into());
+        }
+        match event {
+            Some(DatagramEvent::NewConnection(incoming)) => {
+                if self.output.stream.is_closed() {
+                    trace!("Incoming stream channel is closed. Connection dropped.");
+                    return Ok(());
+                }
+                self.accept(incoming)?;
+                if let (Some(packet), Ok(conn)) = (received, self.conn(addr)) {
+                    conn.borrow_mut().qlog_received(packet);
+                }
+                Ok(())
+            }
+            Some(DatagramEvent::ConnectionEvent(hdl, evt)) => {
+                let conn = self.conns.borrow().get(&hdl).cloned();
+                match conn {
+                    Some(conn) => {
+                        if let Some(packet) = received {
+                            conn.borrow_mut().qlog_received(packet);
+                        }
+                        let mut evt = Some(evt);
+                        poll_fn(|cx| conn.borrow_mut().poll_push(cx, &mut evt)).await;
+                        Ok(())
+                    }
+                    None => Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("Connection handle {:?} not found", hdl),
+                    )),
+                }
+            }
+            Some(DatagramEvent::Response(_)) | None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,7 @@
+mod conn;
+mod stream;
+mod runner;
+mod endpoint;
+
+pub use endpoint::*;
+pub use stream::*;
@@ -0,0 +1,193 @@
+use crate::gateway::quic::conn::cut_packet;
+use crate::gateway::quic::error::QuicError;
+use crate::gateway::quic::local::conn::LocalConn;
+use crate::gateway::quic::local::endpoint::LocalQuicOutputTx;
+use crate::gateway::quic::local::stream::LocalQuicStream;
+use crate::gateway::quic::QuicDatagram;
+use quinn_proto::{ConnectionHandle, Dir, Event, StreamEvent};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::poll_fn;
+use std::io::Result;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::time::sleep;
+
+pub(super) type LocalConns = Rc<RefCell<HashMap<ConnectionHandle, LocalConn>>>;
+pub(super) type LocalAddrs = Rc<RefCell<HashMap<SocketAddr, ConnectionHandle>>>;
+
+#[derive(Debug)]
+pub(super) struct LocalRunner {
+    hdl: ConnectionHandle,
+    addr: SocketAddr,
+    conn: LocalConn,
+    conns: LocalConns,
+    addrs: LocalAddrs,
+    output: LocalQuicOutputTx,
+}
+
+impl LocalRunner {
+    pub(super) fn new(
+        hdl: ConnectionHandle,
+        addr: SocketAddr,
+        conn: LocalConn,
+        conns: LocalConns,
+        addrs: LocalAddrs,
+        output: LocalQuicOutputTx,
+    ) -> Self {
+        Self {
+            hdl,
+            addr,
+            conn,
+            conns,
+            addrs,
+            output,
+        }
+    }
+
+    pub(super) async fn run(self) -> Result<()> {
+        let res = self.drive().await;
+        let error = match &res {
+            Ok(()) => QuicError::ConnectionClosed,
+            Err(e) => e.clone(),
+        };
+        self.conn.borrow_mut().fail(error);
+        res.map_err(Into::into)
+    }
+
+    async fn drive(&self) -> std::result::Result<(), QuicError> {
+        let mut timer = Box::pin(sleep(Duration::MAX));
+        let mut timeout: Option<Instant> = None;
+        let mut transmits = VecDeque::new();
+        let mut chunks = VecDeque::new();
+        let mut packets = VecDeque::new();
+        let mut streams = Vec::new();
+        let mut datagrams = Vec::new();
+        let mut stream_tx = self.output.stream.clone();
+
+        loop {
+            {
+                let mut state = self.conn.borrow_mut();
+                let state = &mut *state;
+                state.dirty = false;
+                let now = Instant::now();
+
+                while let Some(evt) = state.inbox.pop_front() {
+                    state.conn.handle_event(evt);
+                }
+                state.drained();
+                if timeout.is_some_and(|t| t <= now) {
+                    state.conn.handle_timeout(now);
+                }
+                state.settle_early_data();
+
+                while let Some(evt) = state.conn.poll() {
+                    match evt {
+                        Event::Stream(StreamEvent::Opened { dir }) if !stream_tx.is_closed() => {
+                            while let Some(id) = state.conn.streams().accept(dir) {
+                                if !state.authorize(id) {
+                                    continue;
+                                }
+                                streams.push(LocalQuicStream::new(id, self.conn.clone(), false));
+                            }
+                        }
+                        Event::Stream(StreamEvent::Available { dir }) => state.grant(dir),
+                        Event::Stream(StreamEvent::Readable { id }) => {
+                            state.read_ahead_fill(id);
+                            if let Some(waker) = state.readers.remove(&id) {
+                                waker.wake();
+                            }
+                        }
+                        Event::Stream(StreamEvent::Writable { id } | StreamEvent::Stopped { id, .. }) => {
+                            if let Some(waker) = state.writers.remove(&id) {
+                                waker.wake();
+                            }
+                        }
+                        Event::DatagramReceived => {
+                            while let Some(data) = state.conn.datagrams().recv() {
+                                datagrams.push(data);
+                            }
+                        }
+                        // 服务端此时已知 SNI，早于任何流被接受
+                        Event::HandshakeDataReady => {
+                            state.apply_profile();
+                            // 同一连接的流都属于协商出的协议，改投该协议的通道
+                            let route = state.alpn().and_then(|protocol| self.output.routes.borrow().get(&protocol).cloned());
+                            if let Some(tx) = route {
+                                stream_tx = tx;
+                            }
+                        }
+                        Event::Connected => {
+                            state.established = true;
+                            state.connected();
+                            // 握手带来的初始额度不会产生 Available 事件
+                            state.grant(Dir::Bi);
+                        }
+                        Event::ConnectionLost { reason } => return Err(state.lost(reason, &self.output.packet)),
+                        _ => {}
+                    }
+                }
+
+                let margins = self.output.packet.margins;
+                state.poll_transmits(margins, &mut transmits, &mut chunks);
+                // 单线程下没有锁，直接切分成数据包
+                while let Some(packet) = cut_packet(&mut transmits, &mut chunks, &self.output.packet) {
+                    packets.push_back(packet);
+                }
+
+                // 与多线程版一致，出口限速只作用于流数据，令牌恢复时唤醒停车的写入方
+                state.wake_throttled();
+                timeout = state.timeout();
+            }
+
+            let worked = !packets.is_empty() || !streams.is_empty() || !datagrams.is_empty();
+            for packet in packets.drain(..) {
+                self.output
+                    .packet
+                    .send(packet)
+                    .await
+                    .map_err(|_| QuicError::EndpointClosed)?;
+            }
+            for stream in streams.drain(..) {
+                // 接收方已关闭时丢弃新流
+                let _ = stream_tx.send(stream).await;
+            }
+            for payload in datagrams.drain(..) {
+                let _ = self
+                    .output
+                    .datagram
+                    .try_send(QuicDatagram::new(self.addr, payload));
+            }
+            if worked {
+                continue;
+            }
+
+            let sleep = match timeout {
+                Some(deadline) if deadline <= Instant::now() => continue,
+                Some(deadline) => {
+                    timer.as_mut().reset(deadline.into());
+                    true
+                }
+                None => false,
+            };
+            let parked = poll_fn(|cx| match self.conn.borrow_mut().park(cx.waker()) {
+                true => Poll::Pending,
+                false => Poll::Ready(()),
+            });
+            select! {
+                _ = parked => {},
+                _ = timer.as_mut(), if sleep => {},
+            }
+        }
+    }
+}
+
+impl Drop for LocalRunner {
+    fn drop(&mut self) {
+        self.conns.borrow_mut().remove(&self.hdl);
+        self.addrs.borrow_mut().remove(&self.addr);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::gateway::quic::error::QuicError;
+use crate::gateway::quic::local::conn::LocalConn;
+use crate::gateway::quic::shaper::{RateLimit, Throttle};
+use crate::gateway::quic::tls::PeerIdentity;
+use bytes::{Buf, Bytes};
+use quinn_proto::{FinishError, ReadError, ReadableError, SendStream, StreamId, WriteError, Written};
+use std::future::poll_fn;
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+/// Single-threaded counterpart of [`QuicStream`](crate::gateway::quic::QuicStream). It reads
+/// and writes the connection directly, leaving only packet I/O to the runner.
+#[derive(Debug)]
+pub struct LocalQuicStream {
+    id: StreamId,
+    conn: LocalConn,
+    throttle: Throttle,
+    /// Opened before the handshake, sending 0-RTT data.
+    early: bool,
+}
+
+pub(super) type LocalQuicStreamTx = mpsc::Sender<LocalQuicStream>;
+pub type LocalQuicStreamRx = mpsc::Receiver<LocalQuicStream>;
+
+fn read_error(e: ReadError) -> Error {
+    match e {
+        ReadError::Reset(code) => QuicError::StreamReset(code).into(),
+        ReadError::Blocked => unreachable!("Blocked is handled by the caller"),
+    }
+}
+
+fn write_error(e: WriteError) -> Error {
+    match e {
+        WriteError::Stopped(code) => QuicError::StreamStopped(code).into(),
+        WriteError::ClosedStream => QuicError::StreamClosed.into(),
+        WriteError::Blocked => unreachable!("Blocked is handled by the caller"),
+    }
+}
+
+/// Writes at most the first `quota` bytes of `bufs` without copying, advancing `bufs` past
+/// what quinn-proto took.
+fn write_prefix(
+    stream: &mut SendStream<'_>,
+    bufs: &mut [Bytes],
+    quota: usize,
+) -> std::result::Result<Written, WriteError> {
+    let mut left = quota;
+    let mut prefix = Vec::new();
+    for buf in bufs.iter() {
+        if left == 0 {
+            break;
+        }
+        let len = buf.len().min(left);
+        prefix.push(buf.slice(..len));
+        left -= len;
+    }
+    let mut left = stream.write_chunks(&mut prefix)?.bytes;
+    let mut written = Written {
+        bytes: left,
+        chunks: 0,
+    };
+    for buf in bufs.iter_mut() {
+        if left == 0 {
+            break;
+        }
+        let len = buf.len().min(left);
+        buf.advance(len);
+        left -= len;
+        if buf.is_empty() {
+            written.chunks += 1;
+        }
+    }
+    Ok(written)
+}
+
+impl LocalQuicStream {
+    pub(super) fn new(id: StreamId, conn: LocalConn, early: bool) -> Self {
+        Self {
+            id,
+            conn,
+            throttle: Throttle::default(),
+            early,
+        }
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.conn.borrow().conn.remote_address()
+    }
+
+    /// See [`QuicStream::peer_identity`](crate::gateway::quic::QuicStream::peer_identity).
+    pub fn peer_identity(&self) -> Option<Arc<PeerIdentity>> {
+        self.conn.borrow_mut().peer_identity()
+    }
+
+    /// See [`QuicStream::alpn`](crate::gateway::quic::QuicStream::alpn).
+    pub fn alpn(&self) -> Option<Vec<u8>> {
+        self.conn.borrow().alpn()
+    }
+
+    /// See [`QuicStream::server_name`](crate::gateway::quic::QuicStream::server_name).
+    pub fn server_name(&self) -> Option<String> {
+        self.conn.borrow().server_name()
+    }
+
+    /// See [`QuicStream::set_rate_limit`](crate::gateway::quic::QuicStream::set_rate_limit).
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.throttle.set(limit);
+    }
+
+    /// Bytes read out of quinn-proto ahead of this stream's reader, none by default. Like
+    /// [`QuicStream::set_receive_window`](crate::gateway::quic::QuicStream::set_receive_window),
+    /// the peer may send this much plus the endpoint's
+    /// [stream receive window](super::LocalQuicEndpoint::set_stream_receive_window) before it
+    /// blocks. Data keeps arriving while the reader is busy, at the cost of that memory.
+    pub fn set_receive_window(&self, window: usize) {
+        self.conn.borrow_mut().set_read_ahead(self.id, window);
+    }
+
+    fn poll_chunk(&mut self, cx: &mut Context<'_>, max: usize) -> Poll<Result<Option<Bytes>>> {
+        let mut state = self.conn.borrow_mut();
+        state.check_stream(self.early)?;
+        if let Some(res) = state.poll_read_ahead(cx, self.id, max) {
+            return res;
+        }
+        let mut recv = state.conn.recv_stream(self.id);
+        let mut chunks = match recv.read(true) {
+            Ok(chunks) => chunks,
+            Err(ReadableError::ClosedStream) => return Poll::Ready(Ok(None)),
+            Err(ReadableError::IllegalOrderedRead) => unreachable!("Reads are always ordered"),
+        };
+        let res = match chunks.next(max) {
+            Ok(Some(chunk)) => Ok(Some(chunk.bytes)),
+            Ok(None) => Ok(None),
+            Err(ReadError::Blocked) => Err(None),
+            Err(e) => Err(Some(read_error(e))),
+        };
+        // 读出数据后可能需要发送 MAX_STREAM_DATA，交给 runner
+        if chunks.finalize().should_transmit() {
+            state.wake();
+        }
+        match res {
+            Ok(chunk) => Poll::Ready(Ok(chunk)),
+            Err(Some(e)) => Poll::Ready(Err(e)),
+            Err(None) => {
+                state.readers.insert(self.id, cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Reads the next chunk of at most `max` bytes without copying, `None` at the end of the stream.
+    pub async fn read_chunk(&mut self, max: usize) -> Result<Option<Bytes>> {
+        poll_fn(|cx| self.poll_chunk(cx, max)).await
+    }
+
+    fn poll_write_chunks(&mut self, cx: &mut Context<'_>, bufs: &mut [Bytes]) -> Poll<Result<Written>> {
+        let total: usize = bufs.iter().map(Bytes::len).sum();
+        let quota = ready!(self.throttle.poll_quota(cx, total));
+        let mut state = self.conn.borrow_mut();
+        state.check_stream(self.early)?;
+        let quota = ready!(state.poll_egress(cx, quota));
+        let mut stream = state.conn.send_stream(self.id);
+        let res = match quota < total {
+            true => write_prefix(&mut stream, bufs, quota),
+            false => stream.write_chunks(bufs),
+        };
+        match res {
+            Ok(written) => {
+                self.throttle.consume(written.bytes);
+                state.egress.consume(written.bytes);
+                state.wake();
+                Poll::Ready(Ok(written))
+            }
+            Err(WriteError::Blocked) => {
+                state.writers.insert(self.id, cx.waker().clone());
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(write_error(e))),
+        }
+    }
+
+    /// Writes as much of `bufs` as flow control allows without copying, advancing them in place,
+    /// and returns how many bytes that was.
+    pub async fn write_chunks(&mut self, bufs: &mut [Bytes]) -> Result<usize> {
+        if bufs.iter().all(|b| b.is_empty()) {
+            return Ok(0);
+        }
+        let written = poll_fn(|cx| self.poll_write_chunks(cx, bufs)).await?;
+        Ok(written.bytes)
+    }
+
+    /// Writes all of `data` without copying.
+    pub async fn write_chunk(&mut self, data: Bytes) -> Result<()> {
+        let mut bufs = [data];
+        while !bufs[0].is_empty() {
+            self.write_chunks(&mut bufs).await?;
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for LocalQuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        match this.poll_chunk(cx, buf.remaining()) {
+            Poll::Ready(Ok(Some(chunk))) => {
+                buf.put_slice(&chunk);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Ok(None)) => Poll::Ready(Ok(())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for LocalQuicStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let quota = ready!(this.throttle.poll_quota(cx, buf.len()));
+        let mut state = this.conn.borrow_mut();
+        state.check_stream(this.early)?;
+        let quota = ready!(state.poll_egress(cx, quota));
+        match state.conn.send_stream(this.id).write(&buf[..quota]) {
+            Ok(n) => {
+                this.throttle.consume(n);
+                state.egress.consume(n);
+                state.wake();
+                Poll::Ready(Ok(n))
+            }
+            Err(WriteError::Blocked) => {
+                state.writers.insert(this.id, cx.waker().clone());
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(write_error(e))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        // 写入即进入状态机，flush 只需唤醒 runner
+        self.conn.borrow_mut().wake();
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let mut state = self.conn.borrow_mut();
+        state.check_stream(self.early)?;
+        let res = match state.conn.send_stream(self.id).finish() {
+            Ok(()) | Err(FinishError::ClosedStream) => Ok(()),
+            Err(FinishError::Stopped(code)) => Err(QuicError::StreamStopped(code).into()),
+        };
+        state.wake();
+        Poll::Ready(res)
+    }
+}
+
+impl Drop for LocalQuicStream {
+    fn drop(&mut self) {
+        let mut state = self.conn.borrow_mut();
+        // 被拒绝的 0-RTT 流的 ID 可能已被新流复用
+        if !state.forgotten(self.early) {
+            state.close(self.id);
+        }
+    }
+}
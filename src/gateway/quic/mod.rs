@@ -1,10 +1,41 @@
 mod utils;
+mod error;
 mod packet;
+mod datagram;
+mod framer;
 mod conn;
+mod shard;
+mod shaper;
+mod transport;
+mod tls;
+mod certs;
+mod qlog;
+mod capture;
+mod staging;
 mod stream;
 mod runner;
+mod driver;
 mod endpoint;
+pub mod local;
 
+pub use error::QuicError;
 pub use packet::*;
+pub use datagram::*;
+pub use conn::{InboxConfig, InboxOverflow};
+pub use framer::*;
+pub use shard::{ShardedCidGenerator, SHARDED_CID_LEN};
 pub use endpoint::*;
 pub use stream::*;
+pub use shaper::RateLimit;
+pub use transport::{CongestionControl, MtuConfig, TransportProfile};
+pub use certs::ServerCerts;
+pub use capture::CaptureConfig;
+pub use tls::{
+    Authorizer, ClientAuth, EarlyData, Identity, PeerIdentity, SessionConfig, TlsConfig, STREAM_UNAUTHORIZED,
+};
+pub use quinn_proto::congestion::{BbrConfig, Controller, ControllerFactory, CubicConfig, NewRenoConfig};
+pub use quinn_proto::{ConnectionStats, RttEstimator};
+pub use rustls::client::{ClientSessionMemoryCache, ClientSessionStore};
+pub use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+pub use rustls::server::{ServerSessionMemoryCache, StoresServerSessions};
+pub use rustls::RootCertStore;
@@ -1,8 +1,12 @@
+use std::io::{Error, ErrorKind, Result};
 use std::net::SocketAddr;
-use bytes::BytesMut;
+use std::sync::{Arc, OnceLock};
+use bytes::{Buf, BytesMut};
 use derive_more::{Constructor, Deref, DerefMut};
 use quinn_proto::Transmit;
 use tokio::sync::mpsc;
+use crate::gateway::quic::capture::{Capture, Direction};
+use crate::gateway::quic::framer::PacketFramer;
 use crate::gateway::quic::utils::{BufMargins, BufPool};
 
 const PACKET_POOL_MIN_CAPACITY: usize = 65536;
@@ -35,12 +39,78 @@ impl PacketPool {
     }
 }
 
-#[derive(Debug, Clone, Deref, DerefMut, Constructor)]
+#[derive(Debug, Clone, Deref, DerefMut)]
 pub(super) struct QuicPacketTx {
     #[deref]
     #[deref_mut]
     packet: mpsc::Sender<QuicPacket>,
     pub(super) margins: QuicPacketMargins,
+    /// Shared with the connections' copies, which exist before the framer may be set.
+    framer: Arc<OnceLock<Arc<dyn PacketFramer>>>,
+    pub(super) capture: Capture,
+}
+
+impl QuicPacketTx {
+    pub(super) fn new(packet: mpsc::Sender<QuicPacket>, margins: QuicPacketMargins) -> Self {
+        Self {
+            packet,
+            margins,
+            framer: Arc::default(),
+            capture: Capture::default(),
+        }
+    }
+
+    /// Sets the framer once. Its margins must be the ones packets are laid out with.
+    pub(super) fn set_framer(&self, framer: Arc<dyn PacketFramer>) -> Result<()> {
+        let margins = framer.margins();
+        if (margins.header, margins.trailer) != self.margins.into() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Framer margins {:?} differ from the packet margins {:?}", margins, self.margins),
+            ));
+        }
+        self.framer
+            .set(framer)
+            .map_err(|_| Error::new(ErrorKind::AlreadyExists, "A framer is already set"))
+    }
+
+    /// Fills the margins of an outgoing packet.
+    pub(super) fn frame(&self, packet: &mut QuicPacket) {
+        if self.capture.is_on() {
+            let (header, trailer) = self.margins.into();
+            let len = packet.payload.len();
+            self.capture
+                .record(packet.addr, &packet.payload[header..len - trailer], Direction::Outbound);
+        }
+        if let Some(framer) = self.framer.get() {
+            framer.frame(&mut packet.payload);
+        }
+    }
+
+    /// Checks and strips the margins of a packet from `addr`. Without a framer, incoming
+    /// packets are taken as bare QUIC datagrams.
+    pub(super) fn unframe(&self, addr: SocketAddr, payload: &mut BytesMut) -> Result<()> {
+        self.strip(payload)?;
+        self.capture.record(addr, payload, Direction::Inbound);
+        Ok(())
+    }
+
+    fn strip(&self, payload: &mut BytesMut) -> Result<()> {
+        let Some(framer) = self.framer.get() else {
+            return Ok(());
+        };
+        let (header, trailer) = self.margins.into();
+        if payload.len() < header + trailer {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Packet of {} bytes shorter than its margins", payload.len()),
+            ));
+        }
+        framer.check(payload)?;
+        payload.advance(header);
+        payload.truncate(payload.len() - trailer);
+        Ok(())
+    }
 }
 
 pub type QuicPacketRx = mpsc::Receiver<QuicPacket>;
\ No newline at end of file
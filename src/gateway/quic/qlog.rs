@@ -0,0 +1,251 @@
+use quinn_proto::{Connection, ConnectionError, Side};
+use std::fmt::{Arguments, Write as _};
+use std::fs::File;
+use std::io::{BufWriter, Error, Result, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// qlog trace of one connection, written as JSON-SEQ to a `.sqlog` file.
+///
+/// quinn-proto does not expose packet numbers or the frames of a packet, so packets are
+/// described by their header type and length, and frames, losses and congestion state are
+/// derived from [`ConnectionStats`](quinn_proto::ConnectionStats) between events.
+#[derive(Debug)]
+pub(super) struct Qlog {
+    /// Dropped after the first failed write.
+    out: Option<BufWriter<File>>,
+    start: Instant,
+    last: Snapshot,
+}
+
+#[derive(Debug, Default)]
+struct Snapshot {
+    stream_tx: u64,
+    stream_rx: u64,
+    lost_packets: u64,
+    lost_bytes: u64,
+    congestion_events: u64,
+    rtt: Duration,
+    cwnd: u64,
+    ssthresh: Option<u64>,
+    pacing_rate: Option<u64>,
+    state: &'static str,
+}
+
+/// Header type and length of a datagram, taken before quinn-proto consumes it.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Received {
+    packet_type: &'static str,
+    length: usize,
+}
+
+impl Received {
+    pub(super) fn of(datagram: &[u8]) -> Self {
+        Self {
+            packet_type: packet_type(datagram),
+            length: datagram.len(),
+        }
+    }
+}
+
+impl Qlog {
+    /// Creates `<side>-<peer>-<unix ms>.sqlog` in `dir`.
+    pub(super) fn create(dir: &Path, conn: &Connection) -> Result<Self> {
+        let side = match conn.side() {
+            Side::Client => "client",
+            Side::Server => "server",
+        };
+        let peer = conn.remote_address().to_string();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let path = dir.join(format!(
+            "{}-{}-{}.sqlog",
+            side,
+            peer.replace([':', '[', ']'], "_"),
+            now.as_millis()
+        ));
+        let file = File::create(&path)
+            .map_err(|e| Error::new(e.kind(), format!("Failed to create qlog file {:?}: {}", path, e)))?;
+
+        let mut qlog = Self {
+            out: Some(BufWriter::new(file)),
+            start: Instant::now(),
+            last: Snapshot::default(),
+        };
+        qlog.record(format_args!(
+            "{{\"qlog_version\":\"0.3\",\"qlog_format\":\"JSON-SEQ\",\"title\":\"qs\",\"trace\":{{\
+             \"vantage_point\":{{\"type\":\"{}\"}},\"common_fields\":{{\"time_format\":\"relative\",\
+             \"reference_time\":{},\"group_id\":{}}}}}}}",
+            side,
+            now.as_millis(),
+            json_string(&peer),
+        ));
+        Ok(qlog)
+    }
+
+    /// Logs a datagram `conn` just wrote, with the stream frames it took.
+    pub(super) fn packet_sent(&mut self, conn: &Connection, datagram: &[u8]) {
+        let stream_tx = conn.stats().frame_tx.stream;
+        let frames = stream_tx.saturating_sub(self.last.stream_tx);
+        self.last.stream_tx = stream_tx;
+
+        let mut data = format!(
+            "\"header\":{{\"packet_type\":\"{}\"}},\"raw\":{{\"length\":{}}}",
+            packet_type(datagram),
+            datagram.len()
+        );
+        if frames > 0 {
+            let _ = write!(data, ",\"frames\":{}", stream_frames(frames));
+        }
+        self.event("transport:packet_sent", format_args!("{}", data));
+    }
+
+    pub(super) fn packet_received(&mut self, packet: Received) {
+        self.event(
+            "transport:packet_received",
+            format_args!(
+                "\"header\":{{\"packet_type\":\"{}\"}},\"raw\":{{\"length\":{}}}",
+                packet.packet_type, packet.length
+            ),
+        );
+    }
+
+    /// Logs what changed in `conn` since the last call: stream frames processed, losses,
+    /// recovery metrics and the congestion state.
+    pub(super) fn update(&mut self, conn: &Connection) {
+        let stats = conn.stats();
+        let metrics = conn.congestion_state().metrics();
+        let last = std::mem::take(&mut self.last);
+
+        let frames = stats.frame_rx.stream.saturating_sub(last.stream_rx);
+        if frames > 0 {
+            self.event(
+                "transport:frames_processed",
+                format_args!("\"frames\":{}", stream_frames(frames)),
+            );
+        }
+
+        // 丢包只有累计值，按两次之间的差值合并记录
+        let lost = stats.path.lost_packets.saturating_sub(last.lost_packets);
+        if lost > 0 {
+            self.event(
+                "recovery:packet_lost",
+                format_args!(
+                    "\"count\":{},\"bytes\":{}",
+                    lost,
+                    stats.path.lost_bytes.saturating_sub(last.lost_bytes)
+                ),
+            );
+        }
+
+        let next = Snapshot {
+            stream_tx: last.stream_tx,
+            stream_rx: stats.frame_rx.stream,
+            lost_packets: stats.path.lost_packets,
+            lost_bytes: stats.path.lost_bytes,
+            congestion_events: stats.path.congestion_events,
+            rtt: stats.path.rtt,
+            cwnd: metrics.congestion_window,
+            ssthresh: metrics.ssthresh,
+            pacing_rate: metrics.pacing_rate,
+            state: match metrics.ssthresh {
+                _ if stats.path.congestion_events > last.congestion_events => "recovery",
+                Some(ssthresh) if metrics.congestion_window >= ssthresh => "congestion_avoidance",
+                _ => "slow_start",
+            },
+        };
+
+        if (next.rtt, next.cwnd, next.ssthresh, next.pacing_rate)
+            != (last.rtt, last.cwnd, last.ssthresh, last.pacing_rate)
+        {
+            let mut data = format!(
+                "\"smoothed_rtt\":{:.3},\"congestion_window\":{}",
+                next.rtt.as_secs_f64() * 1000.0,
+                next.cwnd
+            );
+            if let Some(ssthresh) = next.ssthresh {
+                let _ = write!(data, ",\"ssthresh\":{}", ssthresh);
+            }
+            if let Some(pacing_rate) = next.pacing_rate {
+                let _ = write!(data, ",\"pacing_rate\":{}", pacing_rate);
+            }
+            self.event("recovery:metrics_updated", format_args!("{}", data));
+        }
+        if next.state != last.state {
+            let data = match last.state {
+                "" => format!("\"new\":\"{}\"", next.state),
+                old => format!("\"old\":\"{}\",\"new\":\"{}\"", old, next.state),
+            };
+            self.event("recovery:congestion_state_updated", format_args!("{}", data));
+        }
+        self.last = next;
+    }
+
+    /// Logs why the connection was lost and flushes the file.
+    pub(super) fn closed(&mut self, reason: &ConnectionError) {
+        self.event(
+            "connectivity:connection_closed",
+            format_args!("\"reason\":{}", json_string(&reason.to_string())),
+        );
+        if let Some(Err(e)) = self.out.as_mut().map(BufWriter::flush) {
+            error!("Failed to write qlog: {:?}", e);
+            self.out = None;
+        }
+    }
+
+    fn event(&mut self, name: &str, data: Arguments<'_>) {
+        let time = self.start.elapsed().as_secs_f64() * 1000.0;
+        self.record(format_args!(
+            "{{\"time\":{:.3},\"name\":\"{}\",\"data\":{{{}}}}}",
+            time, name, data
+        ));
+    }
+
+    fn record(&mut self, record: Arguments<'_>) {
+        let Some(out) = &mut self.out else {
+            return;
+        };
+        // JSON-SEQ：每条记录以 RS 开头、换行结尾
+        if let Err(e) = writeln!(out, "\x1e{}", record) {
+            error!("Failed to write qlog: {:?}", e);
+            self.out = None;
+        }
+    }
+}
+
+/// Type of the first packet in a datagram, from its header form and long packet type.
+fn packet_type(datagram: &[u8]) -> &'static str {
+    match datagram.first() {
+        None => "unknown",
+        Some(b) if b & 0x80 == 0 => "1RTT",
+        Some(_) if datagram.get(1..5) == Some(&[0; 4]) => "version_negotiation",
+        Some(b) => match (b >> 4) & 0x03 {
+            0 => "initial",
+            1 => "0RTT",
+            2 => "handshake",
+            _ => "retry",
+        },
+    }
+}
+
+fn stream_frames(count: u64) -> String {
+    let frames = vec!["{\"frame_type\":\"stream\"}"; count as usize];
+    format!("[{}]", frames.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
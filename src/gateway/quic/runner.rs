@@ -1,35 +1,53 @@
-use crate::gateway::quic::conn::ConnCtrl;
+use crate::gateway::quic::conn::{cut_packet, ConnCtrl, ConnState, InboxConfig, InboxCounters, WaitingOpens};
+use crate::gateway::quic::error::QuicError;
 use crate::gateway::quic::endpoint::QuicOutputTx;
 use crate::gateway::quic::stream::QuicStream;
-use crate::gateway::quic::utils::BufAcc;
-use crate::gateway::quic::QuicPacket;
+use crate::gateway::quic::QuicDatagram;
+use bytes::{Bytes, BytesMut};
 use derive_more::{Deref, DerefMut};
-use quinn_proto::{Connection, Event, StreamEvent};
+use quinn_proto::{Connection, ConnectionError, Dir, Event, StreamEvent, Transmit};
 use std::collections::VecDeque;
-use std::io::{Error, ErrorKind};
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::select;
 use tokio::time::sleep;
-use tracing::error;
 
 #[derive(Debug, Deref, DerefMut)]
 pub(super) struct Runner {
     #[deref]
     #[deref_mut]
-    ctrl: ConnCtrl,
+    ctrl: Arc<ConnCtrl>,
 
     output: QuicOutputTx,
+
+    pending_streams: VecDeque<QuicStream>,
+    pending_datagrams: Vec<Bytes>,
+    pending_transmits: VecDeque<Transmit>,
+    pending_chunks: VecDeque<BytesMut>,
+    waiting_opens: WaitingOpens<QuicStream>,
+    timeout: Option<Instant>,
 }
 
 impl Runner {
-    pub(super) fn new(conn: Connection, output: QuicOutputTx) -> (ConnCtrl, Self) {
-        let ctrl = ConnCtrl::new(conn);
+    pub(super) fn new(
+        conn: Connection,
+        output: QuicOutputTx,
+        inbox: InboxConfig,
+        counters: Arc<InboxCounters>,
+    ) -> (Arc<ConnCtrl>, Self) {
+        let ctrl = Arc::new(ConnCtrl::new(conn, inbox, counters));
         (
             ctrl.clone(),
             Self {
                 ctrl,
                 output,
+                pending_streams: VecDeque::new(),
+                pending_datagrams: Vec::new(),
+                pending_transmits: VecDeque::new(),
+                pending_chunks: VecDeque::new(),
+                waiting_opens: WaitingOpens::default(),
+                timeout: None,
             },
         )
     }
@@ -37,188 +55,256 @@ impl Runner {
 
 impl Runner {
     pub(super) async fn run(&mut self) -> std::io::Result<()> {
-        let mut pending_streams = VecDeque::new();
-        let mut pending_wakers = Vec::new();
-        let mut pending_transmits = VecDeque::new();
-        let mut pending_chunks = VecDeque::new();
+        let res = self.drive().await;
+        let reason = match &res {
+            Ok(()) => QuicError::ConnectionClosed,
+            Err(e) => QuicError::closed_by(e),
+        };
+        self.finish(reason);
+        res
+    }
+
+    /// Releases everything still waiting on the connection once it stopped being driven,
+    /// failing its streams with `reason`.
+    fn finish(&mut self, reason: QuicError) {
+        // 连接结束后立即唤醒仍在等待暂存区的流，并告知真实原因
+        self.ctrl.state.lock().clear(reason);
+        // runner 不会再清空 inbox，放行仍在背压等待的 send
+        self.ctrl.closed.store(true, Ordering::Release);
+        self.ctrl.refuse_opens();
+        let reason = self.ctrl.state.lock().reason();
+        self.waiting_opens.refuse(reason);
+        self.ctrl.drained.notify_waiters();
+        self.ctrl.connected.notify_waiters();
+    }
 
+    async fn drive(&mut self) -> std::io::Result<()> {
         let mut timer = Box::pin(sleep(Duration::MAX));
-        let mut timeout: Option<Instant> = None;
         let mut handle_timeout = false;
 
         // 启动时强制唤醒一次，确保发送握手包
-        self.ctrl.notify.notify_one();
+        self.ctrl.wake();
 
         loop {
-            let mut worked = false;
-
-            // 2. --- 核心逻辑：处理状态机 ---
-            // [修复] 移除之前的 if !inbox.is_empty() || timeout 判断
-            // 只要醒来，就必须检查状态机，因为可能需要发送握手包或者重传
-            {
-                let mut state = self.ctrl.state.lock();
-                let now = Instant::now();
-
-                // 处理收到的包
-                while let Some(evt) = self.ctrl.inbox.pop() {
-                    state.conn.handle_event(evt);
-                    worked = true;
-                }
+            let mut worked = self.poll(handle_timeout)?;
+            handle_timeout = false;
+            worked |= self.transmit().await?;
 
-                // [Fix]: 即使 select! 没触发，只要时间到了，就必须处理超时
-                // 这是修复 "Connection Lost" 的关键：防止在高吞吐下的时间饥饿
-                if handle_timeout || timeout.is_some_and(|t| t <= now) {
-                    state.conn.handle_timeout(now);
-                    handle_timeout = false;
-                    worked = true; // 标记为工作过，防止 cpu 空转
-                }
-
-                // 处理流开启
-                while let Some((dir, tx)) = self.ctrl.open.pop() {
-                    let id = state.conn.streams().open(dir).ok_or(Error::other("Failed to open new QUIC stream: exhausted"));
-                    if let Err(e) = tx.send(id) {
-                        error!("Failed to send stream ID to ctrl: {:?}", e);
+            // 5. --- 休眠等待 ---
+            // 只有当这一轮什么都没干（没发包，没收包，没状态变更）时才睡觉
+            if !worked {
+                let sleep = match self.timeout {
+                    None => false,
+                    Some(deadline) => {
+                        if deadline <= Instant::now() {
+                            handle_timeout = true;
+                            continue; // 直接进入下一轮循环处理超时
+                        }
+                        timer.as_mut().reset(deadline.into());
+                        true
                     }
+                };
+
+                select! {
+                    _ = self.ctrl.notify.notified() => {}, // 醒来，下一轮循环处理
+                    _ = timer.as_mut(), if sleep => handle_timeout = true,
                 }
+            }
+        }
+    }
+
+    /// Runs the state machine once over everything queued for it and collects its output.
+    fn poll(&mut self, handle_timeout: bool) -> std::io::Result<bool> {
+        let mut worked = false;
+
+        // 2. --- 核心逻辑：处理状态机 ---
+        // [修复] 移除之前的 if !inbox.is_empty() || timeout 判断
+        // 只要醒来，就必须检查状态机，因为可能需要发送握手包或者重传
+        {
+            let mut state = self.ctrl.state.lock();
+            let state = &mut *state;
+            let now = Instant::now();
+
+            // 端点已被丢弃：立即通知对端关闭，对端无需等到空闲超时
+            if self.ctrl.shutdown.load(Ordering::Acquire) {
+                state.destroy();
+                return Err(state.lost(ConnectionError::LocallyClosed, &self.output.packet).into());
+            }
+
+            // 处理收到的包
+            let mut drained = false;
+            while let Some(evt) = self.ctrl.inbox.pop() {
+                state.conn.handle_event(evt);
+                drained = true;
+            }
+            if drained {
+                // 唤醒因 inbox 已满而等待的 QuicEndpoint::send
+                self.ctrl.drained.notify_waiters();
+                worked = true;
+            }
+
+            // [Fix]: 即使 select! 没触发，只要时间到了，就必须处理超时
+            // 这是修复 "Connection Lost" 的关键：防止在高吞吐下的时间饥饿
+            if handle_timeout || self.timeout.is_some_and(|t| t <= now) {
+                state.conn.handle_timeout(now);
+                worked = true; // 标记为工作过，防止 cpu 空转
+            }
 
-                // 处理流关闭
-                while let Some(id) = self.ctrl.close.pop() {
-                    state.close(id, false);
+            // 0-RTT 被拒绝后流 ID 会被复用，必须在开新流之前清理旧的暂存区
+            state.settle_early_data();
+
+            // 处理流开启：额度不足时排队，按请求顺序等待 Available
+            let mut requested = (false, false);
+            while let Some((dir, tx)) = self.ctrl.open.pop() {
+                match dir {
+                    Dir::Bi => requested.0 = true,
+                    Dir::Uni => requested.1 = true,
                 }
+                self.waiting_opens.queue(dir).push_back(tx);
+            }
+            if requested.0 {
+                self.waiting_opens.grant_staged(state, &self.ctrl, Dir::Bi);
+            }
+            if requested.1 {
+                self.waiting_opens.grant_staged(state, &self.ctrl, Dir::Uni);
+            }
 
-                // 驱动状态机 (处理握手、流开启等)
-                while let Some(evt) = state.conn.poll() {
-                    worked = true; // 状态机有变动，标记为工作过
-                    match evt {
-                        Event::Stream(StreamEvent::Opened { dir }) => {
-                            if self.output.stream.switch().load(Ordering::Relaxed) {
-                                while let Some(id) = state.conn.streams().accept(dir) {
-                                    pending_streams.push_back(id);
-                                }
-                            }
-                        }
-                        Event::Stream(StreamEvent::Readable { id }) => {
-                            if let Some(waker) = state.readers.remove(&id) {
-                                pending_wakers.push(waker);
-                            }
-                        }
-                        Event::Stream(StreamEvent::Writable { id }) => {
-                            if let Some(waker) = state.writers.remove(&id) {
-                                pending_wakers.push(waker);
+            // 处理流关闭
+            while let Some(id) = self.ctrl.close.pop() {
+                state.close(id, false);
+            }
+
+            // 把流暂存区的数据交给状态机，流本身从不持有连接锁
+            state.flush_throttled();
+            while let Some(id) = self.ctrl.flush.pop() {
+                state.flush(id);
+            }
+            while let Some(id) = self.ctrl.pump.pop() {
+                state.pump(id);
+            }
+
+            // 运行时调整的流数与窗口，放宽的额度随后的 poll_transmit 通告给对端
+            while let Some(profile) = self.ctrl.profile.pop() {
+                state.set_profile(profile);
+            }
+
+            // 驱动状态机 (处理握手、流开启等)
+            while let Some(evt) = state.conn.poll() {
+                worked = true; // 状态机有变动，标记为工作过
+                match evt {
+                    Event::Stream(StreamEvent::Opened { dir })
+                        if self.output.stream.switch().load(Ordering::Relaxed) =>
+                    {
+                        while let Some(id) = state.conn.streams().accept(dir) {
+                            // 未通过授权的流不进入暂存区，也不交给上层
+                            if !state.authorize(id) {
+                                continue;
                             }
+                            // 新流的首批数据不会再产生 Readable 事件，立即拉取
+                            let staging = state.stage(id);
+                            state.pump(id);
+                            self.pending_streams.push_back(QuicStream::new(id, self.ctrl.clone(), staging));
                         }
-                        Event::ConnectionLost { reason } => {
-                            return Err(Error::new(ErrorKind::ConnectionReset, format!("Connection lost: {:?}", reason)));
-                        }
-                        _ => {}
                     }
-                }
-
-                // 生成待发送数据包
-                let margins = self.output.packet.margins;
-                let mut chunk = BufAcc::new(256 * 1200);
-                loop {
-                    let mut buf = match chunk.buf(
-                        state.conn.current_mtu() as usize,
-                        margins,
-                    ) {
-                        Some(buf) => buf,
-                        None => {
-                            pending_chunks.push_back(chunk.renew());
-                            chunk
-                                .buf(
-                                    state.conn.current_mtu() as usize,
-                                    margins,
-                                )
-                                .unwrap()
-                        }
-                    };
-                    let transmit = state.conn.poll_transmit(Instant::now(), 1, &mut buf);
-                    match transmit {
-                        None => {
-                            if !chunk.is_empty() {
-                                pending_chunks.push_back(chunk.take());
-                            }
-                            break;
+                    Event::Stream(StreamEvent::Available { dir }) => self.waiting_opens.grant_staged(state, &self.ctrl, dir),
+                    Event::Stream(StreamEvent::Readable { id }) => state.pump(id),
+                    Event::Stream(StreamEvent::Writable { id }) => state.flush(id),
+                    Event::Stream(StreamEvent::Stopped { id, error_code }) => state.stopped(id, error_code),
+                    Event::DatagramReceived => {
+                        while let Some(data) = state.conn.datagrams().recv() {
+                            self.pending_datagrams.push(data);
                         }
-                        Some(transmit) => {
-                            buf.commit();
-                            pending_transmits.push_back(transmit);
+                    }
+                    // 服务端此时已知 SNI，早于任何流被接受
+                    Event::HandshakeDataReady => {
+                        state.apply_profile();
+                        // 同一连接的流都属于协商出的协议，改投该协议的通道
+                        let route = state.alpn().and_then(|protocol| self.output.routes.get(&protocol).map(|tx| tx.clone()));
+                        if let Some(tx) = route {
+                            self.output.stream = tx;
                         }
                     }
+                    Event::Connected => {
+                        state.established = true;
+                        // 握手带来的初始额度不会产生 Available 事件
+                        self.waiting_opens.grant_staged(state, &self.ctrl, Dir::Bi);
+                        self.waiting_opens.grant_staged(state, &self.ctrl, Dir::Uni);
+                        self.ctrl.connected.notify_waiters();
+                    }
+                    Event::ConnectionLost { reason } => return Err(state.lost(reason, &self.output.packet).into()),
+                    _ => {}
                 }
+            }
 
-                timeout = state.conn.poll_timeout();
-            } // 释放 state 锁
+            // 生成待发送数据包
+            let margins = self.output.packet.margins;
+            state.poll_transmits(margins, &mut self.pending_transmits, &mut self.pending_chunks);
 
-            // 3. --- 唤醒应用层 Wakers ---
-            for waker in pending_wakers.drain(..) {
-                waker.wake();
-            }
+            // 出口限速只作用于交给状态机的流数据，令牌恢复时醒来继续刷出被限速的流
+            self.timeout = state.timeout();
+        } // 释放 state 锁
 
-            // 4. --- 发送阶段：带“接收抢占”的发送 ---
-            let (header, trailer) = self.output.packet.margins.into();
-            while !pending_transmits.is_empty() || !pending_streams.is_empty() {
-                select! {
-                    biased;
-
-                    // 发送 Packet
-                    res = self.output.packet.reserve(), if !pending_transmits.is_empty() => {
-                        match res {
-                            Ok(permit) => {
-                                let transmit = pending_transmits.pop_front().unwrap();
-                                let chunk = pending_chunks.front_mut().unwrap();
-                                let data = chunk.split_to(header + transmit.size + trailer);
-                                if chunk.is_empty() {
-                                    pending_chunks.pop_front();
-                                }
-                                let packet = QuicPacket::new(transmit.destination, data);
-                                permit.send(packet);
-                                worked = true;
-                            }
-                            Err(_) => return Err(Error::new(ErrorKind::BrokenPipe, "Packet channel closed")),
-                        }
-                    }
+        // 数据报不可靠，接收方处理不过来时直接丢弃
+        for payload in self.pending_datagrams.drain(..) {
+            let _ = self
+                .output
+                .datagram
+                .try_send(QuicDatagram::new(self.ctrl.addr, payload));
+        }
 
-                    // 发送 Stream
-                    res = self.output.stream.reserve(), if !pending_streams.is_empty() => {
-                        match res {
-                            Ok(permit) => {
-                                permit.send(QuicStream::new(pending_streams.pop_front().unwrap(), self.ctrl.clone()));
-                                worked = true;
-                            }
-                            Err(_) => return Err(Error::new(ErrorKind::BrokenPipe, "Stream channel closed")),
-                        }
-                    }
+        Ok(worked)
+    }
+
+    /// Hands collected packets and streams to the output channels until done or woken.
+    async fn transmit(&mut self) -> std::io::Result<bool> {
+        let mut worked = false;
 
-                    // 监听接收事件：如果有新包入队，立即停止发送，回去处理接收
-                    _ = self.ctrl.notify.notified() => {
-                        worked = true;
-                        break;
+        // 4. --- 发送阶段：带“接收抢占”的发送 ---
+        while !self.pending_transmits.is_empty() || !self.pending_streams.is_empty() {
+            select! {
+                biased;
+
+                // 发送 Packet
+                res = self.output.packet.reserve(), if !self.pending_transmits.is_empty() => {
+                    match res {
+                        Ok(permit) => {
+                            permit.send(cut_packet(&mut self.pending_transmits, &mut self.pending_chunks, &self.output.packet).unwrap());
+                            worked = true;
+                        }
+                        Err(_) => return Err(QuicError::EndpointClosed.into()),
                     }
                 }
-            }
 
-            // 5. --- 休眠等待 ---
-            // 只有当这一轮什么都没干（没发包，没收包，没状态变更）时才睡觉
-            if !worked {
-                let sleep = match timeout {
-                    None => false,
-                    Some(deadline) => {
-                        if deadline <= Instant::now() {
-                            handle_timeout = true;
-                            continue; // 直接进入下一轮循环处理超时
+                // 发送 Stream
+                res = self.output.stream.reserve(), if !self.pending_streams.is_empty() => {
+                    match res {
+                        Ok(permit) => {
+                            permit.send(self.pending_streams.pop_front().unwrap());
+                            worked = true;
                         }
-                        timer.as_mut().reset(deadline.into());
-                        true
+                        Err(_) => return Err(QuicError::EndpointClosed.into()),
                     }
-                };
+                }
 
-                select! {
-                    _ = self.ctrl.notify.notified() => {}, // 醒来，下一轮循环处理
-                    _ = timer.as_mut(), if sleep => handle_timeout = true,
+                // 监听接收事件：如果有新包入队，立即停止发送，回去处理接收
+                _ = self.ctrl.notify.notified() => {
+                    worked = true;
+                    break;
                 }
             }
         }
+
+        Ok(worked)
+    }
+}
+
+impl WaitingOpens<QuicStream> {
+    /// Opens and stages streams for the requests waiting in `dir`, as far as the peer's
+    /// credit goes.
+    fn grant_staged(&mut self, state: &mut ConnState, ctrl: &Arc<ConnCtrl>, dir: Dir) {
+        self.grant(dir, |dir| {
+            let (id, staging) = state.open(dir)?;
+            Some(QuicStream::new(id, ctrl.clone(), staging))
+        });
     }
 }
@@ -0,0 +1,130 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::{sleep_until, Sleep};
+
+/// Smallest grant a parked writer waits for, so a drained bucket does not hand out
+/// byte-sized writes as it refills.
+pub(super) const WRITE_GRANULE: usize = 16 * 1024;
+
+/// Token bucket refilled at `rate` bytes per second and holding at most `burst` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub rate: u64,
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// `rate` bytes per second with a burst of 100 ms worth, but at least 64 KiB.
+    pub fn per_second(rate: u64) -> Self {
+        Self {
+            rate,
+            burst: (rate / 10).max(64 * 1024),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    /// Goes negative when a packet larger than what was left passed, delaying the next one.
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate as f64).min(self.limit.burst as f64);
+        self.last = now;
+    }
+}
+
+/// Optional token bucket, letting everything pass while no limit is set.
+#[derive(Debug, Default)]
+pub(super) struct Shaper {
+    bucket: Option<TokenBucket>,
+}
+
+impl Shaper {
+    /// Replaces the limit, starting from a full bucket.
+    pub(super) fn set(&mut self, limit: Option<RateLimit>) {
+        self.bucket = limit.map(|limit| TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            last: Instant::now(),
+        });
+    }
+
+    /// Bytes that may pass at `now`, `None` without a limit.
+    pub(super) fn quota(&mut self, now: Instant) -> Option<usize> {
+        let bucket = self.bucket.as_mut()?;
+        bucket.refill(now);
+        Some(bucket.tokens.max(0.0) as usize)
+    }
+
+    pub(super) fn consume(&mut self, len: usize) {
+        if let Some(bucket) = &mut self.bucket {
+            bucket.tokens -= len as f64;
+        }
+    }
+
+    /// Whether `len` bytes, capped at the burst, may pass at `now`.
+    pub(super) fn allows(&mut self, len: usize, now: Instant) -> bool {
+        let Some(bucket) = &mut self.bucket else {
+            return true;
+        };
+        bucket.refill(now);
+        self.ready_for(len).is_none()
+    }
+
+    /// When `len` bytes may pass, capped at the burst. `None` if they may pass already.
+    pub(super) fn ready_for(&self, len: usize) -> Option<Instant> {
+        let bucket = self.bucket.as_ref()?;
+        let need = (len as u64).min(bucket.limit.burst).max(1) as f64;
+        if bucket.tokens >= need {
+            return None;
+        }
+        let wait = (need - bucket.tokens) / bucket.limit.rate.max(1) as f64;
+        Some(bucket.last + Duration::from_secs_f64(wait))
+    }
+}
+
+/// A [`Shaper`] for writers, parking them on a timer while the bucket refills.
+#[derive(Debug, Default)]
+pub(super) struct Throttle {
+    shaper: Shaper,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    pub(super) fn set(&mut self, limit: Option<RateLimit>) {
+        self.shaper.set(limit);
+        self.sleep = None;
+    }
+
+    /// Bytes that may be written now, at most `max`. Waits until `max` or
+    /// [`WRITE_GRANULE`] bytes fit, whichever is smaller.
+    pub(super) fn poll_quota(&mut self, cx: &mut Context<'_>, max: usize) -> Poll<usize> {
+        let need = max.min(WRITE_GRANULE);
+        loop {
+            let Some(quota) = self.shaper.quota(Instant::now()) else {
+                return Poll::Ready(max);
+            };
+            let Some(deadline) = self.shaper.ready_for(need) else {
+                return Poll::Ready(quota.min(max));
+            };
+            let deadline = deadline.into();
+            let sleep = self.sleep.get_or_insert_with(|| Box::pin(sleep_until(deadline)));
+            sleep.as_mut().reset(deadline);
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    pub(super) fn consume(&mut self, len: usize) {
+        self.shaper.consume(len);
+    }
+}
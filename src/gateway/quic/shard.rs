@@ -0,0 +1,124 @@
+use quinn_proto::{
+    ConnectionId, ConnectionIdGenerator, InvalidCid, RandomConnectionIdGenerator,
+};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+pub const SHARDED_CID_LEN: usize = 8;
+
+/// Longest connection ID QUIC v1 allows.
+const MAX_CID_LEN: usize = 20;
+
+/// How long packets to the connection ID a client picked keep following the first one.
+/// Clients switch to an ID the server issued with its first reply, so only retransmitted
+/// or reordered packets of the handshake come later. Matches the idle timeout.
+const HANDSHAKE_ROUTE_TTL: Duration = Duration::from_secs(30);
+
+/// Issues random connection IDs whose first byte is congruent to `shard` modulo `shards`,
+/// so that packets for a connection can be routed to the endpoint shard owning it.
+#[derive(Debug, Clone, Copy)]
+pub struct ShardedCidGenerator {
+    inner: RandomConnectionIdGenerator,
+    shard: usize,
+    shards: usize,
+}
+
+impl ShardedCidGenerator {
+    pub fn new(shard: usize, shards: usize) -> Self {
+        assert!(shard < shards && shards <= 256, "Invalid shard {} of {}", shard, shards);
+        Self {
+            inner: RandomConnectionIdGenerator::new(SHARDED_CID_LEN),
+            shard,
+            shards,
+        }
+    }
+}
+
+impl ConnectionIdGenerator for ShardedCidGenerator {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let mut bytes = [0u8; SHARDED_CID_LEN];
+        bytes.copy_from_slice(&self.inner.generate_cid());
+        // 保留首字节的随机高位，只把余数固定为分片号
+        let byte = bytes[0] as usize;
+        let mut byte = byte - byte % self.shards + self.shard;
+        if byte > u8::MAX as usize {
+            byte -= self.shards;
+        }
+        bytes[0] = byte as u8;
+        ConnectionId::new(&bytes)
+    }
+
+    fn validate(&self, cid: &ConnectionId) -> Result<(), InvalidCid> {
+        match cid.len() == SHARDED_CID_LEN && cid[0] as usize % self.shards == self.shard {
+            true => Ok(()),
+            false => Err(InvalidCid),
+        }
+    }
+
+    fn cid_len(&self) -> usize {
+        SHARDED_CID_LEN
+    }
+
+    fn cid_lifetime(&self) -> Option<Duration> {
+        self.inner.cid_lifetime()
+    }
+}
+
+fn destination_cid(packet: &[u8]) -> Option<&[u8]> {
+    match *packet.first()? & 0x80 {
+        0 => packet.get(1..1 + SHARDED_CID_LEN),
+        _ => {
+            let len = *packet.get(5)? as usize;
+            packet.get(6..6 + len)
+        }
+    }
+}
+
+/// Picks the shard for an inbound packet. Connection IDs we issued carry their shard.
+/// `None` for any other ID, i.e. one a client picked for a new connection, which the
+/// [`Coordinator`] assigns.
+pub(super) fn route(packet: &[u8], shards: usize) -> Option<usize> {
+    if shards == 1 {
+        return Some(0);
+    }
+    match destination_cid(packet) {
+        Some(cid) if cid.len() == SHARDED_CID_LEN => Some(cid[0] as usize % shards),
+        _ => None,
+    }
+}
+
+/// Spreads new connections over the shards in turn. Every packet of a handshake must reach
+/// the shard that accepted it, so the ID the client picked is remembered until the client
+/// has certainly switched to one issued by that shard.
+#[derive(Debug, Default)]
+pub(super) struct Coordinator {
+    handshakes: HashMap<ConnectionId, usize>,
+    /// Remembered IDs, oldest first.
+    expiry: VecDeque<(Instant, ConnectionId)>,
+    next: usize,
+}
+
+impl Coordinator {
+    /// Shard for a packet [`route`] left to the coordinator.
+    pub(super) fn shard(&mut self, packet: &[u8], shards: usize, now: Instant) -> usize {
+        while let Some((_, cid)) = self.expiry.front().filter(|(at, _)| *at <= now) {
+            self.handshakes.remove(cid);
+            self.expiry.pop_front();
+        }
+        let known = destination_cid(packet)
+            .filter(|cid| cid.len() <= MAX_CID_LEN)
+            .and_then(|cid| self.handshakes.get(&ConnectionId::new(cid)));
+        if let Some(&shard) = known {
+            return shard;
+        }
+        self.next = (self.next + 1) % shards;
+        self.next
+    }
+
+    /// Routes further packets to `cid` to `shard`, which started a connection for it.
+    pub(super) fn started(&mut self, cid: ConnectionId, shard: usize, now: Instant) {
+        if self.handshakes.insert(cid, shard).is_none() {
+            self.expiry.push_back((now + HANDSHAKE_ROUTE_TTL, cid));
+        }
+    }
+}
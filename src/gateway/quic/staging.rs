@@ -0,0 +1,408 @@
+use crate::gateway::quic::error::QuicError;
+use crate::gateway::quic::shaper::{RateLimit, Throttle};
+use bytes::Bytes;
+use parking_lot::Mutex;
+use quinn_proto::{Connection, FinishError, ReadError, ReadableError, StreamId, VarInt, WriteError, Written};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Waker};
+
+/// Bytes a stream may stage for sending before writers wait.
+const SEND_STAGING_LIMIT: usize = 1024 * 1024;
+/// Bytes a stream may stage after reading before the runner stops pulling from quinn-proto,
+/// unless [`StreamStaging::set_recv_window`] changed it.
+const RECV_STAGING_LIMIT: usize = 256 * 1024;
+
+#[derive(Debug, Clone)]
+enum Failure {
+    Quic(QuicError),
+    Io(ErrorKind, String),
+}
+
+impl From<&Failure> for Error {
+    fn from(failure: &Failure) -> Self {
+        match failure {
+            Failure::Quic(e) => e.clone().into(),
+            Failure::Io(kind, msg) => Error::new(*kind, msg.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SendStaging {
+    chunks: VecDeque<Bytes>,
+    len: usize,
+    /// The stream is queued for the runner to flush.
+    queued: bool,
+    finish: bool,
+    finished: bool,
+    /// The `QuicStream` is gone, the runner forgets the stream once it is drained.
+    closed: bool,
+    failure: Option<Failure>,
+    throttle: Throttle,
+    waker: Option<Waker>,
+}
+
+impl SendStaging {
+    fn drained(&self) -> bool {
+        self.failure.is_some() || (self.chunks.is_empty() && self.finish == self.finished)
+    }
+
+    fn fail(&mut self, failure: Failure) {
+        self.chunks.clear();
+        self.len = 0;
+        self.failure = Some(failure);
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecvStaging {
+    chunks: VecDeque<Bytes>,
+    len: usize,
+    /// The runner stopped pulling because the staging was full.
+    stalled: bool,
+    /// Replaces [`RECV_STAGING_LIMIT`].
+    window: Option<usize>,
+    /// `Ok` once the stream is finished, `Err` once it is reset or failed. Reported after
+    /// the staged chunks are consumed.
+    end: Option<std::result::Result<(), Failure>>,
+    waker: Option<Waker>,
+}
+
+impl RecvStaging {
+    fn window(&self) -> usize {
+        self.window.unwrap_or(RECV_STAGING_LIMIT)
+    }
+}
+
+/// Outcome of [`StreamStaging::flush`].
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Flushed {
+    /// The stream can be forgotten.
+    pub(super) forget: bool,
+    /// Staged data was left behind because the budget ran out.
+    pub(super) throttled: bool,
+}
+
+/// Per-stream buffers between a [`QuicStream`](super::QuicStream) and the runner, so that
+/// stream I/O never takes the connection lock. The runner moves data between these and
+/// quinn-proto while it holds the lock anyway.
+#[derive(Debug, Default)]
+pub(super) struct StreamStaging {
+    send: Mutex<SendStaging>,
+    recv: Mutex<RecvStaging>,
+    /// quinn-proto forgot the stream, its ID may belong to another one by now.
+    abandoned: AtomicBool,
+}
+
+impl StreamStaging {
+    /// Stages chunks produced by `fill`, which is given the number of bytes that still fit
+    /// and returns how many it staged. Also resolves to whether the stream must be queued
+    /// for flushing.
+    pub(super) fn poll_send<T>(
+        &self,
+        cx: &mut Context<'_>,
+        fill: impl FnOnce(usize, &mut VecDeque<Bytes>) -> (T, usize),
+    ) -> Poll<Result<(T, bool)>> {
+        let mut send = self.send.lock();
+        if let Some(failure) = &send.failure {
+            return Poll::Ready(Err(failure.into()));
+        }
+        if send.finish {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "QUIC stream has been finished",
+            )));
+        }
+        let room = SEND_STAGING_LIMIT.saturating_sub(send.len);
+        if room == 0 {
+            send.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        // 限速的令牌耗尽时同样停车，由定时器唤醒；登记 waker 以便调整限速时立即唤醒
+        let room = match send.throttle.poll_quota(cx, room) {
+            Poll::Ready(room) => room,
+            Poll::Pending => {
+                send.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+        let (res, len) = fill(room, &mut send.chunks);
+        send.len += len;
+        send.throttle.consume(len);
+        let queue = !std::mem::replace(&mut send.queued, true);
+        Poll::Ready(Ok((res, queue)))
+    }
+
+    /// Stages whole chunks of `bufs` and splits the first one that does not fit, the same
+    /// way quinn-proto's `write_chunks` does.
+    pub(super) fn poll_send_chunks(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [Bytes],
+    ) -> Poll<Result<(Written, bool)>> {
+        self.poll_send(cx, |mut room, chunks| {
+            let mut written = Written::default();
+            for buf in bufs.iter_mut().filter(|buf| !buf.is_empty()) {
+                if room == 0 {
+                    break;
+                }
+                if buf.len() <= room {
+                    room -= buf.len();
+                    written.bytes += buf.len();
+                    written.chunks += 1;
+                    chunks.push_back(std::mem::take(buf));
+                } else {
+                    written.bytes += room;
+                    chunks.push_back(buf.split_to(room));
+                    room = 0;
+                }
+            }
+            let len = written.bytes;
+            (written, len)
+        })
+    }
+
+    /// Limits how fast writers may stage data, waking a writer parked on the old limit.
+    pub(super) fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        let mut send = self.send.lock();
+        send.throttle.set(limit);
+        let waker = send.waker.take();
+        drop(send);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Changes how many bytes the runner stages ahead of the reader. Returns whether the
+    /// stream must be queued for pumping.
+    pub(super) fn set_recv_window(&self, window: usize) -> bool {
+        let mut recv = self.recv.lock();
+        // 至少允许拉取一块，否则流永远读不到数据
+        recv.window = Some(window.max(1));
+        let resume = recv.stalled && recv.len < recv.window();
+        if resume {
+            recv.stalled = false;
+        }
+        resume
+    }
+
+    /// Requests a FIN after the staged data. Returns whether the stream must be queued
+    /// for flushing.
+    pub(super) fn finish(&self) -> Result<bool> {
+        let mut send = self.send.lock();
+        if let Some(failure) = &send.failure {
+            return Err(failure.into());
+        }
+        send.finish = true;
+        Ok(!std::mem::replace(&mut send.queued, true))
+    }
+
+    /// Hands out up to `bufs.len()` staged chunks totalling at most `max` bytes. Also
+    /// resolves to whether the runner must resume pulling from quinn-proto.
+    pub(super) fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        max: usize,
+        bufs: &mut [Bytes],
+    ) -> Poll<Result<(Option<usize>, bool)>> {
+        let mut recv = self.recv.lock();
+        if recv.chunks.is_empty() {
+            return match &recv.end {
+                Some(Ok(())) => Poll::Ready(Ok((None, false))),
+                Some(Err(failure)) => Poll::Ready(Err(failure.into())),
+                None => {
+                    recv.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            };
+        }
+
+        let mut count = 0;
+        let mut len = 0;
+        while count < bufs.len() && len < max {
+            let Some(chunk) = recv.chunks.front_mut() else {
+                break;
+            };
+            bufs[count] = if chunk.len() <= max - len {
+                recv.chunks.pop_front().unwrap()
+            } else {
+                chunk.split_to(max - len)
+            };
+            len += bufs[count].len();
+            count += 1;
+        }
+        recv.len -= len;
+
+        let resume = recv.stalled && recv.len <= recv.window() / 2;
+        if resume {
+            recv.stalled = false;
+        }
+        Poll::Ready(Ok((Some(count), resume)))
+    }
+
+    /// Moves staged data and a requested FIN into quinn-proto, at most `budget` bytes of data,
+    /// and takes what it moved off `budget`.
+    pub(super) fn flush(&self, conn: &mut Connection, id: StreamId, budget: &mut usize) -> Flushed {
+        let mut send = self.send.lock();
+        let mut stream = conn.send_stream(id);
+        while !send.chunks.is_empty() && send.failure.is_none() && *budget > 0 {
+            // 限速时只交出令牌允许的部分，其余留在暂存区
+            if send.chunks[0].len() > *budget {
+                let head = send.chunks[0].split_to(*budget);
+                send.chunks.push_front(head);
+            }
+            let mut fit = 0;
+            let count = send
+                .chunks
+                .iter()
+                .take_while(|chunk| {
+                    fit += chunk.len();
+                    fit <= *budget
+                })
+                .count();
+            // 部分写入后再写一次，让 quinn-proto 把流登记为阻塞，额度恢复时才会产生 Writable 事件
+            match stream.write_chunks(&mut send.chunks.make_contiguous()[..count]) {
+                Ok(written) => {
+                    send.chunks.drain(..written.chunks);
+                    send.len -= written.bytes;
+                    *budget -= written.bytes;
+                }
+                Err(WriteError::Blocked) => break,
+                Err(WriteError::Stopped(code)) => send.fail(Failure::Quic(QuicError::StreamStopped(code))),
+                Err(WriteError::ClosedStream) => send.fail(Failure::Quic(QuicError::StreamClosed)),
+            }
+        }
+        if send.chunks.is_empty() && send.finish && !send.finished && send.failure.is_none() {
+            match stream.finish() {
+                Ok(()) | Err(FinishError::ClosedStream) => send.finished = true,
+                Err(FinishError::Stopped(code)) => send.fail(Failure::Quic(QuicError::StreamStopped(code))),
+            }
+        }
+        // 因限速留下的数据由 runner 在令牌恢复后再次刷出，期间不必由写入方重新排队
+        let throttled = *budget == 0 && !send.chunks.is_empty() && send.failure.is_none();
+        send.queued = throttled;
+
+        let waker = match send.len <= SEND_STAGING_LIMIT / 2 || send.failure.is_some() {
+            true => send.waker.take(),
+            false => None,
+        };
+        let forget = send.closed && send.drained();
+        drop(send);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Flushed { forget, throttled }
+    }
+
+    /// Pulls readable data out of quinn-proto until the staging is full.
+    pub(super) fn pump(&self, conn: &mut Connection, id: StreamId) {
+        let mut recv = self.recv.lock();
+        if recv.end.is_some() {
+            return;
+        }
+        let before = recv.len;
+        let mut stream = conn.recv_stream(id);
+        let mut chunks = match stream.read(true) {
+            Ok(chunks) => chunks,
+            Err(ReadableError::ClosedStream) => {
+                recv.end = Some(Ok(()));
+                return;
+            }
+            Err(ReadableError::IllegalOrderedRead) => {
+                recv.end = Some(Err(Failure::Io(
+                    ErrorKind::InvalidData,
+                    "QUIC illegal ordered read".into(),
+                )));
+                return;
+            }
+        };
+        loop {
+            if recv.len >= recv.window() {
+                recv.stalled = true;
+                break;
+            }
+            match chunks.next(usize::MAX) {
+                Ok(Some(chunk)) => {
+                    recv.len += chunk.bytes.len();
+                    recv.chunks.push_back(chunk.bytes);
+                }
+                Ok(None) => {
+                    recv.end = Some(Ok(()));
+                    break;
+                }
+                Err(ReadError::Blocked) => break,
+                Err(ReadError::Reset(code)) => {
+                    recv.end = Some(Err(Failure::Quic(QuicError::StreamReset(code))));
+                    break;
+                }
+            }
+        }
+        // 扩展流控额度的帧由 runner 随后的 poll_transmit 一并发出
+        let _ = chunks.finalize();
+
+        let waker = match recv.len > before || recv.end.is_some() {
+            true => recv.waker.take(),
+            false => None,
+        };
+        drop(recv);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Marks the `QuicStream` as gone. Returns whether the stream can be forgotten.
+    pub(super) fn close(&self) -> bool {
+        let mut recv = self.recv.lock();
+        recv.chunks.clear();
+        recv.len = 0;
+        recv.end.get_or_insert(Ok(()));
+        drop(recv);
+        let mut send = self.send.lock();
+        send.closed = true;
+        send.drained()
+    }
+
+    /// Fails the sending direction the peer stopped, so that writes and [`Self::finish`]
+    /// report it even with nothing staged.
+    pub(super) fn stopped(&self, code: VarInt) {
+        let mut send = self.send.lock();
+        if send.failure.is_none() && !send.finished {
+            send.fail(Failure::Quic(QuicError::StreamStopped(code)));
+        }
+        let waker = send.waker.take();
+        drop(send);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Fails both directions of a stream quinn-proto no longer knows.
+    pub(super) fn abandon(&self, error: QuicError) {
+        self.abandoned.store(true, Ordering::Release);
+        self.fail(error);
+    }
+
+    pub(super) fn abandoned(&self) -> bool {
+        self.abandoned.load(Ordering::Acquire)
+    }
+
+    /// Fails both directions, e.g. when the connection is gone.
+    pub(super) fn fail(&self, error: QuicError) {
+        let failure = Failure::Quic(error);
+        let mut recv = self.recv.lock();
+        recv.end.get_or_insert(Err(failure.clone()));
+        let reader = recv.waker.take();
+        drop(recv);
+        let mut send = self.send.lock();
+        if send.failure.is_none() && !send.finished {
+            send.fail(failure);
+        }
+        let writer = send.waker.take();
+        drop(send);
+        for waker in reader.into_iter().chain(writer) {
+            waker.wake();
+        }
+    }
+}
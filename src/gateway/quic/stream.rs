@@ -1,151 +1,275 @@
-use std::io::{Error, ErrorKind};
+use std::future::poll_fn;
+use std::io::IoSlice;
+use std::mem::take;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::task::{Context, Poll};
-use quinn_proto::{FinishError, ReadError, ReadableError, StreamId, WriteError};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use bytes::{Buf, Bytes};
+use quinn_proto::{StreamId, Written};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
 use tracing::trace;
-use crate::gateway::quic::conn::{ConnCtrl, ConnState};
+use crate::gateway::quic::conn::ConnCtrl;
+use crate::gateway::quic::shaper::RateLimit;
+use crate::gateway::quic::staging::StreamStaging;
+use crate::gateway::quic::tls::PeerIdentity;
 use crate::gateway::quic::utils::{BufPool, SwitchedReceiver, SwitchedSender};
 
 pub(crate) type QuicStreamTx = SwitchedSender<QuicStream>;
 pub type QuicStreamRx = SwitchedReceiver<QuicStream>;
 
+const READ_CHUNKS: usize = 16;
+
 #[derive(Debug)]
 pub struct QuicStream {
     pub(crate) id: StreamId,
-    ctrl: ConnCtrl,
+    ctrl: Arc<ConnCtrl>,
+    staging: Arc<StreamStaging>,
     pool: BufPool,
+    /// Chunk handed out by `poll_fill_buf` and not yet consumed.
+    buffered: Bytes,
 }
 
 impl QuicStream {
-    pub(super) fn new(id: StreamId, ctrl: ConnCtrl) -> Self {
+    pub(super) fn new(id: StreamId, ctrl: Arc<ConnCtrl>, staging: Arc<StreamStaging>) -> Self {
         Self {
             id,
             ctrl,
+            staging,
             pool: BufPool::new(2048),
+            buffered: Bytes::new(),
+        }
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.ctrl.addr
+    }
+
+    /// Certificate the peer authenticated with, see [`QuicEndpoint::peer_identity`](super::QuicEndpoint::peer_identity).
+    pub fn peer_identity(&self) -> Option<Arc<PeerIdentity>> {
+        self.ctrl.peer_identity()
+    }
+
+    /// Application protocol negotiated with ALPN.
+    pub fn alpn(&self) -> Option<Vec<u8>> {
+        self.ctrl.alpn()
+    }
+
+    /// SNI the client asked for, on streams of incoming TLS connections.
+    pub fn server_name(&self) -> Option<String> {
+        self.ctrl.server_name()
+    }
+
+    /// Shapes writes to this stream with a token bucket, or lifts the limit with `None`.
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        self.staging.set_rate_limit(limit);
+    }
+
+    /// Bytes read out of quinn-proto ahead of this stream's reader, 256 KiB by default. The
+    /// peer may send this much plus the endpoint's
+    /// [stream receive window](super::QuicEndpoint::set_stream_receive_window) before it
+    /// blocks, 10 MiB unless lowered there. Lower both to bound what a stream takes.
+    pub fn set_receive_window(&self, window: usize) {
+        if self.staging.set_recv_window(window) {
+            self.ctrl.pump(self.id);
         }
     }
 }
 
+impl QuicStream {
+    /// Takes up to `bufs.len()` chunks totalling at most `max` bytes out of the receive
+    /// staging without copying them. Resolves to `None` at the end of the stream.
+    fn poll_chunks(
+        &mut self,
+        cx: &mut Context<'_>,
+        max: usize,
+        bufs: &mut [Bytes],
+    ) -> Poll<std::io::Result<Option<usize>>> {
+        if bufs.is_empty() || max == 0 {
+            return Poll::Ready(Ok(Some(0)));
+        }
+        if !self.buffered.is_empty() {
+            bufs[0] = self.buffered.split_to(max.min(self.buffered.len()));
+            return Poll::Ready(Ok(Some(1)));
+        }
+
+        let (count, resume) = ready!(self.staging.poll_recv(cx, max, bufs))?;
+        trace!("poll_chunks stream_id={} count={:?}", self.id, count);
+        if resume {
+            self.ctrl.pump(self.id);
+        }
+        Poll::Ready(Ok(count))
+    }
+
+    /// Reads the next chunk of at most `max` bytes without copying it. Returns `None` at
+    /// the end of the stream.
+    pub async fn read_chunk(&mut self, max: usize) -> std::io::Result<Option<Bytes>> {
+        poll_fn(|cx| {
+            let mut chunk = [Bytes::new()];
+            self.poll_chunks(cx, max, &mut chunk)
+                .map_ok(|n| n.map(|_| take(&mut chunk[0])))
+        })
+        .await
+    }
+
+    /// Fills `bufs` with as many chunks as are readable without copying them, returning
+    /// how many were filled, or `None` at the end of the stream.
+    pub async fn read_chunks(&mut self, bufs: &mut [Bytes]) -> std::io::Result<Option<usize>> {
+        poll_fn(|cx| self.poll_chunks(cx, usize::MAX, bufs)).await
+    }
+}
+
 impl AsyncRead for QuicStream {
     fn poll_read(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        let mut data = Vec::new();
-        let mut len = 0;
-        let mut state = self.ctrl.state.lock();
-        let ConnState {
-            ref mut conn,
-            ref mut readers,
-            ..
-        } = *state;
-        let mut stream = conn.recv_stream(self.id);
-        let mut chunks = match stream.read(true) {
-            Ok(chunks) => chunks,
-            Err(ReadableError::ClosedStream) => return Poll::Ready(Ok(())),
-            Err(ReadableError::IllegalOrderedRead) => {
-                return Poll::Ready(Err(Error::new(
-                    ErrorKind::InvalidData,
-                    "QUIC illegal ordered read",
-                )));
-            }
+        let mut chunks: [Bytes; READ_CHUNKS] = std::array::from_fn(|_| Bytes::new());
+        let count = match ready!(self.poll_chunks(cx, buf.remaining(), &mut chunks))? {
+            Some(count) => count,
+            None => return Poll::Ready(Ok(())),
         };
-
-        while len < buf.remaining() {
-            let chunk = match chunks.next(buf.remaining() - len) {
-                Ok(Some(chunk)) => chunk.bytes,
-                Ok(None) => return Poll::Ready(Ok(())), // ChunksState::Finished 流关闭
-                Err(ReadError::Blocked) => {
-                    if len == 0 {
-                        readers.insert(self.id, cx.waker().clone());
-                        return Poll::Pending;
-                    } else {
-                        break;
-                    }
-                }
-                Err(ReadError::Reset(err)) => {
-                    return Poll::Ready(Err(Error::new(
-                        ErrorKind::ConnectionReset,
-                        format!("QUIC stream reset: {}", err),
-                    )));
-                }
-            };
-            len += chunk.len();
-            data.push(chunk);
+        for chunk in &chunks[..count] {
+            buf.put_slice(chunk);
         }
+        Poll::Ready(Ok(()))
+    }
+}
 
-        let transmit = chunks.finalize().should_transmit();
+impl AsyncBufRead for QuicStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.buffered.is_empty() {
+            let mut chunk = [Bytes::new()];
+            if ready!(this.poll_chunks(cx, usize::MAX, &mut chunk))?.is_some() {
+                this.buffered = take(&mut chunk[0]);
+            }
+        }
+        Poll::Ready(Ok(&this.buffered))
+    }
 
-        drop(state);
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.buffered.advance(amt);
+    }
+}
 
-        trace!("poll_read stream_id={} len={}", self.id, len);
+impl QuicStream {
+    /// Stages `bufs` for the runner without copying them. A partially staged chunk is
+    /// advanced in place, so `bufs` holds exactly the unwritten data afterwards.
+    fn poll_write_chunks(
+        &mut self,
+        cx: &mut Context<'_>,
+        bufs: &mut [Bytes],
+    ) -> Poll<std::io::Result<Written>> {
+        let (written, queue) = ready!(self.staging.poll_send_chunks(cx, bufs))?;
+        trace!(
+            "poll_write_chunks stream_id={} chunks={} len={}",
+            self.id, written.chunks, written.bytes
+        );
+        if queue {
+            self.ctrl.flush(self.id);
+        }
+        Poll::Ready(Ok(written))
+    }
 
-        for data in data.drain(..) {
-            buf.put_slice(&data);
+    /// Writes as much of `bufs` as the stream accepts without copying it, returning how many
+    /// bytes that was. Fully written chunks are left empty, a partially written one is
+    /// advanced past the written bytes.
+    pub async fn write_chunks(&mut self, bufs: &mut [Bytes]) -> std::io::Result<usize> {
+        if bufs.iter().all(Bytes::is_empty) {
+            return Ok(0);
         }
-        if transmit {
-            self.ctrl.notify.notify_one();
+        let written = poll_fn(|cx| self.poll_write_chunks(cx, bufs)).await?;
+        Ok(written.bytes)
+    }
+
+    /// Writes all of `data` without copying it.
+    pub async fn write_chunk(&mut self, data: Bytes) -> std::io::Result<()> {
+        let mut chunk = [data];
+        while !chunk[0].is_empty() {
+            poll_fn(|cx| self.poll_write_chunks(cx, &mut chunk)).await?;
         }
-        Poll::Ready(Ok(()))
+        Ok(())
     }
 }
 
 impl AsyncWrite for QuicStream {
     fn poll_write(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
         trace!("poll_write stream_id={} len={}", self.id, buf.len());
-        let data = self.pool.buf(buf, (0, 0).into()).freeze();
-        let mut state = self.ctrl.state.lock();
-        let ConnState {
-            ref mut conn,
-            ref mut writers,
-            ..
-        } = *state;
-        match conn.send_stream(self.id).write_chunks(&mut [data]) {
-            Ok(written) => {
-                drop(state);
-                self.ctrl.notify.notify_one();
-                Poll::Ready(Ok(written.bytes))
-            }
-            Err(WriteError::Blocked) => {
-                writers.insert(self.id, cx.waker().clone());
-                Poll::Pending
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        let pool = &mut this.pool;
+        let (n, queue) = ready!(this.staging.poll_send(cx, |room, chunks| {
+            let n = buf.len().min(room);
+            chunks.push_back(pool.buf(&buf[..n], (0, 0).into()).freeze());
+            (n, n)
+        }))?;
+        if queue {
+            this.ctrl.flush(this.id);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        trace!("poll_write_vectored stream_id={} bufs={}", self.id, bufs.len());
+        if bufs.iter().all(|buf| buf.is_empty()) {
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        let pool = &mut this.pool;
+        // 只复制暂存区放得下的部分
+        let (n, queue) = ready!(this.staging.poll_send(cx, |mut room, chunks| {
+            let mut n = 0;
+            for buf in bufs.iter().filter(|buf| !buf.is_empty()) {
+                if room == 0 {
+                    break;
+                }
+                let len = buf.len().min(room);
+                chunks.push_back(pool.buf(&buf[..len], (0, 0).into()).freeze());
+                room -= len;
+                n += len;
             }
-            Err(_) => Poll::Ready(Err(Error::new(
-                ErrorKind::BrokenPipe,
-                "QUIC stream closed or stopped by peer",
-            ))),
+            (n, n)
+        }))?;
+        if queue {
+            this.ctrl.flush(this.id);
         }
+        Poll::Ready(Ok(n))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        self.ctrl.notify.notify_one();
+        self.ctrl.wake();
         Poll::Ready(Ok(()))
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        let finish = self.ctrl.state.lock().conn.send_stream(self.id).finish();
-        match finish {
-            Ok(()) => {
-                self.ctrl.notify.notify_one();
-                Poll::Ready(Ok(()))
-            }
-            Err(FinishError::ClosedStream) => Poll::Ready(Ok(())),
-            Err(FinishError::Stopped(error)) => Poll::Ready(Err(Error::new(
-                ErrorKind::BrokenPipe,
-                format!("QUIC stream has been stopped by peer: {:?}", error),
-            ))),
+        if self.staging.finish()? {
+            self.ctrl.flush(self.id);
         }
+        Poll::Ready(Ok(()))
     }
 }
 
 impl Drop for QuicStream {
     fn drop(&mut self) {
-        self.ctrl.close(self.id);
+        // 被拒绝的 0-RTT 流的 ID 可能已被新流复用
+        if !self.staging.abandoned() {
+            self.ctrl.close(self.id);
+        }
     }
 }
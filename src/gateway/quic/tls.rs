@@ -0,0 +1,427 @@
+use crate::gateway::quic::certs::{lookup, ServerCerts};
+use crate::gateway::quic::transport::TransportProfile;
+use derive_more::Debug;
+use quinn_proto::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn_proto::crypto::rustls::HandshakeData;
+use quinn_proto::{Connection, StreamId, VarInt};
+use rustls::client::{ClientSessionMemoryCache, ClientSessionStore, Resumption};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ServerSessionMemoryCache, StoresServerSessions, WebPkiClientVerifier};
+use rustls::{KeyLog, RootCertStore};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{error, trace};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// Error code streams denied by an [`Authorizer`] are stopped and reset with.
+pub const STREAM_UNAUTHORIZED: VarInt = VarInt::from_u32(0x403);
+
+/// Decides whether a stream the peer opened is handed out, given the peer's address and,
+/// over TLS, its identity. Runs before the stream reaches [`QuicOutputRx::stream`](super::QuicOutputRx).
+pub type Authorizer = Arc<dyn Fn(SocketAddr, Option<&PeerIdentity>) -> bool + Send + Sync>;
+
+/// Certificate chain and private key an endpoint authenticates with.
+#[derive(Debug)]
+pub struct Identity {
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl Clone for Identity {
+    fn clone(&self) -> Self {
+        Self {
+            cert_chain: self.cert_chain.clone(),
+            key: self.key.clone_key(),
+        }
+    }
+}
+
+impl Identity {
+    /// Generates a self-signed certificate valid for `names`, which may be DNS names or
+    /// IP addresses.
+    pub fn self_signed(names: impl Into<Vec<String>>) -> Result<Self> {
+        let certified = rcgen::generate_simple_self_signed(names)
+            .map_err(|e| Error::other(format!("Failed to generate certificate: {:?}", e)))?;
+        Ok(Self {
+            cert_chain: vec![certified.cert.der().clone()],
+            key: PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der()).into(),
+        })
+    }
+
+    /// Reads a PEM certificate chain, end-entity certificate first, and a PEM private key.
+    pub fn from_pem_files(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        let (cert, key) = (cert.as_ref(), key.as_ref());
+        let cert_chain = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(cert, e))?;
+        if cert_chain.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("No certificate in {:?}", cert),
+            ));
+        }
+        Ok(Self {
+            cert_chain,
+            key: PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?,
+        })
+    }
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> Error {
+    match e {
+        rustls::pki_types::pem::Error::Io(e) => Error::new(e.kind(), format!("Failed to read {:?}: {}", path, e)),
+        e => Error::new(ErrorKind::InvalidData, format!("Invalid PEM in {:?}: {:?}", path, e)),
+    }
+}
+
+/// Runs `authorizer` on a stream the peer opened, stopping and resetting it if denied.
+pub(super) fn authorize(
+    authorizer: &Authorizer,
+    conn: &mut Connection,
+    identity: Option<&PeerIdentity>,
+    id: StreamId,
+) -> bool {
+    if authorizer(conn.remote_address(), identity) {
+        return true;
+    }
+    trace!("Stream {} from {:?} denied", id, conn.remote_address());
+    let _ = conn.recv_stream(id).stop(STREAM_UNAUTHORIZED);
+    let _ = conn.send_stream(id).reset(STREAM_UNAUTHORIZED);
+    false
+}
+
+/// Client certificates servers ask for.
+#[derive(Debug, Clone, Default)]
+pub enum ClientAuth {
+    #[default]
+    None,
+    /// Clients may go without a certificate, but one they present must chain to these roots.
+    Optional(RootCertStore),
+    /// Clients without a certificate chaining to these roots are refused.
+    Required(RootCertStore),
+}
+
+/// Session resumption and 0-RTT.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Tickets clients keep per server name. rustls only resumes a ticket with the client
+    /// config that received it, i.e. on the endpoint it was issued to, since the last
+    /// [`set_tls`](super::QuicEndpoint::set_tls).
+    pub client_store: Arc<dyn ClientSessionStore>,
+    /// Sessions servers can resume. Share it between endpoints to resume their tickets on
+    /// each other. A ticket is taken out of the store when presented, so 0-RTT data sent
+    /// with it is accepted at most once; the capacity bounds how many resumable sessions
+    /// are outstanding.
+    pub server_store: Arc<dyn StoresServerSessions>,
+    /// Tickets a server issues per connection, i.e. how many 0-RTT reconnects one full
+    /// handshake buys.
+    pub tickets: usize,
+    /// Whether clients send and servers accept 0-RTT data. rustls additionally rejects
+    /// tickets whose age is off by more than a minute.
+    pub early_data: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            client_store: Arc::new(ClientSessionMemoryCache::new(256)),
+            server_store: ServerSessionMemoryCache::new(4096),
+            tickets: 2,
+            early_data: true,
+        }
+    }
+}
+
+/// TLS 1.3 handshakes in place of the default plaintext ones.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Presented to clients, and to servers asking for a client certificate. Without one
+    /// or [`Self::server_certs`], incoming connections are refused.
+    pub identity: Option<Identity>,
+    /// Picks the certificate presented to clients by SNI, in place of [`Self::identity`].
+    pub server_certs: Option<ServerCerts>,
+    /// Application protocols clients offer and servers accept, by preference. Empty leaves
+    /// ALPN out, but servers with protocols refuse clients offering none of them.
+    pub alpn: Vec<Vec<u8>>,
+    /// Limits applied to incoming connections by the server name their client asked for,
+    /// matched like [`ServerCerts`] names.
+    pub profiles: HashMap<String, TransportProfile>,
+    /// Trust anchors server certificates are verified against.
+    pub roots: RootCertStore,
+    /// Name verified against server certificates, the peer's IP address if `None`.
+    pub server_name: Option<String>,
+    pub client_auth: ClientAuth,
+    pub session: SessionConfig,
+    /// File TLS secrets of both sides are appended to in the NSS key log format, e.g. the
+    /// path in `SSLKEYLOGFILE`, letting Wireshark decrypt captured traffic.
+    pub key_log: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn new(identity: Option<Identity>, roots: RootCertStore) -> Self {
+        Self {
+            identity,
+            server_certs: None,
+            alpn: Vec::new(),
+            profiles: HashMap::new(),
+            roots,
+            server_name: None,
+            client_auth: ClientAuth::None,
+            session: SessionConfig::default(),
+            key_log: None,
+        }
+    }
+
+    pub(super) fn build(&self) -> Result<Tls> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let key_log = match &self.key_log {
+            Some(path) => Some(Arc::new(KeyLogFile::open(path)?) as Arc<dyn KeyLog>),
+            None => None,
+        };
+        let certs = match (&self.server_certs, &self.identity) {
+            (Some(certs), _) => Some(certs.clone()),
+            (None, Some(identity)) => {
+                let certs = ServerCerts::default();
+                certs.insert(None, identity)?;
+                Some(certs)
+            }
+            (None, None) => None,
+        };
+        let server = match certs {
+            Some(certs) => {
+                let verifier = match &self.client_auth {
+                    ClientAuth::None => WebPkiClientVerifier::no_client_auth(),
+                    ClientAuth::Optional(roots) => {
+                        WebPkiClientVerifier::builder_with_provider(Arc::new(roots.clone()), provider.clone())
+                            .allow_unauthenticated()
+                            .build()
+                            .map_err(tls_error)?
+                    }
+                    ClientAuth::Required(roots) => {
+                        WebPkiClientVerifier::builder_with_provider(Arc::new(roots.clone()), provider.clone())
+                            .build()
+                            .map_err(tls_error)?
+                    }
+                };
+                let mut config = rustls::ServerConfig::builder_with_provider(provider.clone())
+                    .with_protocol_versions(&[&rustls::version::TLS13])
+                    .map_err(tls_error)?
+                    .with_client_cert_verifier(verifier)
+                    .with_cert_resolver(Arc::new(certs));
+                config.alpn_protocols = self.alpn.clone();
+                config.session_storage = self.session.server_store.clone();
+                config.send_tls13_tickets = self.session.tickets;
+                // QUIC 只允许 0 或 u32::MAX
+                config.max_early_data_size = match self.session.early_data {
+                    true => u32::MAX,
+                    false => 0,
+                };
+                if let Some(key_log) = &key_log {
+                    config.key_log = key_log.clone();
+                }
+                Some(Arc::new(QuicServerConfig::try_from(config).map_err(tls_error)?))
+            }
+            None => None,
+        };
+
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(tls_error)?
+            .with_root_certificates(self.roots.clone());
+        let mut config = match &self.identity {
+            Some(identity) => config
+                .with_client_auth_cert(identity.cert_chain.clone(), identity.key.clone_key())
+                .map_err(tls_error)?,
+            None => config.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn.clone();
+        config.resumption = Resumption::store(self.session.client_store.clone());
+        config.enable_early_data = self.session.early_data;
+        if let Some(key_log) = key_log {
+            config.key_log = key_log;
+        }
+        let client = Arc::new(QuicClientConfig::try_from(config).map_err(tls_error)?);
+
+        Ok(Tls {
+            server,
+            client,
+            server_name: self.server_name.clone(),
+            profiles: Arc::new(
+                self.profiles
+                    .iter()
+                    .map(|(name, profile)| (name.to_ascii_lowercase(), *profile))
+                    .collect(),
+            ),
+        })
+    }
+}
+
+/// Appends secrets to a key log file, shared by the server and client configs.
+#[derive(Debug)]
+struct KeyLogFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl KeyLogFile {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::new(e.kind(), format!("Failed to open key log {:?}: {}", path, e)))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut line = String::with_capacity(label.len() + 2 * (client_random.len() + secret.len()) + 3);
+        line.push_str(label);
+        for bytes in [client_random, secret] {
+            line.push(' ');
+            for b in bytes {
+                let _ = write!(line, "{:02x}", b);
+            }
+        }
+        line.push('\n');
+        // 整行一次写入，多个连接并发记录时不会交错
+        if let Err(e) = self.file.lock().write_all(line.as_bytes()) {
+            error!("Failed to write key log {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+fn tls_error(e: impl std::fmt::Debug) -> Error {
+    Error::other(format!("Invalid TLS config: {:?}", e))
+}
+
+/// Handshake crypto built from a [`TlsConfig`].
+#[derive(Debug, Clone)]
+pub(super) struct Tls {
+    #[debug(skip)]
+    pub(super) server: Option<Arc<QuicServerConfig>>,
+    #[debug(skip)]
+    pub(super) client: Arc<QuicClientConfig>,
+    server_name: Option<String>,
+    pub(super) profiles: Arc<HashMap<String, TransportProfile>>,
+}
+
+impl Tls {
+    pub(super) fn server_name(&self, addr: SocketAddr) -> String {
+        self.server_name
+            .clone()
+            .unwrap_or_else(|| addr.ip().to_string())
+    }
+}
+
+/// `None` before [`Event::HandshakeDataReady`](quinn_proto::Event::HandshakeDataReady)
+/// and for plaintext handshakes.
+fn handshake_data(conn: &Connection) -> Option<Box<HandshakeData>> {
+    conn.crypto_session()
+        .handshake_data()?
+        .downcast::<HandshakeData>()
+        .ok()
+}
+
+/// SNI the client sent, known to servers once its ClientHello was processed.
+pub(super) fn server_name(conn: &Connection) -> Option<String> {
+    handshake_data(conn)?.server_name
+}
+
+/// Application protocol negotiated with ALPN.
+pub(super) fn alpn(conn: &Connection) -> Option<Vec<u8>> {
+    handshake_data(conn)?.protocol
+}
+
+/// Profile the SNI of an incoming connection picked.
+pub(super) fn profile(profiles: &HashMap<String, TransportProfile>, conn: &Connection) -> Option<TransportProfile> {
+    lookup(profiles, &server_name(conn)?).copied()
+}
+
+/// What became of the data a connection sent before its handshake completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EarlyData {
+    /// Nothing was sent early, e.g. there was no ticket for the server.
+    Unused,
+    Accepted,
+    /// The server refused it. Streams opened before the handshake fail and their data
+    /// has to be sent again on new streams.
+    Rejected,
+}
+
+impl EarlyData {
+    /// Meaningful once the handshake completed.
+    pub(super) fn of(conn: &Connection) -> Self {
+        // 服务端的 has_0rtt 表示已接受 0-RTT
+        match (conn.has_0rtt(), conn.side().is_server() || conn.accepted_0rtt()) {
+            (false, _) => Self::Unused,
+            (true, true) => Self::Accepted,
+            (true, false) => Self::Rejected,
+        }
+    }
+}
+
+/// Certificate a peer authenticated with.
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+    /// End-entity certificate first.
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    /// Distinguished name of the end-entity certificate, e.g. `CN=gw-17, O=Fleet`.
+    pub subject: String,
+    /// DNS names, IP addresses, URIs and e-mail addresses among its subject alternative names.
+    pub sans: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// `None` until the peer's certificate was verified, and for peers without one.
+    pub(super) fn of(conn: &Connection) -> Option<Self> {
+        let cert_chain = *conn
+            .crypto_session()
+            .peer_identity()?
+            .downcast::<Vec<CertificateDer<'static>>>()
+            .ok()?;
+        // 证书已通过校验，解析失败只会丢失名称
+        let (subject, sans) = match X509Certificate::from_der(cert_chain.first()?) {
+            Ok((_, cert)) => (cert.subject().to_string(), alt_names(&cert)),
+            Err(_) => (String::new(), Vec::new()),
+        };
+        Some(Self {
+            cert_chain,
+            subject,
+            sans,
+        })
+    }
+}
+
+fn alt_names(cert: &X509Certificate) -> Vec<String> {
+    let names = match cert.subject_alternative_name() {
+        Ok(Some(ext)) => &ext.value.general_names,
+        _ => return Vec::new(),
+    };
+    names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(s) | GeneralName::URI(s) | GeneralName::RFC822Name(s) => {
+                Some(s.to_string())
+            }
+            GeneralName::IPAddress(ip) => match ip.len() {
+                4 => <[u8; 4]>::try_from(*ip).ok().map(IpAddr::from),
+                16 => <[u8; 16]>::try_from(*ip).ok().map(IpAddr::from),
+                _ => None,
+            }
+            .map(|ip| ip.to_string()),
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,209 @@
+use crate::gateway::quic::shaper::RateLimit;
+use crate::gateway::quic::tls::Tls;
+use derive_more::Debug;
+use quinn_plaintext::{client_config, server_config};
+use quinn_proto::congestion::{BbrConfig, ControllerFactory, CubicConfig, NewRenoConfig};
+use quinn_proto::{ClientConfig, Connection, Dir, MtuDiscoveryConfig, ServerConfig, TransportConfig, VarInt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Datagram sizes a connection starts from, falls back to and probes up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtuConfig {
+    /// Size used from the handshake on, before any probing.
+    ///
+    /// Must fit the path. Black-hole detection only reacts to losses declared once later
+    /// packets are acknowledged, so if the datagram completing the handshake is already
+    /// too large and dropped, nothing is ever acknowledged and the connection times out
+    /// instead of falling back to `min`. Leave it at `min` and let PLPMTUD probe upwards
+    /// when the path is unknown.
+    pub initial: u16,
+    /// Size assumed to always get through; black-hole detection falls back to it.
+    pub min: u16,
+    /// Ceiling for PLPMTUD probes. `None` disables discovery.
+    pub upper_bound: Option<u16>,
+}
+
+impl Default for MtuConfig {
+    /// Safe on any IPv4/IPv6 path, probing up to what fits a 1500-byte Ethernet MTU.
+    fn default() -> Self {
+        Self {
+            initial: 1200,
+            min: 1200,
+            upper_bound: Some(1452),
+        }
+    }
+}
+
+impl MtuConfig {
+    /// Pins the MTU to `mtu` without probing. It is still capped by the peer's
+    /// `max_udp_payload_size`, 1472 with quinn's default [`EndpointConfig`](quinn_proto::EndpointConfig).
+    pub fn fixed(mtu: u16) -> Self {
+        Self {
+            initial: mtu,
+            min: mtu,
+            upper_bound: None,
+        }
+    }
+
+    pub fn apply(&self, config: &mut TransportConfig) {
+        config.initial_mtu(self.initial);
+        config.min_mtu(self.min);
+        config.mtu_discovery_config(self.upper_bound.map(|upper_bound| {
+            let mut discovery = MtuDiscoveryConfig::default();
+            discovery.upper_bound(upper_bound);
+            discovery
+        }));
+    }
+}
+
+/// Congestion controller of a connection.
+#[derive(Debug, Clone)]
+pub enum CongestionControl {
+    Bbr(BbrConfig),
+    Cubic(CubicConfig),
+    NewReno(NewRenoConfig),
+    /// Any other controller, e.g. a fixed rate on a dedicated link.
+    Custom(#[debug(skip)] Arc<dyn ControllerFactory + Send + Sync>),
+}
+
+impl Default for CongestionControl {
+    fn default() -> Self {
+        Self::Bbr(BbrConfig::default())
+    }
+}
+
+impl CongestionControl {
+    pub fn apply(&self, config: &mut TransportConfig) {
+        config.congestion_controller_factory(match self {
+            Self::Bbr(bbr) => Arc::new(bbr.clone()),
+            Self::Cubic(cubic) => Arc::new(cubic.clone()),
+            Self::NewReno(new_reno) => Arc::new(new_reno.clone()),
+            Self::Custom(factory) => factory.clone(),
+        });
+    }
+}
+
+/// Limits a server puts on a connection once the client's SNI picked them, in place of
+/// those of its transport config. Everything else is fixed before the SNI is known, and
+/// stream limits below the transport config's only bind once the client used up the
+/// credit the handshake granted. [`QuicEndpoint::set_conn_profile`](super::QuicEndpoint::set_conn_profile)
+/// changes them on a live connection the same way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransportProfile {
+    pub max_concurrent_bidi_streams: Option<u32>,
+    pub max_concurrent_uni_streams: Option<u32>,
+    /// Bytes the peer may send ahead across all streams.
+    pub receive_window: Option<u32>,
+    /// Bytes sent ahead of the peer's acknowledgements across all streams.
+    pub send_window: Option<u64>,
+    /// Egress rate limit of the connection.
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl TransportProfile {
+    /// Applies everything but the rate limit.
+    pub(super) fn apply(&self, conn: &mut Connection) {
+        if let Some(count) = self.max_concurrent_bidi_streams {
+            conn.set_max_concurrent_streams(Dir::Bi, count.into());
+        }
+        if let Some(count) = self.max_concurrent_uni_streams {
+            conn.set_max_concurrent_streams(Dir::Uni, count.into());
+        }
+        if let Some(window) = self.receive_window {
+            conn.set_receive_window(window.into());
+        }
+        if let Some(window) = self.send_window {
+            conn.set_send_window(window);
+        }
+    }
+}
+
+/// Settings the default server and client configs are built from.
+#[derive(Debug, Clone, Default)]
+pub(super) struct DefaultTransport {
+    pub(super) mtu: MtuConfig,
+    pub(super) congestion: CongestionControl,
+    /// Plaintext handshakes if `None`.
+    pub(super) tls: Option<Tls>,
+    /// Bytes the peer may send ahead on each stream. `None` keeps 10 MiB on the server
+    /// side and quinn's default on the client side.
+    pub(super) stream_receive_window: Option<u32>,
+}
+
+/// Configs replacing the endpoint's for connections with one peer.
+#[derive(Debug, Clone)]
+pub(super) struct PeerTransport {
+    pub(super) server: Option<Arc<ServerConfig>>,
+    pub(super) client: ClientConfig,
+}
+
+impl DefaultTransport {
+    fn server_transport(&self) -> TransportConfig {
+        let mut config = TransportConfig::default();
+
+        self.mtu.apply(&mut config);
+
+        let window = self.stream_receive_window.unwrap_or(10 * 1024 * 1024);
+        config.stream_receive_window(VarInt::from_u32(window));
+        config.receive_window(VarInt::from_u32(15 * 1024 * 1024));
+
+        config.max_concurrent_bidi_streams(VarInt::from_u32(1024));
+        config.max_concurrent_uni_streams(VarInt::from_u32(1024));
+
+        self.congestion.apply(&mut config);
+
+        config.keep_alive_interval(Some(Duration::from_secs(5)));
+        config.max_idle_timeout(Some(VarInt::from_u32(30_000).into()));
+
+        config
+    }
+
+    /// `None` if TLS is on without an identity to present.
+    pub(super) fn server_config(&self) -> Option<ServerConfig> {
+        let mut config = match &self.tls {
+            None => server_config(),
+            Some(tls) => ServerConfig::with_crypto(tls.server.clone()?),
+        };
+        config.transport = Arc::new(self.server_transport());
+        Some(config)
+    }
+
+    pub(super) fn client_config(&self) -> ClientConfig {
+        let mut transport = TransportConfig::default();
+        self.mtu.apply(&mut transport);
+        self.congestion.apply(&mut transport);
+        if let Some(window) = self.stream_receive_window {
+            transport.stream_receive_window(VarInt::from_u32(window));
+        }
+        let mut config = match &self.tls {
+            None => client_config(),
+            Some(tls) => ClientConfig::new(tls.client.clone()),
+        };
+        config.transport_config(Arc::new(transport));
+        config
+    }
+
+    /// Server name to connect to `addr` with. Ignored by plaintext handshakes.
+    pub(super) fn server_name(&self, addr: SocketAddr) -> String {
+        match &self.tls {
+            None => String::new(),
+            Some(tls) => tls.server_name(addr),
+        }
+    }
+
+    /// Same settings, but with `congestion` as the controller.
+    pub(super) fn peer(&self, congestion: CongestionControl) -> PeerTransport {
+        let transport = Self {
+            mtu: self.mtu,
+            congestion,
+            tls: self.tls.clone(),
+            stream_receive_window: self.stream_receive_window,
+        };
+        PeerTransport {
+            server: transport.server_config().map(Arc::new),
+            client: transport.client_config(),
+        }
+    }
+}
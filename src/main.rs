@@ -1,9 +1,12 @@
-mod gateway;
-
-// 假设你的库名为 my_quic_lib，且相关模块是公开的
-// 如果是在同一个 crate 内部测试，使用 crate::gateway::quic...
 #[allow(unused_imports)]
-use crate::gateway::quic::{QuicEndpoint, QuicOutputRx, QuicPacket, QuicPacketMargins, QuicStream};
+use qs::gateway::quic::{
+    BbrConfig, CongestionControl, ConnectionStats, Controller, ControllerFactory, CubicConfig,
+    InboxConfig, InboxOverflow, MtuConfig, NewRenoConfig, QuicEndpoint, QuicOutputRx, QuicPacket, QuicPacketMargins,
+    QuicStream, RateLimit, RttEstimator,
+};
+use bytes::{Bytes, BytesMut};
+use std::any::Any;
+use std::cell::Cell;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -21,10 +24,74 @@ const PAYLOAD_SIZE_1: usize = 8192 * 1024 * 1024;
 const TEST2: bool = false;
 const ITERATION_COUNT: usize = 100_000;
 
+// 服务端使用 read_chunk 零拷贝读取，false 时走 AsyncRead 拷贝路径作对比
+const ZERO_COPY_READ: bool = true;
+// 客户端使用 write_chunk 零拷贝写入，false 时走 AsyncWrite 拷贝路径作对比
+const ZERO_COPY_WRITE: bool = true;
+
 const TEST3: bool = true;
 const STREAM_COUNT: usize = 8;
 const PAYLOAD_SIZE_3: usize = 4096 * 1024 * 1024;
 
+const TEST4: bool = false;
+const IDLE_CONN_COUNT: usize = 10_000;
+const IDLE_SECS: u64 = 20;
+// 收件箱按容量预分配，默认 1024 个事件每连接约 220 KB，空闲连接用不上
+const IDLE_INBOX_CAPACITY: usize = 64;
+
+const TEST5: bool = false;
+const PAYLOAD_SIZE_5: usize = 4096 * 1024 * 1024;
+
+const TEST6: bool = false;
+// 每个算法每个流发送的数据量，流数量同测试 3
+const PAYLOAD_SIZE_6: usize = 256 * 1024 * 1024;
+// FixedRate 的目标速率 (bytes/s)
+const FIXED_RATE: u64 = 200 * 1024 * 1024;
+// 模拟链路的随机丢包率，内存直连本身不丢包，各算法的差别只在有丢包时显现
+const LOSS_RATE_6: f64 = 0.01;
+
+const TEST7: bool = false;
+// 客户端连接出口限速与其中一个流的限速 (bytes/s)，另一个流不限速
+const SHAPED_CONN_RATE: u64 = 64 * 1024 * 1024;
+const SHAPED_STREAM_RATE: u64 = 16 * 1024 * 1024;
+const SHAPING_SECS: u64 = 5;
+
+// 模拟链路 MTU，转发时丢弃超长的包（黑洞）。None 为内存直连，不限包长
+const LINK_MTU: Option<usize> = None;
+
+/// 模拟链路时从 min 起步，由 PLPMTUD 探测到链路能通过的最大值。
+/// initial 不能高于链路：完成握手的包被丢弃后不会有任何确认，黑洞检测无从触发
+fn mtu_config() -> MtuConfig {
+    match LINK_MTU {
+        None => MtuConfig::default(),
+        Some(_) => MtuConfig {
+            initial: 1200,
+            min: 1200,
+            upper_bound: Some(9000),
+        },
+    }
+}
+
+fn link_drops(pkt: &QuicPacket) -> bool {
+    LINK_MTU.is_some_and(|mtu| pkt.payload.len() > mtu)
+}
+
+/// 以 `rate` 的概率随机丢包，用 xorshift 生成伪随机数
+fn link_loses(rate: f64) -> bool {
+    thread_local!(static STATE: Cell<u64> = const { Cell::new(0x9E37_79B9_7F4A_7C15) });
+    if rate <= 0.0 {
+        return false;
+    }
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        ((x >> 11) as f64 / (1u64 << 53) as f64) < rate
+    })
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
     // console_subscriber::init();
@@ -45,7 +112,14 @@ async fn main() {
 
     if TEST1 { benchmark_throughput().await; }
     if TEST2 { benchmark_latency_pps().await;}
-    if TEST3 { benchmark_concurrent_throughput().await;}
+    if TEST3 { benchmark_concurrent_throughput(CongestionControl::default(), PAYLOAD_SIZE_3, 0.0).await;}
+    if TEST4 { benchmark_idle_connections().await;}
+    if TEST5 {
+        // 单线程端点是 !Send 的，放到独立线程的 current_thread 运行时里跑
+        std::thread::spawn(benchmark_local_throughput).join().unwrap();
+    }
+    if TEST6 { benchmark_congestion_controls().await;}
+    if TEST7 { benchmark_shaping().await;}
 }
 
 /// 测试 1: 最大单流吞吐量 (Bandwidth)
@@ -65,7 +139,9 @@ async fn benchmark_throughput() {
     // 为了测试方便，我们需要把 stream channel 分离出来。
     // 由于 QuicOutputRx 的字段是公有的，我们可以解构它。
     let (server, server_out) = QuicEndpoint::new(margins);
+    server.set_mtu(mtu_config());
     let (client, client_out) = QuicEndpoint::new(margins);
+    client.set_mtu(mtu_config());
 
     let server_packet_rx = server_out.packet;
     let mut server_new_streams = server_out.stream;
@@ -87,6 +163,9 @@ async fn benchmark_throughput() {
         // 使用 limit 限制每次连续处理的包数量，避免单次占用时间过长
         let mut count = 0;
         while let Some(pkt) = rx.recv().await {
+            if link_drops(&pkt) {
+                continue;
+            }
             // 如果 send 返回 Err，说明 Server 已经关闭/崩溃，我们应该退出而不是 Panic
             if s_arc
                 .send(CLIENT_ADDR.parse().unwrap(), pkt.payload)
@@ -112,6 +191,9 @@ async fn benchmark_throughput() {
         let mut rx = server_packet_rx;
         let mut count = 0;
         while let Some(pkt) = rx.recv().await {
+            if link_drops(&pkt) {
+                continue;
+            }
             if c_arc
                 .send(SERVER_ADDR.parse().unwrap(), pkt.payload)
                 .await
@@ -136,7 +218,14 @@ async fn benchmark_throughput() {
             let mut total_bytes = 0;
             let start = Instant::now();
             loop {
-                let n = stream.read(&mut buf).await.unwrap();
+                let n = if ZERO_COPY_READ {
+                    match stream.read_chunk(usize::MAX).await.unwrap() {
+                        Some(chunk) => chunk.len(),
+                        None => 0,
+                    }
+                } else {
+                    stream.read(&mut buf).await.unwrap()
+                };
                 trace!("Server: 接收数据... 已接收 {} bytes", total_bytes + n);
                 if n == 0 {
                     break;
@@ -178,12 +267,16 @@ async fn benchmark_throughput() {
         // 拿到流之后继续业务逻辑...
         let payload_size = PAYLOAD_SIZE_1; // 1GB
         let chunk_size = 64 * 1024;
-        let data = vec![1u8; chunk_size];
+        let data = Bytes::from(vec![1u8; chunk_size]);
 
         let mut sent = 0;
         while sent < payload_size {
             trace!("Client: 发送数据... {}/{}", sent, payload_size);
-            stream.write_all(&data).await.unwrap();
+            if ZERO_COPY_WRITE {
+                stream.write_chunk(data.clone()).await.unwrap();
+            } else {
+                stream.write_all(&data).await.unwrap();
+            }
             sent += chunk_size;
         }
         trace!("Client: 数据发送完毕，总计 {} bytes", sent);
@@ -211,7 +304,9 @@ async fn benchmark_latency_pps() {
         trailer: 0,
     };
     let (server, server_out) = QuicEndpoint::new(margins);
+    server.set_mtu(mtu_config());
     let (client, client_out) = QuicEndpoint::new(margins);
+    client.set_mtu(mtu_config());
 
     let server_packet_rx = server_out.packet;
     let mut server_new_streams = server_out.stream;
@@ -226,6 +321,9 @@ async fn benchmark_latency_pps() {
     tokio::spawn(async move {
         let mut rx = client_packet_rx;
         while let Some(pkt) = rx.recv().await {
+            if link_drops(&pkt) {
+                continue;
+            }
             trace!("Network: Client -> Server packet");
             s_arc
                 .send(CLIENT_ADDR.parse().unwrap(), pkt.payload)
@@ -236,6 +334,9 @@ async fn benchmark_latency_pps() {
     tokio::spawn(async move {
         let mut rx = server_packet_rx;
         while let Some(pkt) = rx.recv().await {
+            if link_drops(&pkt) {
+                continue;
+            }
             trace!("Network: Server -> Client packet");
             c_arc
                 .send(SERVER_ADDR.parse().unwrap(), pkt.payload)
@@ -306,12 +407,15 @@ async fn benchmark_latency_pps() {
     println!("PPS (Transactions/s): {:.2}", pps);
 }
 
-/// 测试 3: 多流并发吞吐量 (Concurrent Throughput)
-async fn benchmark_concurrent_throughput() {
-    info!("\n--- 测试 3: 多流并发吞吐量 (Concurrent Throughput) ---");
+/// 测试 3: 多流并发吞吐量 (Concurrent Throughput)，链路按 `loss` 随机丢包，返回聚合带宽 (MB/s) 与客户端连接统计
+async fn benchmark_concurrent_throughput(
+    congestion: CongestionControl,
+    size_per_stream: usize,
+    loss: f64,
+) -> (f64, Option<ConnectionStats>) {
+    info!("\n--- 测试 3: 多流并发吞吐量 (Concurrent Throughput), {:?} ---", congestion);
     // 参数配置
     let stream_count = STREAM_COUNT; // 并发流数量
-    let size_per_stream = PAYLOAD_SIZE_3; // 每个流发送 10MB
     let total_expected = stream_count as u64 * size_per_stream as u64;
 
     let margins = QuicPacketMargins {
@@ -320,7 +424,11 @@ async fn benchmark_concurrent_throughput() {
     };
     // 创建新的端点实例，环境是隔离的
     let (server, server_out) = QuicEndpoint::new(margins);
+    server.set_mtu(mtu_config());
+    server.set_congestion(congestion.clone());
     let (client, client_out) = QuicEndpoint::new(margins);
+    client.set_mtu(mtu_config());
+    client.set_congestion(congestion);
 
     let server_packet_rx = server_out.packet;
     let mut server_new_streams = server_out.stream;
@@ -341,6 +449,9 @@ async fn benchmark_concurrent_throughput() {
         // 使用 limit 限制每次连续处理的包数量，避免单次占用时间过长
         let mut count = 0;
         while let Some(pkt) = rx.recv().await {
+            if link_drops(&pkt) || link_loses(loss) {
+                continue;
+            }
             // 如果 send 返回 Err，说明 Server 已经关闭/崩溃，我们应该退出而不是 Panic
             if s_arc
                 .send(CLIENT_ADDR.parse().unwrap(), pkt.payload)
@@ -365,6 +476,9 @@ async fn benchmark_concurrent_throughput() {
         let mut rx = server_packet_rx;
         let mut count = 0;
         while let Some(pkt) = rx.recv().await {
+            if link_drops(&pkt) || link_loses(loss) {
+                continue;
+            }
             if c_arc
                 .send(SERVER_ADDR.parse().unwrap(), pkt.payload)
                 .await
@@ -393,7 +507,15 @@ async fn benchmark_concurrent_throughput() {
                 let mut buf = vec![0u8; 64 * 1024]; // 64KB buffer
                 let mut stream_received = 0;
                 loop {
-                    match stream.read(&mut buf).await {
+                    let res = if ZERO_COPY_READ {
+                        stream
+                            .read_chunk(usize::MAX)
+                            .await
+                            .map(|chunk| chunk.map_or(0, |chunk| chunk.len()))
+                    } else {
+                        stream.read(&mut buf).await
+                    };
+                    match res {
                         Ok(0) => break, // EOF
                         Ok(n) => stream_received += n,
                         Err(_) => break, // Error or Reset
@@ -434,7 +556,7 @@ async fn benchmark_concurrent_throughput() {
         let start = Instant::now();
         let mut join_set = tokio::task::JoinSet::new();
         // 预分配一个只读数据块，避免测试中频繁分配内存影响结果
-        let data = Arc::new(vec![1u8; 64 * 1024]);
+        let data = Bytes::from(vec![1u8; 64 * 1024]);
 
         for i in 0..stream_count {
             let ep = client.clone();
@@ -447,7 +569,11 @@ async fn benchmark_concurrent_throughput() {
                 let mut sent = 0;
                 while sent < size_per_stream {
                     // 发送数据
-                    stream.write_all(&data).await.expect("Write failed");
+                    if ZERO_COPY_WRITE {
+                        stream.write_chunk(data.clone()).await.expect("Write failed");
+                    } else {
+                        stream.write_all(&data).await.expect("Write failed");
+                    }
                     sent += data.len();
                 }
                 // 关闭写端，发送 FIN
@@ -462,11 +588,22 @@ async fn benchmark_concurrent_throughput() {
             res.unwrap();
         }
 
-        start.elapsed()
+        let stats = client.conn_stats(SERVER_ADDR.parse().unwrap());
+        if let Some(stats) = &stats {
+            info!(
+                "Client: MTU {}, PLPMTUD 探测 {} (丢失 {}), 黑洞 {}",
+                stats.path.current_mtu,
+                stats.path.sent_plpmtud_probes,
+                stats.path.lost_plpmtud_probes,
+                stats.path.black_holes_detected
+            );
+        }
+
+        (start.elapsed(), stats)
     });
 
     // 等待测试结束
-    let duration = client_handle.await.unwrap();
+    let (duration, stats) = client_handle.await.unwrap();
     let total_bytes = server_handle.await.unwrap();
 
     // 结果输出
@@ -487,4 +624,396 @@ async fn benchmark_concurrent_throughput() {
         "聚合带宽: {:.2} MB/s ({:.2} Gbps)",
         throughput_mb, throughput_gbps
     );
+    (throughput_mb, stats)
+}
+
+/// 固定速率拥塞控制：窗口取速率 × 最小 RTT，无视丢包，适合独占链路
+#[derive(Debug, Clone)]
+struct FixedRate {
+    bytes_per_sec: u64,
+    window: u64,
+}
+
+impl FixedRate {
+    const MIN_WINDOW: u64 = 16 * 1200;
+
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            window: Self::MIN_WINDOW,
+        }
+    }
+}
+
+impl ControllerFactory for FixedRate {
+    fn build(self: Arc<Self>, _now: Instant, _current_mtu: u16) -> Box<dyn Controller> {
+        Box::new(self.as_ref().clone())
+    }
+}
+
+impl Controller for FixedRate {
+    fn on_ack(&mut self, _now: Instant, _sent: Instant, _bytes: u64, _app_limited: bool, rtt: &RttEstimator) {
+        let window = self.bytes_per_sec as f64 * rtt.min().as_secs_f64();
+        self.window = (window as u64).max(Self::MIN_WINDOW);
+    }
+
+    fn on_congestion_event(&mut self, _now: Instant, _sent: Instant, _persistent: bool, _lost_bytes: u64) {}
+
+    fn on_mtu_update(&mut self, _new_mtu: u16) {}
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+
+    fn initial_window(&self) -> u64 {
+        Self::MIN_WINDOW
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+/// 测试 6: 各拥塞控制算法在同一有损模拟链路上的吞吐量与丢包
+async fn benchmark_congestion_controls() {
+    info!("\n--- 测试 6: 拥塞控制对比 (丢包率 {}%) ---", LOSS_RATE_6 * 100.0);
+    let controls = [
+        ("BBR", CongestionControl::Bbr(BbrConfig::default())),
+        ("Cubic", CongestionControl::Cubic(CubicConfig::default())),
+        ("NewReno", CongestionControl::NewReno(NewRenoConfig::default())),
+        ("FixedRate", CongestionControl::Custom(Arc::new(FixedRate::new(FIXED_RATE)))),
+    ];
+    let mut results = Vec::new();
+    for (name, congestion) in controls {
+        let (throughput, stats) = benchmark_concurrent_throughput(congestion, PAYLOAD_SIZE_6, LOSS_RATE_6).await;
+        results.push((name, throughput, stats));
+    }
+    info!("--- 测试结果 ---");
+    for (name, throughput, stats) in results {
+        let path = stats.map(|s| s.path).unwrap_or_default();
+        info!(
+            "{:<10} {:>8.2} MB/s, 丢包 {} ({} bytes / 发送 {} 包), 拥塞事件 {}",
+            name, throughput, path.lost_packets, path.lost_bytes, path.sent_packets, path.congestion_events
+        );
+    }
+}
+
+/// 测试 7: 连接与流限速，对比实测速率与配置
+async fn benchmark_shaping() {
+    info!(
+        "\n--- 测试 7: 限速 (连接 {} MB/s, 流 {} MB/s) ---",
+        SHAPED_CONN_RATE / 1024 / 1024,
+        SHAPED_STREAM_RATE / 1024 / 1024
+    );
+    let margins = QuicPacketMargins {
+        header: 0,
+        trailer: 0,
+    };
+    let (server, server_out) = QuicEndpoint::new(margins);
+    server.set_mtu(mtu_config());
+    let (client, client_out) = QuicEndpoint::new(margins);
+    client.set_mtu(mtu_config());
+    client.set_rate_limit(Some(RateLimit::per_second(SHAPED_CONN_RATE)));
+    let server = Arc::new(server);
+    let client = Arc::new(client);
+    let mut server_new_streams = server_out.stream;
+
+    let s_arc = server.clone();
+    tokio::spawn(async move {
+        let mut rx = client_out.packet;
+        while let Some(pkt) = rx.recv().await {
+            if link_drops(&pkt) {
+                continue;
+            }
+            if s_arc.send(CLIENT_ADDR.parse().unwrap(), pkt.payload).await.is_err() {
+                break;
+            }
+        }
+    });
+    let c_arc = client.clone();
+    tokio::spawn(async move {
+        let mut rx = server_out.packet;
+        while let Some(pkt) = rx.recv().await {
+            if link_drops(&pkt) {
+                continue;
+            }
+            if c_arc.send(SERVER_ADDR.parse().unwrap(), pkt.payload).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Server 端：首字节标明流是否限速，统计每个流从首包到 EOF 的速率；握手探测流没有首字节，跳过
+    let (rate_tx, mut rate_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(mut stream) = server_new_streams.recv().await {
+            let rate_tx = rate_tx.clone();
+            tokio::spawn(async move {
+                let mut tag = [0u8; 1];
+                if stream.read_exact(&mut tag).await.is_err() {
+                    return;
+                }
+                let start = Instant::now();
+                let mut total = 0;
+                while let Some(chunk) = stream.read_chunk(usize::MAX).await.unwrap() {
+                    total += chunk.len();
+                }
+                let _ = rate_tx.send((tag[0] == 1, total as f64 / start.elapsed().as_secs_f64()));
+            });
+        }
+    });
+
+    let server_addr: SocketAddr = SERVER_ADDR.parse().unwrap();
+    loop {
+        match client.open(server_addr, None).await {
+            Ok(mut s) => {
+                let _ = s.shutdown().await;
+                break;
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(5)).await,
+        }
+    }
+
+    let data = Bytes::from(vec![1u8; 64 * 1024]);
+    let mut join_set = tokio::task::JoinSet::new();
+    for limited in [true, false] {
+        let mut stream = client.open(server_addr, None).await.unwrap();
+        if limited {
+            stream.set_rate_limit(Some(RateLimit::per_second(SHAPED_STREAM_RATE)));
+        }
+        let data = data.clone();
+        join_set.spawn(async move {
+            stream.write_all(&[limited as u8]).await.unwrap();
+            let deadline = Instant::now() + Duration::from_secs(SHAPING_SECS);
+            while Instant::now() < deadline {
+                stream.write_chunk(data.clone()).await.unwrap();
+            }
+            stream.shutdown().await.unwrap();
+        });
+    }
+    join_set.join_all().await;
+
+    let mb = |rate: f64| rate / 1024.0 / 1024.0;
+    let mut total = 0.0;
+    for _ in 0..2 {
+        let (limited, rate) = rate_rx.recv().await.unwrap();
+        total += rate;
+        match limited {
+            true => info!("限速流: {:.2} MB/s (配置 {} MB/s)", mb(rate), mb(SHAPED_STREAM_RATE as f64)),
+            false => info!("不限速流: {:.2} MB/s", mb(rate)),
+        }
+    }
+    info!("连接合计: {:.2} MB/s (配置 {} MB/s)", mb(total), mb(SHAPED_CONN_RATE as f64));
+}
+
+/// 读取进程累计 CPU 时间和常驻内存 (Linux)
+fn process_usage() -> (Duration, usize) {
+    let stat = std::fs::read_to_string("/proc/self/stat").unwrap_or_default();
+    // comm 字段可能含空格，从右括号之后开始数
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .map_or(vec![], |(_, rest)| rest.split_whitespace().collect());
+    let ticks: u64 = fields.get(11..13).map_or(0, |f| {
+        f.iter().map(|v| v.parse::<u64>().unwrap_or(0)).sum()
+    });
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let rss_kb = status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap_or(0);
+    (Duration::from_millis(ticks * 10), rss_kb)
+}
+
+/// 测试 4: 大量空闲连接的建立开销、内存占用和空闲 CPU
+async fn benchmark_idle_connections() {
+    info!("\n--- 测试 4: 空闲连接 ({} 个) ---", IDLE_CONN_COUNT);
+
+    let margins = QuicPacketMargins {
+        header: 0,
+        trailer: 0,
+    };
+    let endpoint = || QuicEndpoint::new(margins);
+    let inbox = InboxConfig {
+        capacity: IDLE_INBOX_CAPACITY,
+        overflow: InboxOverflow::Drop,
+    };
+    let (server, server_out) = endpoint();
+    server.set_mtu(mtu_config());
+    server.set_inbox(inbox);
+    let (client, client_out) = endpoint();
+    client.set_mtu(mtu_config());
+    client.set_inbox(inbox);
+    let server = Arc::new(server);
+    let client = Arc::new(client);
+    let mut server_new_streams = server_out.stream;
+
+    // 每个连接用不同的服务端端口，客户端地址取 127.0.0.2 上的同一端口，双向一一对应
+    // 转发时复制一份，否则对端保存的小切片会钉住发送方整块的发送缓冲，内存远超真实网络
+    let peer = |addr: SocketAddr, ip: [u8; 4]| SocketAddr::from((ip, addr.port()));
+
+    let s_arc = server.clone();
+    tokio::spawn(async move {
+        let mut rx = client_out.packet;
+        while let Some(pkt) = rx.recv().await {
+            if link_drops(&pkt) {
+                continue;
+            }
+            let from = peer(pkt.addr, [127, 0, 0, 2]);
+            if s_arc.send(from, BytesMut::from(&pkt.payload[..])).await.is_err() {
+                break;
+            }
+        }
+    });
+    let c_arc = client.clone();
+    tokio::spawn(async move {
+        let mut rx = server_out.packet;
+        while let Some(pkt) = rx.recv().await {
+            if link_drops(&pkt) {
+                continue;
+            }
+            let from = peer(pkt.addr, [127, 0, 0, 1]);
+            if c_arc.send(from, BytesMut::from(&pkt.payload[..])).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Server 端：每个流读到 EOF 后回一个字节
+    tokio::spawn(async move {
+        while let Some(mut stream) = server_new_streams.recv().await {
+            tokio::spawn(async move {
+                let mut buf = Vec::new();
+                if stream.read_to_end(&mut buf).await.is_ok() {
+                    let _ = stream.write_all(b"y").await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+        }
+    });
+
+    let (cpu_before, rss_before) = process_usage();
+    let start = Instant::now();
+    let mut join_set = tokio::task::JoinSet::new();
+    let mut established = 0;
+    for i in 0..IDLE_CONN_COUNT {
+        // 控制并发握手数量
+        if join_set.len() >= 256 {
+            established += join_set.join_next().await.unwrap().unwrap() as usize;
+        }
+        let ep = client.clone();
+        join_set.spawn(async move {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 20000 + i as u16));
+            // 握手完成前打开流会失败，重试直到成功
+            let mut stream = loop {
+                match ep.open(addr, None).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(5)).await,
+                }
+            };
+            let mut buf = Vec::new();
+            stream.write_all(b"x").await.is_ok()
+                && stream.shutdown().await.is_ok()
+                && stream.read_to_end(&mut buf).await.is_ok()
+                && buf == b"y"
+        });
+    }
+    while let Some(res) = join_set.join_next().await {
+        established += res.unwrap() as usize;
+    }
+    let setup = start.elapsed();
+    let (cpu_setup, rss_setup) = process_usage();
+
+    tokio::time::sleep(Duration::from_secs(IDLE_SECS)).await;
+    let (cpu_idle, rss_idle) = process_usage();
+
+    info!("--- 测试结果 ---");
+    info!("建立连接: {}/{} 耗时 {:.2} s, CPU {:.2} s", established, IDLE_CONN_COUNT, setup.as_secs_f64(), (cpu_setup - cpu_before).as_secs_f64());
+    info!("常驻内存: {} MB -> {} MB (空闲后 {} MB)", rss_before / 1024, rss_setup / 1024, rss_idle / 1024);
+    info!("空闲 {} s CPU: {:.2} s ({:.1}%)", IDLE_SECS, (cpu_idle - cpu_setup).as_secs_f64(), (cpu_idle - cpu_setup).as_secs_f64() / IDLE_SECS as f64 * 100.0);
+}
+
+/// 测试 5: 单线程 (LocalSet) 端点的单流吞吐量
+fn benchmark_local_throughput() {
+    use qs::gateway::quic::local::LocalQuicEndpoint;
+    use std::rc::Rc;
+
+    info!("\n--- 测试 5: 单线程端点单流吞吐量 ---");
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = tokio::task::LocalSet::new();
+    local.block_on(&rt, async {
+        let margins = QuicPacketMargins {
+            header: 0,
+            trailer: 0,
+        };
+        let (server, server_out) = LocalQuicEndpoint::new(margins);
+        server.set_mtu(mtu_config());
+        let (client, client_out) = LocalQuicEndpoint::new(margins);
+        client.set_mtu(mtu_config());
+        let server = Rc::new(server);
+        let client = Rc::new(client);
+        let mut server_new_streams = server_out.stream;
+
+        let s_rc = server.clone();
+        tokio::task::spawn_local(async move {
+            let mut rx = client_out.packet;
+            while let Some(pkt) = rx.recv().await {
+                if link_drops(&pkt) {
+                    continue;
+                }
+                if s_rc.send(CLIENT_ADDR.parse().unwrap(), pkt.payload).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let c_rc = client.clone();
+        tokio::task::spawn_local(async move {
+            let mut rx = server_out.packet;
+            while let Some(pkt) = rx.recv().await {
+                if link_drops(&pkt) {
+                    continue;
+                }
+                if c_rc.send(SERVER_ADDR.parse().unwrap(), pkt.payload).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let server_handle = tokio::task::spawn_local(async move {
+            let mut stream = server_new_streams.recv().await.unwrap();
+            let start = Instant::now();
+            let mut total_bytes = 0;
+            while let Some(chunk) = stream.read_chunk(usize::MAX).await.unwrap() {
+                total_bytes += chunk.len();
+            }
+            (total_bytes, start.elapsed())
+        });
+
+        let mut stream = loop {
+            match client.open(SERVER_ADDR.parse().unwrap(), None).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let data = Bytes::from(vec![1u8; 64 * 1024]);
+        let mut sent = 0;
+        while sent < PAYLOAD_SIZE_5 {
+            stream.write_chunk(data.clone()).await.unwrap();
+            sent += data.len();
+        }
+        stream.shutdown().await.unwrap();
+
+        let (bytes, duration) = server_handle.await.unwrap();
+        let mb = bytes as f64 / 1024.0 / 1024.0;
+        let secs = duration.as_secs_f64();
+        info!("传输: {:.2} MB, 耗时: {:.4} s", mb, secs);
+        info!("速度: {:.2} MB/s ({:.2} Gbps)", mb / secs, (mb * 8.0) / 1024.0 / secs);
+    });
 }
@@ -0,0 +1,69 @@
+mod common;
+
+use bytes::Bytes;
+use common::{addr, handshake, wire, CLIENT, SERVER};
+use qs::gateway::quic::{QuicEndpoint, QuicError, QuicPacketMargins};
+use quinn_proto::{ApplicationClose, ConnectionError, VarInt};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+const MARGINS: QuicPacketMargins = QuicPacketMargins { header: 0, trailer: 0 };
+/// Concurrent bidirectional streams the default server config allows.
+const STREAMS: usize = 1024;
+
+/// Whether `e` carries the close a dropped endpoint sends its peers.
+fn destroyed(e: &std::io::Error) -> bool {
+    let close = ConnectionError::ApplicationClosed(ApplicationClose {
+        error_code: VarInt::from_u32(1),
+        reason: Bytes::from_static(b"QUIC connection destroyed"),
+    });
+    QuicError::of(e) == Some(&QuicError::ConnectionLost(close))
+}
+
+/// Reads, writes and opens waiting on a connection fail with the close code and reason
+/// the peer sent, as soon as it arrives.
+#[tokio::test(flavor = "multi_thread")]
+async fn waiters_see_peer_close() {
+    let (server, mut server_rx) = QuicEndpoint::new(MARGINS);
+    let (client, mut client_rx) = QuicEndpoint::new(MARGINS);
+    let (server, client) = (Arc::new(server), Arc::new(client));
+    // 客户端的包只持有服务端的弱引用，丢弃服务端即丢弃端点
+    let (weak, mut client_packets) = (Arc::downgrade(&server), std::mem::replace(&mut client_rx.packet, mpsc::channel(1).1));
+    tokio::spawn(async move {
+        while let Some(packet) = client_packets.recv().await {
+            if let Some(server) = Weak::upgrade(&weak) {
+                let _ = server.send(addr(CLIENT), packet.payload).await;
+            }
+        }
+    });
+    let server_packets = std::mem::replace(&mut server_rx.packet, mpsc::channel(1).1);
+    wire(server_packets, client.clone(), addr(SERVER));
+    handshake(&client).await;
+
+    let mut reading = client.open(addr(SERVER), None).await.unwrap();
+    reading.write_all(b"x").await.unwrap();
+    let mut writing = client.open(addr(SERVER), None).await.unwrap();
+    let mut streams = Vec::new();
+    while streams.len() < STREAMS - 2 {
+        streams.push(client.open(addr(SERVER), None).await.unwrap());
+    }
+    let read = tokio::spawn(async move { reading.read_to_end(&mut Vec::new()).await });
+    // 对端不读，写满流量控制窗口和暂存区后挂起
+    let write = tokio::spawn(async move { writing.write_all(&vec![0; 16 << 20]).await });
+    let opener = client.clone();
+    let open = tokio::spawn(async move { opener.open(addr(SERVER), None).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!read.is_finished() && !write.is_finished() && !open.is_finished());
+
+    drop(server_rx);
+    drop(server);
+    let e = timeout(Duration::from_secs(5), read).await.unwrap().unwrap().unwrap_err();
+    assert!(destroyed(&e), "{e:?}");
+    let e = timeout(Duration::from_secs(5), write).await.unwrap().unwrap().unwrap_err();
+    assert!(destroyed(&e), "{e:?}");
+    let e = timeout(Duration::from_secs(5), open).await.unwrap().unwrap().unwrap_err();
+    assert!(destroyed(&e), "{e:?}");
+}
@@ -0,0 +1,57 @@
+#![allow(dead_code)]
+
+use qs::gateway::quic::{QuicEndpoint, QuicOutputRx, QuicPacketMargins, QuicPacketRx};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+pub const SERVER: &str = "127.0.0.1:4433";
+pub const CLIENT: &str = "127.0.0.1:10000";
+
+pub fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+/// Feeds the packets `rx` yields into `to` as if they came from `from`.
+pub fn wire(mut rx: QuicPacketRx, to: Arc<QuicEndpoint>, from: SocketAddr) {
+    tokio::spawn(async move {
+        while let Some(packet) = rx.recv().await {
+            if to.send(from, packet.payload).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// A server and a client endpoint linked in memory, at [`SERVER`] and [`CLIENT`].
+pub fn pair() -> (Arc<QuicEndpoint>, QuicOutputRx, Arc<QuicEndpoint>, QuicOutputRx) {
+    let margins = QuicPacketMargins { header: 0, trailer: 0 };
+    let (server, server_rx) = QuicEndpoint::new(margins);
+    let (client, client_rx) = QuicEndpoint::new(margins);
+    link(server.into(), server_rx, client.into(), client_rx)
+}
+
+/// Wires the packet outputs of `server` and `client` to each other.
+pub fn link(
+    server: Arc<QuicEndpoint>,
+    mut server_rx: QuicOutputRx,
+    client: Arc<QuicEndpoint>,
+    mut client_rx: QuicOutputRx,
+) -> (Arc<QuicEndpoint>, QuicOutputRx, Arc<QuicEndpoint>, QuicOutputRx) {
+    let server_packets = std::mem::replace(&mut server_rx.packet, mpsc::channel(1).1);
+    let client_packets = std::mem::replace(&mut client_rx.packet, mpsc::channel(1).1);
+    wire(client_packets, server.clone(), addr(CLIENT));
+    wire(server_packets, client.clone(), addr(SERVER));
+    (server, server_rx, client, client_rx)
+}
+
+/// Waits until `client` completed the handshake with [`SERVER`].
+pub async fn handshake(client: &QuicEndpoint) {
+    // 查询数据报大小会顺带发起连接
+    client.max_datagram_size(addr(SERVER)).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), client.early_data(addr(SERVER)))
+        .await
+        .unwrap()
+        .unwrap();
+}
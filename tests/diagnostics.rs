@@ -0,0 +1,231 @@
+mod common;
+
+use common::{addr, handshake, link, pair, CLIENT, SERVER};
+use qs::gateway::quic::{CaptureConfig, Identity, QuicEndpoint, QuicPacketMargins, TlsConfig};
+use rustls::RootCertStore;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+const MARGINS: QuicPacketMargins = QuicPacketMargins { header: 0, trailer: 0 };
+const SERVER_NAME: &str = "gw.test";
+
+/// Fresh directory for the files of test `name`.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("qs-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// TLS configs of a server presenting `server` and a client trusting `trusted`.
+fn tls(server: &Identity, trusted: &Identity) -> (TlsConfig, TlsConfig) {
+    let mut roots = RootCertStore::empty();
+    roots.add(trusted.cert_chain[0].clone()).unwrap();
+    let mut client = TlsConfig::new(None, roots);
+    client.server_name = Some(SERVER_NAME.into());
+    (TlsConfig::new(Some(server.clone()), RootCertStore::empty()), client)
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn key_log_has_nss_lines() {
+    let dir = scratch("keylog");
+    let identity = Identity::self_signed(vec![SERVER_NAME.to_string()]).unwrap();
+    let (server_tls, mut client_tls) = tls(&identity, &identity);
+    client_tls.key_log = Some(dir.join("keys.log"));
+    let (server, server_rx) = QuicEndpoint::new(MARGINS);
+    let (client, client_rx) = QuicEndpoint::new(MARGINS);
+    server.set_tls(Some(server_tls)).unwrap();
+    client.set_tls(Some(client_tls)).unwrap();
+    let (_server, _server_rx, client, _client_rx) = link(server.into(), server_rx, client.into(), client_rx);
+    handshake(&client).await;
+
+    let log = fs::read_to_string(dir.join("keys.log")).unwrap();
+    let mut labels = Vec::new();
+    for line in log.lines() {
+        let fields: Vec<_> = line.split(' ').collect();
+        assert_eq!(fields.len(), 3, "{line}");
+        // client_random 32 字节，密钥长度随密码套件的哈希而定
+        assert!(is_hex(fields[1], 64), "{line}");
+        assert!(is_hex(fields[2], 64) || is_hex(fields[2], 96), "{line}");
+        labels.push(fields[0]);
+    }
+    for label in [
+        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+        "CLIENT_TRAFFIC_SECRET_0",
+        "SERVER_TRAFFIC_SECRET_0",
+    ] {
+        assert!(labels.contains(&label), "{label} missing from {labels:?}");
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn qlog_traces_connection() {
+    let dir = scratch("qlog");
+    let identity = Identity::self_signed(vec![SERVER_NAME.to_string()]).unwrap();
+    let untrusted = Identity::self_signed(vec![SERVER_NAME.to_string()]).unwrap();
+    let (server_tls, client_tls) = tls(&identity, &untrusted);
+    let (server, server_rx) = QuicEndpoint::new(MARGINS);
+    let (client, client_rx) = QuicEndpoint::new(MARGINS);
+    server.set_tls(Some(server_tls)).unwrap();
+    client.set_tls(Some(client_tls)).unwrap();
+    client.set_qlog(Some(dir.clone()));
+    let (_server, _server_rx, client, _client_rx) = link(server.into(), server_rx, client.into(), client_rx);
+
+    // 握手失败结束连接，qlog 随之写出；连接可能在查询前就已移除
+    client.max_datagram_size(addr(SERVER)).unwrap();
+    timeout(Duration::from_secs(5), client.early_data(addr(SERVER)))
+        .await
+        .unwrap()
+        .unwrap_err();
+
+    let files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert_eq!(files.len(), 1, "{files:?}");
+    let name = files[0].file_name().unwrap().to_str().unwrap();
+    assert!(name.starts_with("client-127.0.0.1_4433-") && name.ends_with(".sqlog"), "{name}");
+
+    let trace = fs::read_to_string(&files[0]).unwrap();
+    let records: Vec<_> = trace.lines().collect();
+    assert!(records.iter().all(|record| record.starts_with("\x1e{") && record.ends_with('}')));
+    assert!(records[0].contains("\"qlog_format\":\"JSON-SEQ\""), "{}", records[0]);
+    assert!(records[0].contains("\"vantage_point\":{\"type\":\"client\"}"), "{}", records[0]);
+    assert!(records.iter().any(|record| record.contains("\"name\":\"transport:packet_sent\"")));
+    assert!(records.iter().any(|record| record.contains("\"name\":\"transport:packet_received\"")));
+    assert!(records.last().unwrap().contains("\"name\":\"connectivity:connection_closed\""));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// One's complement sum of `bytes` as big-endian 16-bit words, folded.
+fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum: u32 = bytes
+        .chunks(2)
+        .map(|word| u32::from(word[0]) << 8 | u32::from(word.get(1).copied().unwrap_or(0)))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Splits a pcapng file into its blocks, checking that each one's leading and trailing
+/// lengths agree.
+fn blocks(file: &[u8]) -> Vec<(u32, &[u8])> {
+    let mut blocks = Vec::new();
+    let mut rest = file;
+    while !rest.is_empty() {
+        let len = u32_at(rest, 4) as usize;
+        assert!(len.is_multiple_of(4) && len <= rest.len(), "block of {len} bytes");
+        assert_eq!(u32_at(rest, len - 4) as usize, len);
+        blocks.push((u32_at(rest, 0), &rest[8..len - 4]));
+        rest = &rest[len..];
+    }
+    blocks
+}
+
+/// Source port, destination port and direction flags of the IPv4/UDP packet in an
+/// enhanced packet block, checking its headers.
+fn udp_packet(body: &[u8]) -> (u16, u16, u32) {
+    let len = u32_at(body, 12) as usize;
+    assert_eq!(u32_at(body, 16) as usize, len);
+    let packet = &body[20..20 + len];
+    let options = &body[20 + len.next_multiple_of(4)..];
+    // epb_flags 选项后以 opt_endofopt 结束
+    assert_eq!((u16_at(options, 0), u16_at(options, 2)), (2, 4));
+    assert_eq!(u32_at(options, 8), 0);
+
+    let (ip, udp) = packet.split_at(20);
+    assert_eq!(ip[0], 0x45);
+    assert_eq!(ip[9], 17);
+    assert_eq!(u16::from_be_bytes([ip[2], ip[3]]) as usize, len);
+    assert_eq!(checksum(ip), 0xffff);
+    assert_eq!(&ip[12..20], &[127, 0, 0, 1, 127, 0, 0, 1]);
+    assert_eq!(u16::from_be_bytes([udp[4], udp[5]]) as usize, udp.len());
+    let mut pseudo = ip[12..20].to_vec();
+    pseudo.extend_from_slice(&[0, 17]);
+    pseudo.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+    pseudo.extend_from_slice(udp);
+    assert_eq!(checksum(&pseudo), 0xffff);
+    (
+        u16::from_be_bytes([udp[0], udp[1]]),
+        u16::from_be_bytes([udp[2], udp[3]]),
+        u32_at(options, 4),
+    )
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn capture_writes_pcapng() {
+    let dir = scratch("capture");
+    let path = dir.join("client.pcapng");
+    let (_server, mut server_rx, client, _client_rx) = pair();
+    let config = CaptureConfig {
+        local: addr(CLIENT),
+        ..CaptureConfig::default()
+    };
+    client.start_capture(&path, config).unwrap();
+    handshake(&client).await;
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    stream.write_all(b"captured").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut peer = server_rx.stream.recv().await.unwrap();
+    peer.read_to_end(&mut Vec::new()).await.unwrap();
+    client.stop_capture();
+    assert!(!client.is_capturing());
+
+    let file = fs::read(&path).unwrap();
+    let blocks = blocks(&file);
+    let (kind, shb) = blocks[0];
+    assert_eq!(kind, 0x0a0d_0d0a);
+    assert_eq!(u32_at(shb, 0), 0x1a2b_3c4d);
+    assert_eq!((u16_at(shb, 4), u16_at(shb, 6)), (1, 0));
+    let (kind, idb) = blocks[1];
+    assert_eq!(kind, 1);
+    // LINKTYPE_RAW
+    assert_eq!(u16_at(idb, 0), 101);
+
+    let packets: Vec<_> = blocks[2..]
+        .iter()
+        .map(|&(kind, body)| {
+            assert_eq!(kind, 6);
+            udp_packet(body)
+        })
+        .collect();
+    assert!(packets.contains(&(10000, 4433, 2)), "{packets:?}");
+    assert!(packets.contains(&(4433, 10000, 1)), "{packets:?}");
+    assert!(packets.iter().all(|packet| matches!(packet, (10000, 4433, 2) | (4433, 10000, 1))));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn capture_stops_at_max_size() {
+    let dir = scratch("capture-limit");
+    let path = dir.join("client.pcapng");
+    let (_server, _server_rx, client, _client_rx) = pair();
+    let config = CaptureConfig {
+        local: addr(CLIENT),
+        max_size: 2048,
+    };
+    client.start_capture(&path, config).unwrap();
+    handshake(&client).await;
+
+    assert!(!client.is_capturing());
+    let file = fs::read(&path).unwrap();
+    assert!(file.len() <= 2048, "{}", file.len());
+    assert!(blocks(&file).len() > 2);
+    let _ = fs::remove_dir_all(&dir);
+}
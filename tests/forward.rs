@@ -0,0 +1,339 @@
+mod common;
+
+use common::{addr, handshake, pair, CLIENT, SERVER};
+use qs::gateway::forward::{
+    Gateway, ReverseAuthorizer, Socks5Server, StreamHeader, TargetAddr, TargetAuthorizer,
+};
+use qs::gateway::quic::QuicDatagram;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+/// A TCP echo service on an ephemeral loopback port.
+async fn tcp_echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut tcp, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = tcp.split();
+                tokio::io::copy(&mut r, &mut w).await.ok();
+            });
+        }
+    });
+    local
+}
+
+/// A UDP echo service on an ephemeral loopback port.
+async fn udp_echo() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+            socket.send_to(&buf[..n], from).await.ok();
+        }
+    });
+    local
+}
+
+async fn echoes(tcp: &mut TcpStream, data: &[u8]) {
+    tcp.write_all(data).await.unwrap();
+    let mut buf = vec![0u8; data.len()];
+    timeout(Duration::from_secs(5), tcp.read_exact(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(buf, data);
+}
+
+/// Runs a gateway on both ends, the server's allowing what `authorizer` allows.
+async fn gateways(authorizer: Option<ReverseAuthorizer>) -> Gateway {
+    let (server, server_rx, client, client_rx) = pair();
+    let mut server_gateway = Gateway::new(server);
+    server_gateway.set_reverse_authorizer(authorizer);
+    let client_gateway = Gateway::new(client.clone());
+    tokio::spawn(async move { server_gateway.run(server_rx.stream).await });
+    let gateway = client_gateway.clone();
+    tokio::spawn(async move { gateway.run(client_rx.stream).await });
+    handshake(&client).await;
+    client_gateway
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reverse_forward() {
+    let gateway = gateways(Some(Arc::new(|_, _, bind: SocketAddr| bind.ip().is_loopback()))).await;
+    let target = tcp_echo().await;
+
+    let err = gateway
+        .forward_remote(addr(SERVER), addr("0.0.0.0:0"), target)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+    let port = gateway
+        .forward_remote(addr(SERVER), addr("127.0.0.1:0"), target)
+        .await
+        .unwrap();
+    let bound = SocketAddr::from(([127, 0, 0, 1], port));
+    echoes(&mut TcpStream::connect(bound).await.unwrap(), b"hello").await;
+
+    gateway.cancel_remote(addr(SERVER), port).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(TcpStream::connect(bound).await.is_err());
+    let err = gateway.cancel_remote(addr(SERVER), port).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AddrNotAvailable);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reverse_forward_needs_authorizer() {
+    let gateway = gateways(None).await;
+    let err = gateway
+        .forward_remote(addr(SERVER), addr("127.0.0.1:0"), tcp_echo().await)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+    // 未曾注册过的服务端没有控制流可用
+    let err = gateway.cancel_remote(addr(CLIENT), 1).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}
+
+/// Lets peers reach loopback targets only.
+fn loopback() -> Option<TargetAuthorizer> {
+    Some(Arc::new(|_, _, target: &TargetAddr| match target {
+        TargetAddr::Ip(addr) => addr.ip().is_loopback(),
+        TargetAddr::Domain(host, _) => host == "localhost",
+    }))
+}
+
+/// Runs a SOCKS5 front-end on the client whose requests the server's gateway carries out,
+/// reaching the targets `authorizer` allows.
+async fn socks5(authorizer: Option<TargetAuthorizer>) -> SocketAddr {
+    let (server, server_rx, client, _client_rx) = pair();
+    let mut gateway = Gateway::new(server);
+    gateway.set_target_authorizer(authorizer);
+    tokio::spawn(async move { gateway.run(server_rx.stream).await });
+    handshake(&client).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local = listener.local_addr().unwrap();
+    let socks = Socks5Server::new(client, addr(SERVER), true);
+    tokio::spawn(async move { socks.run(listener).await });
+    local
+}
+
+/// Sends a SOCKS5 request with `cmd` and `target` and returns the reply code and address.
+async fn request(socks: SocketAddr, cmd: u8, target: &[u8]) -> (TcpStream, u8, SocketAddr) {
+    let mut tcp = TcpStream::connect(socks).await.unwrap();
+    tcp.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    tcp.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 0]);
+
+    let mut req = vec![5, cmd, 0];
+    req.extend_from_slice(target);
+    tcp.write_all(&req).await.unwrap();
+    let mut reply = [0u8; 10];
+    tcp.read_exact(&mut reply).await.unwrap();
+    let bind = SocketAddr::from((
+        [reply[4], reply[5], reply[6], reply[7]],
+        u16::from_be_bytes([reply[8], reply[9]]),
+    ));
+    (tcp, reply[1], bind)
+}
+
+fn ipv4(addr: SocketAddr) -> Vec<u8> {
+    let SocketAddr::V4(addr) = addr else { unreachable!() };
+    let mut buf = vec![1];
+    buf.extend_from_slice(&addr.ip().octets());
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn socks_connect() {
+    let socks = socks5(loopback()).await;
+    let target = tcp_echo().await;
+
+    let mut domain = vec![3, 9];
+    domain.extend_from_slice(b"localhost");
+    domain.extend_from_slice(&target.port().to_be_bytes());
+    let (mut tcp, reply, _) = request(socks, 1, &domain).await;
+    assert_eq!(reply, 0);
+    echoes(&mut tcp, b"ping").await;
+
+    // 对端拒绝连接时回复 REPLY_CONNECTION_REFUSED
+    let (_, reply, _) = request(socks, 1, &ipv4(addr("127.0.0.1:1"))).await;
+    assert_eq!(reply, 5);
+
+    // 请求中的版本号不对时直接断开
+    let mut tcp = TcpStream::connect(socks).await.unwrap();
+    tcp.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    tcp.read_exact(&mut method).await.unwrap();
+    tcp.write_all(&[4, 1, 0]).await.unwrap();
+    tcp.write_all(&ipv4(target)).await.unwrap();
+    let mut buf = [0u8; 1];
+    let n = timeout(Duration::from_secs(5), tcp.read(&mut buf)).await.unwrap();
+    assert!(matches!(n, Ok(0) | Err(_)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn socks_udp_associate() {
+    let socks = socks5(loopback()).await;
+    let target = udp_echo().await;
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_tcp, reply, relay) = request(socks, 3, &ipv4(client.local_addr().unwrap())).await;
+    assert_eq!(reply, 0);
+
+    let mut packet = vec![0, 0, 0];
+    packet.extend_from_slice(&ipv4(target));
+    packet.extend_from_slice(b"dgram");
+    // 只接受请求中声明的客户端地址，先到的陌生地址不会占用中继
+    stranger.send_to(&packet, relay).await.unwrap();
+    // 地址类型不明的包只会被丢弃，不影响之后的包
+    client.send_to(&[0, 0, 0, 9, 1, 2, 3], relay).await.unwrap();
+    client.send_to(&packet, relay).await.unwrap();
+
+    let mut buf = [0u8; 100];
+    let (n, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..n], &packet[..]);
+    let stray = timeout(Duration::from_millis(200), stranger.recv_from(&mut buf)).await;
+    assert!(stray.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn socks_needs_target_authorizer() {
+    let socks = socks5(None).await;
+    let (_, reply, _) = request(socks, 1, &ipv4(tcp_echo().await)).await;
+    assert_eq!(reply, 2);
+
+    // 不允许的目标的数据报被网关丢弃
+    let target = udp_echo().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_tcp, reply, relay) = request(socks, 3, &ipv4(client.local_addr().unwrap())).await;
+    assert_eq!(reply, 0);
+    let mut packet = vec![0, 0, 0];
+    packet.extend_from_slice(&ipv4(target));
+    packet.extend_from_slice(b"dgram");
+    client.send_to(&packet, relay).await.unwrap();
+    let mut buf = [0u8; 100];
+    let stray = timeout(Duration::from_millis(200), client.recv_from(&mut buf)).await;
+    assert!(stray.is_err());
+
+    // 授权函数拒绝的目标同样被拒
+    let socks = socks5(Some(Arc::new(|_, _, target: &TargetAddr| target.port() != 1))).await;
+    let (_, reply, _) = request(socks, 1, &ipv4(addr("127.0.0.1:1"))).await;
+    assert_eq!(reply, 2);
+}
+
+#[test]
+fn oversized_host_is_rejected() {
+    let target = TargetAddr::Domain("a".repeat(256), 80);
+    let err = StreamHeader::Connect(target).encode().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn udp_flows() {
+    let (server, server_rx, client, client_rx) = pair();
+    let mut server_gateway = Gateway::new(server);
+    server_gateway.set_target_authorizer(loopback());
+    // 服务端先于本地超时，本地之后的包须重新建流
+    server_gateway.set_flow_idle_timeout(Duration::from_millis(200));
+    let client_gateway = Gateway::new(client.clone());
+    let gateway = server_gateway.clone();
+    tokio::spawn(async move { gateway.run(server_rx.stream).await });
+    tokio::spawn(async move { server_gateway.run_datagrams(server_rx.datagram).await });
+    // 客户端收到的数据报经由 inject 转交，测试可借此冒充其他对端
+    let (inject, datagrams) = mpsc::channel(64);
+    let gateway = client_gateway.clone();
+    tokio::spawn(async move { gateway.run_datagrams(datagrams).await });
+    let relay = inject.clone();
+    let mut client_datagrams = client_rx.datagram;
+    tokio::spawn(async move {
+        while let Some(datagram) = client_datagrams.recv().await {
+            if relay.send(datagram).await.is_err() {
+                break;
+            }
+        }
+    });
+    handshake(&client).await;
+
+    let target = udp_echo().await;
+    let forwarder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let forwarded = forwarder.local_addr().unwrap();
+    let idle = Duration::from_secs(5);
+    tokio::spawn(async move {
+        client_gateway
+            .forward_udp(forwarder, addr(SERVER), TargetAddr::Ip(target), idle)
+            .await
+    });
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut buf = vec![0u8; 65536];
+    let mut roundtrip = async |payload: Vec<u8>| {
+        socket.send_to(&payload, forwarded).await.unwrap();
+        let (n, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], &payload[..]);
+    };
+
+    for i in 0..5u8 {
+        roundtrip(vec![i; 100]).await;
+    }
+    // 冒充另一对端回复第一条流，不应送达
+    let mut spoofed = vec![0x01, 0x80, 0, 0, 0];
+    spoofed.extend_from_slice(b"spoofed");
+    inject
+        .send(QuicDatagram::new(addr("127.0.0.1:10001"), spoofed.into()))
+        .await
+        .unwrap();
+    // 随后的回程包若先收到冒充的内容，比对即失败
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    roundtrip(vec![5; 100]).await;
+    // 超出数据报上限的包改走流
+    roundtrip(vec![7; 65000]).await;
+
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    roundtrip(vec![9; 10]).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn udp_flows_need_target_authorizer() {
+    let (server, server_rx, client, client_rx) = pair();
+    let server_gateway = Gateway::new(server);
+    let client_gateway = Gateway::new(client.clone());
+    tokio::spawn(async move { server_gateway.run(server_rx.stream).await });
+    let gateway = client_gateway.clone();
+    tokio::spawn(async move { gateway.run_datagrams(client_rx.datagram).await });
+    handshake(&client).await;
+
+    let target = udp_echo().await;
+    let forwarder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let forwarded = forwarder.local_addr().unwrap();
+    let idle = Duration::from_secs(5);
+    tokio::spawn(async move {
+        client_gateway
+            .forward_udp(forwarder, addr(SERVER), TargetAddr::Ip(target), idle)
+            .await
+    });
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(b"denied", forwarded).await.unwrap();
+    let mut buf = [0u8; 100];
+    let stray = timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await;
+    assert!(stray.is_err());
+}
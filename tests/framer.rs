@@ -0,0 +1,126 @@
+mod common;
+
+use bytes::BytesMut;
+use common::{addr, handshake, CLIENT, SERVER};
+use qs::gateway::quic::{
+    ChecksumFramer, LengthPrefixFramer, PacketFramer, QuicEndpoint, QuicOutputRx, QuicPacketRx, StackedFramer,
+    TunnelIdFramer,
+};
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+/// Like [`common::wire`], checking that every packet carries the margins `framer` fills.
+fn wire(mut rx: QuicPacketRx, to: Arc<QuicEndpoint>, from: &str, framer: Arc<dyn PacketFramer>) {
+    let from = addr(from);
+    tokio::spawn(async move {
+        while let Some(packet) = rx.recv().await {
+            framer.check(&packet.payload).unwrap();
+            if to.send(from, packet.payload).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// A server sharded over two endpoints and a client from [`QuicEndpoint::with_framer`], both
+/// framing with `framer` and linked in memory.
+fn framed(framer: Arc<dyn PacketFramer>) -> (Arc<QuicEndpoint>, QuicOutputRx, Arc<QuicEndpoint>) {
+    let (server, mut server_rx) = QuicEndpoint::sharded(2, framer.margins());
+    server.set_framer(framer.clone()).unwrap();
+    let (client, mut client_rx) = QuicEndpoint::with_framer(framer.clone());
+    let (server, client) = (Arc::new(server), Arc::new(client));
+    let server_packets = std::mem::replace(&mut server_rx.packet, mpsc::channel(1).1);
+    let client_packets = std::mem::replace(&mut client_rx.packet, mpsc::channel(1).1);
+    wire(client_packets, server.clone(), CLIENT, framer.clone());
+    wire(server_packets, client.clone(), SERVER, framer);
+    (server, server_rx, client)
+}
+
+async fn roundtrip(framer: Arc<dyn PacketFramer>) {
+    let (_server, mut server_rx, client) = framed(framer);
+    handshake(&client).await;
+
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    stream.write_all(b"framed").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut peer = timeout(Duration::from_secs(5), server_rx.stream.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut buf = Vec::new();
+    peer.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"framed");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn length_prefix() {
+    roundtrip(Arc::new(LengthPrefixFramer)).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tunnel_id() {
+    roundtrip(Arc::new(TunnelIdFramer { id: 0x5157_0001 })).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn checksum() {
+    roundtrip(Arc::new(ChecksumFramer)).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stacked() {
+    roundtrip(Arc::new(StackedFramer {
+        outer: Arc::new(TunnelIdFramer { id: 7 }),
+        inner: Arc::new(StackedFramer {
+            outer: Arc::new(LengthPrefixFramer),
+            inner: Arc::new(ChecksumFramer),
+        }),
+    }))
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mismatched_packets_are_rejected() {
+    let framer = Arc::new(StackedFramer {
+        outer: Arc::new(TunnelIdFramer { id: 7 }),
+        inner: Arc::new(ChecksumFramer),
+    });
+    let (server, _server_rx) = QuicEndpoint::with_framer(framer.clone());
+    let (client, mut client_rx) = QuicEndpoint::with_framer(framer);
+    // 查询数据报大小会顺带发起连接，取到首个 Initial 包
+    client.max_datagram_size(addr(SERVER)).unwrap();
+    let packet = client_rx.packet.recv().await.unwrap().payload;
+
+    // 校验和对不上
+    let mut corrupted = packet.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xff;
+    let e = server.send(addr(CLIENT), corrupted).await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+    // 隧道 ID 对不上
+    let mut foreign = packet.clone();
+    foreign[3] ^= 0xff;
+    let e = server.send(addr(CLIENT), foreign).await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+    // 短于边距的包
+    let e = server.send(addr(CLIENT), BytesMut::from(&packet[..6])).await;
+    assert_eq!(e.unwrap_err().kind(), ErrorKind::InvalidData);
+
+    server.send(addr(CLIENT), packet).await.unwrap();
+}
+
+#[tokio::test]
+async fn framer_must_fit_the_margins() {
+    let (endpoint, _rx) = QuicEndpoint::new((2, 0).into());
+    let e = endpoint.set_framer(Arc::new(ChecksumFramer)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    endpoint.set_framer(Arc::new(LengthPrefixFramer)).unwrap();
+    let e = endpoint.set_framer(Arc::new(LengthPrefixFramer)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+}
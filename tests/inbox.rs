@@ -0,0 +1,108 @@
+mod common;
+
+use bytes::BytesMut;
+use common::{addr, handshake, wire, CLIENT, SERVER};
+use qs::gateway::quic::local::LocalQuicEndpoint;
+use qs::gateway::quic::{InboxConfig, InboxOverflow, QuicEndpoint, QuicEndpointStats, QuicPacketMargins};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const MARGINS: QuicPacketMargins = QuicPacketMargins { header: 0, trailer: 0 };
+const SENT: u64 = 10;
+
+fn inbox(overflow: InboxOverflow) -> InboxConfig {
+    InboxConfig { capacity: 4, overflow }
+}
+
+/// Hands the server a packet of an established connection [`SENT`] times in a row. On a
+/// `current_thread` runtime its runner gets no chance to drain the inbox in between.
+async fn flood(overflow: InboxOverflow) -> QuicEndpointStats {
+    let (server, server_rx) = QuicEndpoint::new(MARGINS);
+    server.set_inbox(inbox(overflow));
+    let (client, mut client_rx) = QuicEndpoint::new(MARGINS);
+    let (server, client) = (Arc::new(server), Arc::new(client));
+    wire(server_rx.packet, client.clone(), addr(SERVER));
+    let (tx, mut packets) = mpsc::unbounded_channel();
+    let to = server.clone();
+    tokio::spawn(async move {
+        while let Some(packet) = client_rx.packet.recv().await {
+            let _ = tx.send(packet.payload.clone());
+            let _ = to.send(addr(CLIENT), packet.payload).await;
+        }
+    });
+    handshake(&client).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut last = packets.recv().await.unwrap();
+    while let Ok(packet) = packets.try_recv() {
+        last = packet;
+    }
+    for _ in 0..SENT {
+        server.send(addr(CLIENT), last.clone()).await.unwrap();
+    }
+    server.stats()
+}
+
+#[tokio::test]
+async fn inbox_drops() {
+    let stats = flood(InboxOverflow::Drop).await;
+    assert_eq!(stats.inbox_dropped, SENT - 4);
+    assert_eq!(stats.inbox_waits, 0);
+}
+
+#[tokio::test]
+async fn inbox_backpressure() {
+    let stats = flood(InboxOverflow::Backpressure).await;
+    assert_eq!(stats.inbox_dropped, 0);
+    assert!(stats.inbox_waits > 0);
+}
+
+/// Like [`flood`] for a [`LocalQuicEndpoint`].
+async fn local_flood(overflow: InboxOverflow) -> QuicEndpointStats {
+    let (server, mut server_rx) = LocalQuicEndpoint::new(MARGINS);
+    server.set_inbox(inbox(overflow));
+    let (client, mut client_rx) = LocalQuicEndpoint::new(MARGINS);
+    let (server, client) = (Rc::new(server), Rc::new(client));
+    let last: Rc<RefCell<Option<BytesMut>>> = Rc::default();
+    let (to, stash) = (server.clone(), last.clone());
+    tokio::task::spawn_local(async move {
+        while let Some(packet) = client_rx.packet.recv().await {
+            stash.replace(Some(packet.payload.clone()));
+            let _ = to.send(addr(CLIENT), packet.payload).await;
+        }
+    });
+    let to = client.clone();
+    tokio::task::spawn_local(async move {
+        while let Some(packet) = server_rx.packet.recv().await {
+            let _ = to.send(addr(SERVER), packet.payload).await;
+        }
+    });
+    client.max_datagram_size(addr(SERVER)).unwrap();
+    client.early_data(addr(SERVER)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let last = last.borrow().clone().unwrap();
+    for _ in 0..SENT {
+        server.send(addr(CLIENT), last.clone()).await.unwrap();
+    }
+    server.stats()
+}
+
+#[test]
+fn local_inbox() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = tokio::task::LocalSet::new();
+    let stats = local.block_on(&rt, local_flood(InboxOverflow::Drop));
+    assert_eq!(stats.inbox_dropped, SENT - 4);
+    assert_eq!(stats.inbox_waits, 0);
+
+    let stats = local.block_on(&rt, local_flood(InboxOverflow::Backpressure));
+    assert_eq!(stats.inbox_dropped, 0);
+    assert!(stats.inbox_waits > 0);
+}
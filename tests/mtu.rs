@@ -0,0 +1,138 @@
+mod common;
+
+use common::{addr, handshake, CLIENT, SERVER};
+use qs::gateway::quic::{MtuConfig, QuicEndpoint, QuicOutputRx, QuicPacketMargins, QuicStream};
+use quinn_proto::{Endpoint, EndpointConfig, TransportConfig};
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+const MARGINS: QuicPacketMargins = QuicPacketMargins { header: 0, trailer: 0 };
+
+/// Links `server` and `client` over a path dropping datagrams longer than `link` (a black hole).
+fn link(
+    server: QuicEndpoint,
+    server_rx: QuicOutputRx,
+    client: QuicEndpoint,
+    client_rx: QuicOutputRx,
+    link: Arc<AtomicUsize>,
+) -> (Arc<QuicEndpoint>, Arc<QuicEndpoint>) {
+    let (server, client) = (Arc::new(server), Arc::new(client));
+    let wires = [
+        (client_rx.packet, server.clone(), addr(CLIENT)),
+        (server_rx.packet, client.clone(), addr(SERVER)),
+    ];
+    for (mut rx, to, from) in wires {
+        let link = link.clone();
+        tokio::spawn(async move {
+            while let Some(packet) = rx.recv().await {
+                if packet.payload.len() > link.load(Ordering::Relaxed) {
+                    continue;
+                }
+                if to.send(from, packet.payload).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    // 服务端读完每个流后回写读到的字节数
+    let mut streams = server_rx.stream;
+    tokio::spawn(async move {
+        while let Some(mut stream) = streams.recv().await {
+            tokio::spawn(async move {
+                let mut data = Vec::new();
+                if stream.read_to_end(&mut data).await.is_ok() {
+                    let _ = stream.write_all(&(data.len() as u64).to_be_bytes()).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+        }
+    });
+    (server, client)
+}
+
+async fn finish(mut stream: QuicStream, written: u64) {
+    stream.shutdown().await.unwrap();
+    let mut len = [0u8; 8];
+    timeout(Duration::from_secs(20), stream.read_exact(&mut len))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(u64::from_be_bytes(len), written);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn discovery_stays_within_link() {
+    let mtu = MtuConfig {
+        initial: 1200,
+        min: 1200,
+        upper_bound: Some(1452),
+    };
+    let (server, server_rx) = QuicEndpoint::new(MARGINS);
+    let (client, client_rx) = QuicEndpoint::new(MARGINS);
+    server.set_mtu(mtu);
+    client.set_mtu(mtu);
+    let (_server, client) = link(server, server_rx, client, client_rx, Arc::new(AtomicUsize::new(1300)));
+    handshake(&client).await;
+
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    stream.write_all(&vec![7u8; 4 << 20]).await.unwrap();
+    finish(stream, 4 << 20).await;
+    let path = client.conn_stats(addr(SERVER)).unwrap().path;
+    assert!(path.current_mtu <= 1300, "{}", path.current_mtu);
+    assert_eq!(path.black_holes_detected, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn black_hole_falls_back_to_min() {
+    let limit = Arc::new(AtomicUsize::new(1500));
+    let (server, server_rx) = QuicEndpoint::new(MARGINS);
+    let (client, client_rx) = QuicEndpoint::new(MARGINS);
+    let (_server, client) = link(server, server_rx, client, client_rx, limit.clone());
+    handshake(&client).await;
+
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    stream.write_all(&vec![7u8; 1 << 20]).await.unwrap();
+    // 等 PLPMTUD 探测到 1500 字节链路的上限后再把链路收窄
+    timeout(Duration::from_secs(5), async {
+        while client.conn_stats(addr(SERVER)).unwrap().path.current_mtu <= 1300 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    limit.store(1300, Ordering::Relaxed);
+
+    stream.write_all(&vec![7u8; 1 << 20]).await.unwrap();
+    finish(stream, 2 << 20).await;
+    let path = client.conn_stats(addr(SERVER)).unwrap().path;
+    assert_eq!(path.current_mtu, 1200);
+    assert!(path.black_holes_detected >= 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn custom_configs_are_left_alone() {
+    let mut transport = TransportConfig::default();
+    MtuConfig::fixed(1300).apply(&mut transport);
+    let mut client_config = quinn_plaintext::client_config();
+    client_config.transport_config(Arc::new(transport));
+    let endpoint = Endpoint::new(Arc::new(EndpointConfig::default()), None, true, None);
+    let (client, client_rx) = QuicEndpoint::with_endpoint(endpoint, client_config, MARGINS);
+    client.set_mtu(MtuConfig::default());
+    let err = client.set_tls(None).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+
+    let (server, server_rx) = QuicEndpoint::new(MARGINS);
+    let (_server, client) = link(server, server_rx, client, client_rx, Arc::new(AtomicUsize::new(1500)));
+    handshake(&client).await;
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    stream.write_all(&vec![7u8; 1 << 20]).await.unwrap();
+    finish(stream, 1 << 20).await;
+    // 自定义配置关闭了探测，set_mtu 未覆盖它
+    let path = client.conn_stats(addr(SERVER)).unwrap().path;
+    assert_eq!(path.current_mtu, 1300);
+    assert_eq!(path.sent_plpmtud_probes, 0);
+}
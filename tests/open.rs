@@ -0,0 +1,98 @@
+mod common;
+
+use common::{addr, handshake, link, CLIENT, SERVER};
+use qs::gateway::quic::local::LocalQuicEndpoint;
+use qs::gateway::quic::{QuicEndpoint, QuicError, QuicPacketMargins};
+use std::io::ErrorKind;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+const MARGINS: QuicPacketMargins = QuicPacketMargins { header: 0, trailer: 0 };
+/// Concurrent bidirectional streams the default server config allows.
+const STREAMS: usize = 1024;
+/// Streams to close before quinn-proto gives credit back, which it does once an eighth of
+/// the limit is free.
+const FREED: usize = STREAMS / 8 + 1;
+const OPEN_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn exhausted(e: &std::io::Error) -> bool {
+    QuicError::of(e) == Some(&QuicError::StreamsExhausted) && e.kind() == ErrorKind::QuotaExceeded
+}
+
+/// Finishes the server's direction of a stream the client finished, so that it no longer
+/// counts against the limit.
+async fn close(mut client: impl AsyncRead + Unpin, mut server: impl AsyncWrite + Unpin) {
+    server.shutdown().await.unwrap();
+    client.read_to_end(&mut Vec::new()).await.unwrap();
+}
+
+/// An open without credit times out, and a later one gets the credit a closed stream
+/// gives back.
+#[tokio::test(flavor = "multi_thread")]
+async fn open_waits_for_credit() {
+    let (server, server_rx) = QuicEndpoint::new(MARGINS);
+    let (client, client_rx) = QuicEndpoint::new(MARGINS);
+    client.set_open_timeout(Some(OPEN_TIMEOUT));
+    let (_server, mut server_rx, client, _client_rx) = link(server.into(), server_rx, client.into(), client_rx);
+    handshake(&client).await;
+
+    let mut streams = Vec::new();
+    for _ in 0..STREAMS {
+        streams.push(client.open(addr(SERVER), None).await.unwrap());
+    }
+    let e = client.open(addr(SERVER), None).await.unwrap_err();
+    assert!(exhausted(&e), "{e:?}");
+
+    let opener = client.clone();
+    let waiting = tokio::spawn(async move { opener.open(addr(SERVER), None).await });
+    for mut stream in streams.drain(..FREED) {
+        stream.shutdown().await.unwrap();
+        let peer = server_rx.stream.recv().await.unwrap();
+        close(stream, peer).await;
+    }
+    waiting.await.unwrap().unwrap();
+}
+
+#[test]
+fn local_open_waits_for_credit() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    tokio::task::LocalSet::new().block_on(&rt, async {
+        let (server, mut server_rx) = LocalQuicEndpoint::new(MARGINS);
+        let (client, mut client_rx) = LocalQuicEndpoint::new(MARGINS);
+        client.set_open_timeout(Some(OPEN_TIMEOUT));
+        let (server, client) = (Rc::new(server), Rc::new(client));
+        let (to, mut server_packets) = (server.clone(), std::mem::replace(&mut server_rx.packet, mpsc::channel(1).1));
+        tokio::task::spawn_local(async move {
+            while let Some(packet) = client_rx.packet.recv().await {
+                let _ = to.send(addr(CLIENT), packet.payload).await;
+            }
+        });
+        let to = client.clone();
+        tokio::task::spawn_local(async move {
+            while let Some(packet) = server_packets.recv().await {
+                let _ = to.send(addr(SERVER), packet.payload).await;
+            }
+        });
+
+        let mut streams = Vec::new();
+        for _ in 0..STREAMS {
+            streams.push(client.open(addr(SERVER), None).await.unwrap());
+        }
+        let e = client.open(addr(SERVER), None).await.unwrap_err();
+        assert!(exhausted(&e), "{e:?}");
+
+        let opener = client.clone();
+        let waiting = tokio::task::spawn_local(async move { opener.open(addr(SERVER), None).await });
+        for mut stream in streams.drain(..FREED) {
+            stream.shutdown().await.unwrap();
+            let peer = server_rx.stream.recv().await.unwrap();
+            close(stream, peer).await;
+        }
+        waiting.await.unwrap().unwrap();
+    });
+}
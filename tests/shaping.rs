@@ -0,0 +1,141 @@
+mod common;
+
+use bytes::Bytes;
+use common::{addr, handshake, link, pair, CLIENT, SERVER};
+use qs::gateway::quic::local::LocalQuicEndpoint;
+use qs::gateway::quic::{QuicEndpoint, QuicPacketMargins, QuicStream, QuicStreamRx, RateLimit};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+const MARGINS: QuicPacketMargins = QuicPacketMargins { header: 0, trailer: 0 };
+
+/// Reads every stream the peer opens to the end, reporting how many bytes each carried.
+fn sink(mut streams: QuicStreamRx) -> mpsc::UnboundedReceiver<usize> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(mut stream) = streams.recv().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut len = 0;
+                while let Ok(Some(chunk)) = stream.read_chunk(usize::MAX).await {
+                    len += chunk.len();
+                }
+                let _ = tx.send(len);
+            });
+        }
+    });
+    rx
+}
+
+/// Seconds it takes to send `len` bytes on `stream` until the peer read them all.
+async fn send(stream: &mut QuicStream, done: &mut mpsc::UnboundedReceiver<usize>, len: usize) -> f64 {
+    let start = Instant::now();
+    stream.write_chunk(Bytes::from(vec![7u8; len])).await.unwrap();
+    stream.shutdown().await.unwrap();
+    assert_eq!(done.recv().await, Some(len));
+    start.elapsed().as_secs_f64()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_rate_limit() {
+    let (_server, server_rx, client, _client_rx) = pair();
+    handshake(&client).await;
+    let mut done = sink(server_rx.stream);
+
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    stream.set_rate_limit(Some(RateLimit::per_second(8 << 20)));
+    // 8 MiB 中扣除 1 MiB 突发，应在 0.9 秒左右发完
+    let secs = send(&mut stream, &mut done, 8 << 20).await;
+    assert!((0.7..1.5).contains(&secs), "{secs}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn conn_rate_limit() {
+    let (_server, server_rx, client, _client_rx) = pair();
+    handshake(&client).await;
+    let mut done = sink(server_rx.stream);
+    assert!(client.set_conn_rate_limit(addr("192.0.2.1:1"), None).is_err());
+
+    client
+        .set_conn_rate_limit(addr(SERVER), Some(RateLimit::per_second(8 << 20)))
+        .unwrap();
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    let secs = send(&mut stream, &mut done, 8 << 20).await;
+    assert!((0.7..1.5).contains(&secs), "{secs}");
+
+    client.set_conn_rate_limit(addr(SERVER), None).unwrap();
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    let secs = send(&mut stream, &mut done, 8 << 20).await;
+    assert!(secs < 0.5, "{secs}");
+}
+
+/// A receiver whose egress is limited far below the sender's rate must still acknowledge
+/// at full speed, or the sender would stall on its congestion window.
+#[tokio::test(flavor = "multi_thread")]
+async fn rate_limit_spares_acks() {
+    let (server, server_rx) = QuicEndpoint::new(MARGINS);
+    server.set_rate_limit(Some(RateLimit {
+        rate: 1024,
+        burst: 1024,
+    }));
+    let (client, client_rx) = QuicEndpoint::new(MARGINS);
+    let (_server, server_rx, client, _client_rx) =
+        link(server.into(), server_rx, client.into(), client_rx);
+    handshake(&client).await;
+    let mut done = sink(server_rx.stream);
+
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    let secs = send(&mut stream, &mut done, 16 << 20).await;
+    assert!(secs < 2.0, "{secs}");
+}
+
+#[test]
+fn local_rate_limit() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    tokio::task::LocalSet::new().block_on(&rt, async {
+        let (server, mut server_rx) = LocalQuicEndpoint::new(MARGINS);
+        let (client, mut client_rx) = LocalQuicEndpoint::new(MARGINS);
+        let (server, client) = (Rc::new(server), Rc::new(client));
+        let (to, mut server_packets) = (server.clone(), std::mem::replace(&mut server_rx.packet, mpsc::channel(1).1));
+        tokio::task::spawn_local(async move {
+            while let Some(packet) = client_rx.packet.recv().await {
+                let _ = to.send(addr(CLIENT), packet.payload).await;
+            }
+        });
+        let to = client.clone();
+        tokio::task::spawn_local(async move {
+            while let Some(packet) = server_packets.recv().await {
+                let _ = to.send(addr(SERVER), packet.payload).await;
+            }
+        });
+        let (tx, mut done) = mpsc::unbounded_channel();
+        tokio::task::spawn_local(async move {
+            while let Some(mut stream) = server_rx.stream.recv().await {
+                let tx = tx.clone();
+                tokio::task::spawn_local(async move {
+                    let mut data = Vec::new();
+                    if stream.read_to_end(&mut data).await.is_ok() {
+                        let _ = tx.send(data.len());
+                    }
+                });
+            }
+        });
+
+        let mut stream = client.open(addr(SERVER), None).await.unwrap();
+        client
+            .set_conn_rate_limit(addr(SERVER), Some(RateLimit::per_second(8 << 20)))
+            .unwrap();
+        let start = Instant::now();
+        stream.write_all(&vec![5u8; 8 << 20]).await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(done.recv().await, Some(8 << 20));
+        let secs = start.elapsed().as_secs_f64();
+        assert!((0.7..1.5).contains(&secs), "{secs}");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    });
+}
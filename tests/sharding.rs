@@ -0,0 +1,60 @@
+mod common;
+
+use bytes::Bytes;
+use common::{addr, handshake, wire, SERVER};
+use qs::gateway::quic::{QuicEndpoint, QuicPacketMargins};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const MARGINS: QuicPacketMargins = QuicPacketMargins { header: 0, trailer: 0 };
+const CLIENTS: u16 = 8;
+
+/// Clients connecting to a sharded server all complete their handshake and get served,
+/// whichever shard the coordinator assigned them to.
+#[tokio::test(flavor = "multi_thread")]
+async fn sharded_server() {
+    let (server, mut server_rx) = QuicEndpoint::sharded(4, MARGINS);
+    let server = Arc::new(server);
+    let mut clients = HashMap::new();
+    for i in 0..CLIENTS {
+        let (client, client_rx) = QuicEndpoint::new(MARGINS);
+        let client = Arc::new(client);
+        let local = SocketAddr::from(([127, 0, 0, 1], 10000 + i));
+        wire(client_rx.packet, server.clone(), local);
+        clients.insert(local, client);
+    }
+
+    // 服务端的出包按目的地址交给各个客户端
+    let peers = clients.clone();
+    tokio::spawn(async move {
+        while let Some(packet) = server_rx.packet.recv().await {
+            if let Some(client) = peers.get(&packet.addr) {
+                let _ = client.send(addr(SERVER), packet.payload).await;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(mut stream) = server_rx.stream.recv().await {
+            tokio::spawn(async move {
+                let mut data = Vec::new();
+                if stream.read_to_end(&mut data).await.is_ok() {
+                    let _ = stream.write_all(&data).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+        }
+    });
+
+    for (local, client) in &clients {
+        handshake(client).await;
+        let mut stream = client.open(addr(SERVER), None).await.unwrap();
+        let data = Bytes::from(local.to_string());
+        stream.write_chunk(data.clone()).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echo = Vec::new();
+        stream.read_to_end(&mut echo).await.unwrap();
+        assert_eq!(echo, data);
+    }
+}
@@ -0,0 +1,225 @@
+mod common;
+
+use bytes::Bytes;
+use common::{addr, handshake, link, pair, SERVER};
+use qs::gateway::quic::{QuicEndpoint, QuicError, QuicStream, STREAM_UNAUTHORIZED};
+use quinn_proto::VarInt;
+use std::io::{ErrorKind, IoSlice};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+/// Vectored writes take no more than the stream accepts and keep the order of the slices.
+#[tokio::test(flavor = "multi_thread")]
+async fn write_vectored() {
+    let (_server, mut server_rx, client, _client_rx) = pair();
+    handshake(&client).await;
+    let reader = tokio::spawn(async move {
+        let mut stream = server_rx.stream.recv().await.unwrap();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.unwrap();
+        data
+    });
+
+    let slices: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i; 512 << 10]).collect();
+    let expected = slices.concat();
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    let mut written = 0;
+    while written < expected.len() {
+        let mut skip = written;
+        let bufs: Vec<IoSlice> = slices
+            .iter()
+            .filter_map(|slice| {
+                let start = skip.min(slice.len());
+                skip -= start;
+                (start < slice.len()).then(|| IoSlice::new(&slice[start..]))
+            })
+            .collect();
+        let n = stream.write_vectored(&bufs).await.unwrap();
+        // 发送暂存区只有 1 MiB，一次写不完 3 MiB
+        assert!(n > 0 && n <= 1 << 20, "{n}");
+        written += n;
+    }
+    stream.shutdown().await.unwrap();
+    assert_eq!(reader.await.unwrap(), expected);
+}
+
+/// Fully written chunks are left empty and the count covers exactly what was taken.
+#[tokio::test(flavor = "multi_thread")]
+async fn write_chunks() {
+    let (_server, mut server_rx, client, _client_rx) = pair();
+    handshake(&client).await;
+    let reader = tokio::spawn(async move {
+        let mut stream = server_rx.stream.recv().await.unwrap();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.unwrap();
+        data.len()
+    });
+
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    let mut bufs: Vec<Bytes> = (0..3).map(|_| Bytes::from(vec![1u8; 768 << 10])).collect();
+    let mut total = 0;
+    while bufs.iter().any(|buf| !buf.is_empty()) {
+        let before: usize = bufs.iter().map(Bytes::len).sum();
+        let n = stream.write_chunks(&mut bufs).await.unwrap();
+        assert_eq!(before - bufs.iter().map(Bytes::len).sum::<usize>(), n);
+        total += n;
+    }
+    stream.shutdown().await.unwrap();
+    assert_eq!(reader.await.unwrap(), total);
+    assert_eq!(total, 3 * (768 << 10));
+}
+
+/// Payload of [`LEN`] bytes counting up, so that misplaced chunks show.
+const LEN: usize = 100_000;
+
+fn payload() -> Vec<u8> {
+    (0..LEN).map(|i| i as u8).collect()
+}
+
+/// Opens a stream that sends `data` and finishes, returning the server's end.
+async fn sent(data: &[u8]) -> (QuicStream, Arc<QuicEndpoint>, Arc<QuicEndpoint>) {
+    let (server, mut server_rx, client, _client_rx) = pair();
+    handshake(&client).await;
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    stream.write_all(data).await.unwrap();
+    stream.shutdown().await.unwrap();
+    (server_rx.stream.recv().await.unwrap(), server, client)
+}
+
+/// Chunks respect `max`, are never empty before the end and add up to what was sent. The
+/// end is reported again on every later read.
+#[tokio::test(flavor = "multi_thread")]
+async fn read_chunk_bounds() {
+    let data = payload();
+    let (mut stream, _server, _client) = sent(&data).await;
+
+    assert_eq!(stream.read_chunk(0).await.unwrap(), Some(Bytes::new()));
+    let mut read = Vec::new();
+    while let Some(chunk) = stream.read_chunk(1000).await.unwrap() {
+        assert!(!chunk.is_empty() && chunk.len() <= 1000, "{}", chunk.len());
+        read.extend_from_slice(&chunk);
+    }
+    assert_eq!(read, data);
+    assert_eq!(stream.read_chunk(1000).await.unwrap(), None);
+    assert_eq!(stream.read_chunks(&mut [Bytes::new(), Bytes::new()]).await.unwrap(), None);
+}
+
+/// Only the filled chunks are counted, and `read_chunks` reports the end like `read_chunk`.
+#[tokio::test(flavor = "multi_thread")]
+async fn read_chunks_fill() {
+    let data = payload();
+    let (mut stream, _server, _client) = sent(&data).await;
+
+    assert_eq!(stream.read_chunks(&mut []).await.unwrap(), Some(0));
+    let mut read = Vec::new();
+    let mut bufs: [Bytes; 4] = Default::default();
+    while let Some(n) = stream.read_chunks(&mut bufs).await.unwrap() {
+        assert!((1..=bufs.len()).contains(&n), "{n}");
+        for buf in &mut bufs[..n] {
+            assert!(!buf.is_empty());
+            read.extend_from_slice(&std::mem::take(buf));
+        }
+    }
+    assert_eq!(read, data);
+    assert_eq!(stream.read_chunk(usize::MAX).await.unwrap(), None);
+}
+
+/// What `AsyncBufRead` filled but did not consume is what chunk reads and `read` return
+/// first, in order and without loss.
+#[tokio::test(flavor = "multi_thread")]
+async fn buf_read_interleaved() {
+    let mut data = b"first line\n".to_vec();
+    data.extend(payload());
+    let (mut stream, _server, _client) = sent(&data).await;
+
+    let mut line = String::new();
+    stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "first line\n");
+    let mut read = line.into_bytes();
+    // 读一行后剩余的数据留在 AsyncBufRead 的缓冲中
+    let head = stream.fill_buf().await.unwrap().to_vec();
+    assert!(!head.is_empty());
+    stream.consume(1);
+    read.push(head[0]);
+    let chunk = stream.read_chunk(2).await.unwrap().unwrap();
+    assert_eq!(chunk.len(), 2);
+    read.extend_from_slice(&chunk);
+    let mut bufs: [Bytes; 2] = Default::default();
+    let n = stream.read_chunks(&mut bufs).await.unwrap().unwrap();
+    for buf in &bufs[..n] {
+        read.extend_from_slice(buf);
+    }
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte).await.unwrap();
+    read.push(byte[0]);
+    stream.read_to_end(&mut read).await.unwrap();
+    assert_eq!(read, data);
+    assert!(stream.fill_buf().await.unwrap().is_empty());
+}
+
+/// Once the peer stopped a stream, finishing it reports that like writing to it does,
+/// even with nothing left to send.
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_after_stop() {
+    let (_server, mut server_rx, client, _client_rx) = pair();
+    handshake(&client).await;
+
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    stream.write_all(b"x").await.unwrap();
+    let mut peer = server_rx.stream.recv().await.unwrap();
+    let mut byte = [0u8; 1];
+    peer.read_exact(&mut byte).await.unwrap();
+    // 丢弃流即向对端发送 STOP_SENDING
+    drop(peer);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let err = stream.shutdown().await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+}
+
+/// A stream the peer refuses is reset, which reads report as a typed [`QuicError`].
+#[tokio::test(flavor = "multi_thread")]
+async fn reset_by_peer() {
+    let (server, server_rx) = QuicEndpoint::new((0, 0).into());
+    server.set_authorizer(Some(Arc::new(|_, _| false)));
+    let (client, client_rx) = QuicEndpoint::new((0, 0).into());
+    let (_server, _server_rx, client, _client_rx) = link(server.into(), server_rx, client.into(), client_rx);
+    handshake(&client).await;
+
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    stream.write_all(b"x").await.unwrap();
+    let err = stream.read_to_end(&mut Vec::new()).await.unwrap_err();
+    assert_eq!(QuicError::of(&err), Some(&QuicError::StreamReset(STREAM_UNAUTHORIZED)), "{err:?}");
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+}
+
+/// Writes to a stream the peer dropped fail with its stop code as a typed [`QuicError`].
+#[tokio::test(flavor = "multi_thread")]
+async fn stopped_by_peer() {
+    let (_server, mut server_rx, client, _client_rx) = pair();
+    handshake(&client).await;
+
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    stream.write_all(b"x").await.unwrap();
+    drop(server_rx.stream.recv().await.unwrap());
+    let err = loop {
+        if let Err(err) = stream.write_all(&[0; 1024]).await {
+            break err;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(QuicError::of(&err), Some(&QuicError::StreamStopped(VarInt::from_u32(0))), "{err:?}");
+    assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+}
+
+/// Once the endpoint's packet output is gone, its connections end with
+/// [`QuicError::EndpointClosed`].
+#[tokio::test(flavor = "multi_thread")]
+async fn endpoint_closed() {
+    let (client, client_rx) = QuicEndpoint::new((0, 0).into());
+    drop(client_rx);
+    let err = client.open(addr(SERVER), None).await.unwrap_err();
+    assert_eq!(QuicError::of(&err), Some(&QuicError::EndpointClosed), "{err:?}");
+    assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+}
@@ -0,0 +1,364 @@
+mod common;
+
+use common::addr;
+use dashmap::DashMap;
+use qs::gateway::quic::{
+    ClientAuth, EarlyData, Identity, PeerIdentity, QuicEndpoint, QuicError, QuicOutputRx, QuicPacketMargins,
+    ServerCerts, QuicStreamRx, SessionConfig, TlsConfig, STREAM_UNAUTHORIZED,
+};
+use rustls::RootCertStore;
+use rustls::server::ServerSessionMemoryCache;
+use std::io::{ErrorKind, Result};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tokio::time::timeout;
+
+const MARGINS: QuicPacketMargins = QuicPacketMargins { header: 0, trailer: 0 };
+const SERVER_NAME: &str = "gw.test";
+const CLIENT: &str = "127.0.0.1:10000";
+
+/// Endpoints linked in memory, every packet going to the endpoint at its destination.
+#[derive(Clone, Default)]
+struct Net {
+    endpoints: Arc<DashMap<SocketAddr, Arc<QuicEndpoint>>>,
+    /// Whether the packets of an endpoint are let through.
+    gates: Arc<DashMap<SocketAddr, watch::Sender<bool>>>,
+}
+
+impl Net {
+    /// Puts an endpoint handshaking with `tls` on the net at `at`.
+    fn join(&self, at: &str, tls: TlsConfig) -> (Arc<QuicEndpoint>, QuicOutputRx) {
+        let (endpoint, rx) = QuicEndpoint::new(MARGINS);
+        endpoint.set_tls(Some(tls)).unwrap();
+        self.attach(at, endpoint, rx)
+    }
+
+    fn attach(&self, at: &str, endpoint: QuicEndpoint, mut rx: QuicOutputRx) -> (Arc<QuicEndpoint>, QuicOutputRx) {
+        let endpoint = Arc::new(endpoint);
+        let from = addr(at);
+        self.endpoints.insert(from, endpoint.clone());
+        let (gate, mut open) = watch::channel(true);
+        self.gates.insert(from, gate);
+
+        let mut packets = std::mem::replace(&mut rx.packet, mpsc::channel(1).1);
+        let endpoints = self.endpoints.clone();
+        tokio::spawn(async move {
+            while let Some(packet) = packets.recv().await {
+                if open.wait_for(|open| *open).await.is_err() {
+                    break;
+                }
+                let to = endpoints.get(&packet.addr).map(|to| to.clone());
+                if let Some(to) = to {
+                    let _ = to.send(from, packet.payload).await;
+                }
+            }
+        });
+        (endpoint, rx)
+    }
+
+    /// Holds back the packets of the endpoint at `at` until [`Self::release`]d.
+    fn hold(&self, at: &str) {
+        self.gates.get(&addr(at)).unwrap().send_replace(false);
+    }
+
+    fn release(&self, at: &str) {
+        self.gates.get(&addr(at)).unwrap().send_replace(true);
+    }
+}
+
+fn identity(names: &[&str]) -> Identity {
+    Identity::self_signed(names.iter().map(|name| name.to_string()).collect::<Vec<_>>()).unwrap()
+}
+
+fn roots(identities: &[&Identity]) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    for identity in identities {
+        roots.add(identity.cert_chain[0].clone()).unwrap();
+    }
+    roots
+}
+
+fn server_tls(identity: &Identity) -> TlsConfig {
+    TlsConfig::new(Some(identity.clone()), RootCertStore::empty())
+}
+
+/// Trusts `trusted` and asks servers for [`SERVER_NAME`].
+fn client_tls(trusted: &[&Identity]) -> TlsConfig {
+    let mut tls = TlsConfig::new(None, roots(trusted));
+    tls.server_name = Some(SERVER_NAME.into());
+    tls
+}
+
+/// Connects `client` to `server` and waits for the handshake.
+async fn connect(client: &QuicEndpoint, server: &str) -> Result<EarlyData> {
+    client.max_datagram_size(addr(server))?;
+    timeout(Duration::from_secs(5), client.early_data(addr(server)))
+        .await
+        .unwrap()
+}
+
+/// Whether `e` is from a failed handshake. A connection failing before
+/// [`QuicEndpoint::early_data`] looks it up is already gone.
+fn refused(e: &std::io::Error) -> bool {
+    matches!(QuicError::of(e), Some(QuicError::ConnectionLost(_))) || e.kind() == ErrorKind::NotFound
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn handshake_verifies_server() {
+    let server = identity(&[SERVER_NAME]);
+    let net = Net::default();
+    let _server = net.join("127.0.0.1:4433", server_tls(&server));
+
+    let (client, _client_rx) = net.join(CLIENT, client_tls(&[&server]));
+    assert_eq!(connect(&client, "127.0.0.1:4433").await.unwrap(), EarlyData::Unused);
+    let peer = client.peer_identity(addr("127.0.0.1:4433")).unwrap();
+    assert_eq!(peer.sans, [SERVER_NAME]);
+
+    // 不受信任的证书导致握手失败
+    let (client, _client_rx) = net.join("127.0.0.1:10001", client_tls(&[&identity(&[SERVER_NAME])]));
+    let e = connect(&client, "127.0.0.1:4433").await.unwrap_err();
+    assert!(refused(&e), "{e:?}");
+}
+
+/// Opens a stream to `server`, sends `data` on it and waits for the server to finish the
+/// stream.
+async fn send(client: &QuicEndpoint, server: &str, data: &[u8]) -> Result<()> {
+    let mut stream = client.open(addr(server), None).await?;
+    stream.write_all(data).await?;
+    stream.shutdown().await?;
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut Vec::new()))
+        .await
+        .unwrap()?;
+    Ok(())
+}
+
+/// Like [`send`], sending `data` before the handshake can complete,
+/// i.e. as 0-RTT data if the session is resumed.
+async fn send_early(net: &Net, client: &QuicEndpoint, server: &str, data: &[u8]) -> Result<()> {
+    net.hold(CLIENT);
+    let stream = async {
+        let mut stream = client.open(addr(server), None).await?;
+        stream.write_all(data).await?;
+        stream.shutdown().await?;
+        Ok::<_, std::io::Error>(stream)
+    }
+    .await;
+    net.release(CLIENT);
+    timeout(Duration::from_secs(5), stream?.read_to_end(&mut Vec::new()))
+        .await
+        .unwrap()?;
+    Ok(())
+}
+
+async fn receive(rx: &mut QuicStreamRx) -> Vec<u8> {
+    let mut stream = timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    stream.shutdown().await.unwrap();
+    buf
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resumption_and_early_data() {
+    let server = identity(&[SERVER_NAME]);
+    let net = Net::default();
+    // A 与 B 共享会话存储，能恢复彼此签发的票据；C 不能
+    let shared = SessionConfig {
+        server_store: ServerSessionMemoryCache::new(16),
+        ..SessionConfig::default()
+    };
+    let mut tls = server_tls(&server);
+    tls.session = shared.clone();
+    let _a = net.join("127.0.0.1:4433", tls.clone());
+    let (_b, mut b_rx) = net.join("127.0.0.1:4434", tls);
+    let (_c, mut c_rx) = net.join("127.0.0.1:4435", server_tls(&server));
+
+    let (client, _client_rx) = net.join(CLIENT, client_tls(&[&server]));
+    assert_eq!(connect(&client, "127.0.0.1:4433").await.unwrap(), EarlyData::Unused);
+    // 票据在握手后才送达
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let (sent, received) = tokio::join!(send_early(&net, &client, "127.0.0.1:4434", b"early"), receive(&mut b_rx.stream));
+    sent.unwrap();
+    assert_eq!(received, b"early");
+    assert_eq!(client.early_data(addr("127.0.0.1:4434")).await.unwrap(), EarlyData::Accepted);
+
+    // C 不认识票据，0-RTT 数据须在新流上重发
+    let e = send_early(&net, &client, "127.0.0.1:4435", b"early").await.unwrap_err();
+    assert_eq!(QuicError::of(&e), Some(&QuicError::EarlyDataRejected), "{e:?}");
+    assert_eq!(client.early_data(addr("127.0.0.1:4435")).await.unwrap(), EarlyData::Rejected);
+    let (sent, received) = tokio::join!(send_early(&net, &client, "127.0.0.1:4435", b"again"), receive(&mut c_rx.stream));
+    sent.unwrap();
+    assert_eq!(received, b"again");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mutual_tls_authorizes_streams() {
+    let server = identity(&[SERVER_NAME]);
+    let (allowed, denied) = (identity(&["client-a"]), identity(&["client-b"]));
+    let mut tls = server_tls(&server);
+    tls.client_auth = ClientAuth::Required(roots(&[&allowed, &denied]));
+    let (endpoint, rx) = QuicEndpoint::new(MARGINS);
+    endpoint.set_tls(Some(tls)).unwrap();
+    endpoint.set_authorizer(Some(Arc::new(|_, identity: Option<&PeerIdentity>| {
+        identity.is_some_and(|identity| identity.sans == ["client-a"])
+    })));
+    let net = Net::default();
+    let (server_endpoint, mut server_rx) = net.attach("127.0.0.1:4433", endpoint, rx);
+
+    let mut tls = client_tls(&[&server]);
+    tls.identity = Some(allowed);
+    let (client, _client_rx) = net.join(CLIENT, tls);
+    let (sent, received) = tokio::join!(send(&client, "127.0.0.1:4433", b"allowed"), receive(&mut server_rx.stream));
+    sent.unwrap();
+    assert_eq!(received, b"allowed");
+    let peer = server_endpoint.peer_identity(addr(CLIENT)).unwrap();
+    assert_eq!(peer.sans, ["client-a"]);
+
+    // 证书有效但授权函数拒绝：流被 STREAM_UNAUTHORIZED 停止并重置，不会交给服务端
+    let mut tls = client_tls(&[&server]);
+    tls.identity = Some(denied);
+    let (client, _client_rx) = net.join("127.0.0.1:10001", tls);
+    let e = send(&client, "127.0.0.1:4433", b"denied").await.unwrap_err();
+    assert!(
+        matches!(
+            QuicError::of(&e),
+            Some(QuicError::StreamReset(code) | QuicError::StreamStopped(code)) if *code == STREAM_UNAUTHORIZED
+        ),
+        "{e:?}"
+    );
+    assert!(timeout(Duration::from_millis(200), server_rx.stream.recv()).await.is_err());
+
+    // 没有客户端证书时，客户端一侧的握手虽已完成，服务端随即关闭连接
+    let (client, _client_rx) = net.join("127.0.0.1:10002", client_tls(&[&server]));
+    let e = send(&client, "127.0.0.1:4433", b"anonymous").await.unwrap_err();
+    assert!(matches!(QuicError::of(&e), Some(QuicError::ConnectionLost(_))), "{e:?}");
+}
+
+/// Certificate a client asking for `name` is served by the server at 4433, connecting from
+/// `at` and checking the SNI the server saw.
+async fn served(net: &Net, server: &QuicEndpoint, at: &str, name: &str, trusted: &[&Identity]) -> Vec<u8> {
+    let mut tls = client_tls(trusted);
+    tls.server_name = Some(name.into());
+    let (client, _client_rx) = net.join(at, tls);
+    connect(&client, "127.0.0.1:4433").await.unwrap();
+    assert_eq!(server.server_name(addr(at)).as_deref(), Some(name));
+    let peer = client.peer_identity(addr("127.0.0.1:4433")).unwrap();
+    peer.cert_chain[0].to_vec()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn server_names_pick_certificates() {
+    let exact = identity(&[SERVER_NAME]);
+    let wildcard = identity(&["*.wild.test"]);
+    let default = identity(&["other.test", "a.b.wild.test"]);
+    let certs = ServerCerts::default();
+    certs.insert(Some("GW.test"), &exact).unwrap();
+    certs.insert(Some("*.wild.test"), &wildcard).unwrap();
+    certs.insert(None, &default).unwrap();
+    let mut tls = TlsConfig::new(None, RootCertStore::empty());
+    tls.server_certs = Some(certs);
+    let net = Net::default();
+    let (server, _server_rx) = net.join("127.0.0.1:4433", tls);
+
+    let trusted = [&exact, &wildcard, &default];
+    let cert = |identity: &Identity| identity.cert_chain[0].to_vec();
+    assert_eq!(served(&net, &server, "127.0.0.1:10000", SERVER_NAME, &trusted).await, cert(&exact));
+    assert_eq!(served(&net, &server, "127.0.0.1:10001", "a.wild.test", &trusted).await, cert(&wildcard));
+    // 通配符只代替一级标签
+    assert_eq!(served(&net, &server, "127.0.0.1:10002", "a.b.wild.test", &trusted).await, cert(&default));
+    assert_eq!(served(&net, &server, "127.0.0.1:10003", "other.test", &trusted).await, cert(&default));
+}
+
+/// Fresh directory for the PEM files of test `name`.
+fn pem_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("qs-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes a new self-signed certificate for [`SERVER_NAME`] and its key to `dir`.
+fn write_pem(dir: &Path) -> Identity {
+    let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).unwrap();
+    fs::write(dir.join("cert.pem"), certified.cert.pem()).unwrap();
+    fs::write(dir.join("key.pem"), certified.signing_key.serialize_pem()).unwrap();
+    Identity::from_pem_files(dir.join("cert.pem"), dir.join("key.pem")).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn server_certs_reload_from_files() {
+    let dir = pem_dir("reload");
+    let first = write_pem(&dir);
+    let certs = ServerCerts::default();
+    certs.load(None, dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+    let mut tls = TlsConfig::new(None, RootCertStore::empty());
+    tls.server_certs = Some(certs.clone());
+    let net = Net::default();
+    let (server, _server_rx) = net.join("127.0.0.1:4433", tls);
+    let cert = |identity: &Identity| identity.cert_chain[0].to_vec();
+
+    // 读不出的文件不影响正在使用的证书
+    fs::write(dir.join("cert.pem"), "not a certificate").unwrap();
+    certs.reload().unwrap_err();
+    let served_now = served(&net, &server, "127.0.0.1:10000", SERVER_NAME, &[&first]).await;
+    assert_eq!(served_now, cert(&first));
+
+    let second = write_pem(&dir);
+    certs.reload().unwrap();
+    let served_now = served(&net, &server, "127.0.0.1:10001", SERVER_NAME, &[&second]).await;
+    assert_eq!(served_now, cert(&second));
+
+    // watch 发现文件变化后重新加载
+    let watch = certs.watch(Duration::from_millis(20));
+    let third = write_pem(&dir);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let served_now = served(&net, &server, "127.0.0.1:10002", SERVER_NAME, &[&second, &third]).await;
+    assert_eq!(served_now, cert(&third));
+    watch.abort();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// A client offering only `protocol`, at `at`.
+fn offering(net: &Net, at: &str, server: &Identity, protocol: &[u8]) -> Arc<QuicEndpoint> {
+    let mut tls = client_tls(&[server]);
+    tls.alpn = vec![protocol.to_vec()];
+    net.join(at, tls).0
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn alpn_routes_streams() {
+    let server = identity(&[SERVER_NAME]);
+    let mut tls = server_tls(&server);
+    tls.alpn = vec![b"gw".to_vec(), b"h3".to_vec()];
+    let net = Net::default();
+    let (endpoint, mut server_rx) = net.join("127.0.0.1:4433", tls);
+    let mut gw_rx = endpoint.route("gw");
+
+    let client = offering(&net, "127.0.0.1:10000", &server, b"gw");
+    let (sent, received) = tokio::join!(send(&client, "127.0.0.1:4433", b"gw"), receive(&mut gw_rx));
+    sent.unwrap();
+    assert_eq!(received, b"gw");
+    assert_eq!(endpoint.alpn(addr("127.0.0.1:10000")).as_deref(), Some(&b"gw"[..]));
+    assert_eq!(client.alpn(addr("127.0.0.1:4433")).as_deref(), Some(&b"gw"[..]));
+
+    // 未路由的协议仍交给默认接收端
+    let client = offering(&net, "127.0.0.1:10001", &server, b"h3");
+    let (sent, received) = tokio::join!(send(&client, "127.0.0.1:4433", b"h3"), receive(&mut server_rx.stream));
+    sent.unwrap();
+    assert_eq!(received, b"h3");
+    assert!(timeout(Duration::from_millis(200), gw_rx.recv()).await.is_err());
+
+    // 没有共同协议的客户端被拒绝
+    let client = offering(&net, "127.0.0.1:10002", &server, b"smtp");
+    let e = connect(&client, "127.0.0.1:4433").await.unwrap_err();
+    assert!(refused(&e), "{e:?}");
+}
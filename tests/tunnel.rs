@@ -0,0 +1,101 @@
+mod common;
+
+use common::{addr, handshake, pair, CLIENT, SERVER};
+use qs::gateway::device::{MemoryDevice, PacketDevice};
+use qs::gateway::forward::{Gateway, IpPrefix, IpRoutes};
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// A 40-byte IPv4 packet from `src` to `dst`, enough for the tunnel to route it.
+fn packet(src: [u8; 4], dst: [u8; 4]) -> Vec<u8> {
+    let mut packet = vec![0x45; 40];
+    packet[12..16].copy_from_slice(&src);
+    packet[16..20].copy_from_slice(&dst);
+    packet
+}
+
+#[test]
+fn prefix_masks_host_bits() {
+    let a: IpPrefix = "10.0.0.1/8".parse().unwrap();
+    let b: IpPrefix = "10.0.0.0/8".parse().unwrap();
+    assert_eq!(a, b);
+    assert!(a.contains("10.255.0.1".parse().unwrap()));
+    assert!(!a.contains("11.0.0.1".parse().unwrap()));
+
+    let mut routes = IpRoutes::default();
+    routes.add(a, addr(SERVER));
+    routes.remove(b);
+    assert_eq!(routes.lookup("10.0.0.5".parse().unwrap()), None);
+
+    let v6: IpPrefix = "fd00::1/64".parse().unwrap();
+    assert_eq!(v6, "fd00::/64".parse().unwrap());
+    assert!("10.0.0.0/33".parse::<IpPrefix>().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn memory_devices() {
+    let (server, server_rx, client, client_rx) = pair();
+    let server_gateway = Gateway::new(server);
+    let client_gateway = Gateway::new(client.clone());
+    let g = server_gateway.clone();
+    tokio::spawn(async move { g.run_datagrams(server_rx.datagram).await });
+    let g = client_gateway.clone();
+    tokio::spawn(async move { g.run_datagrams(client_rx.datagram).await });
+    handshake(&client).await;
+
+    let (client_dev, client_host) = MemoryDevice::pair(16);
+    let (server_dev, server_host) = MemoryDevice::pair(16);
+    let mut client_routes = IpRoutes::default();
+    client_routes.add("10.0.0.0/8".parse().unwrap(), addr(SERVER));
+    let mut server_routes = IpRoutes::default();
+    server_routes.add("10.1.0.2/32".parse().unwrap(), addr(CLIENT));
+    let g = client_gateway.clone();
+    tokio::spawn(async move { g.tunnel(client_dev, client_routes).await });
+    let g = server_gateway.clone();
+    tokio::spawn(async move { g.tunnel(server_dev, server_routes).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut buf = vec![0u8; 2048];
+    let request = packet([10, 1, 0, 2], [10, 0, 0, 5]);
+    client_host.write(&request).await.unwrap();
+    let n = timeout(Duration::from_secs(2), server_host.read(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(&buf[..n], &request[..]);
+
+    let reply = packet([10, 0, 0, 5], [10, 1, 0, 2]);
+    server_host.write(&reply).await.unwrap();
+    let n = timeout(Duration::from_secs(2), client_host.read(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(&buf[..n], &reply[..]);
+
+    // 源地址不回指发送方的包被丢弃
+    client_host.write(&packet([10, 1, 0, 3], [10, 0, 0, 5])).await.unwrap();
+    assert!(timeout(Duration::from_millis(200), server_host.read(&mut buf)).await.is_err());
+
+    // 已有隧道运行时再开一个会失败，且不影响原隧道
+    let (other, _other_host) = MemoryDevice::pair(16);
+    let err = client_gateway.tunnel(other, IpRoutes::default()).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AddrInUse);
+    server_host.write(&reply).await.unwrap();
+    let n = timeout(Duration::from_secs(2), client_host.read(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(&buf[..n], &reply[..]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tunnel_can_restart() {
+    let (_server, _server_rx, client, _client_rx) = pair();
+    let gateway = Gateway::new(client);
+    let (dev, host) = MemoryDevice::pair(16);
+    let g = gateway.clone();
+    let first = tokio::spawn(async move { g.tunnel(dev, IpRoutes::default()).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // 设备对端关闭后隧道结束，登记随之清除
+    drop(host);
+    let _ = timeout(Duration::from_secs(2), first).await.unwrap().unwrap();
+
+    let (dev, _host) = MemoryDevice::pair(16);
+    let g = gateway.clone();
+    let second = tokio::spawn(async move { g.tunnel(dev, IpRoutes::default()).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!second.is_finished());
+    second.abort();
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use common::{addr, handshake, link, CLIENT, SERVER};
+use qs::gateway::quic::local::LocalQuicEndpoint;
+use qs::gateway::quic::{ConnectionStats, QuicEndpoint, QuicPacketMargins};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+const MARGINS: QuicPacketMargins = QuicPacketMargins { header: 0, trailer: 0 };
+const STREAM_WINDOW: u32 = 64 << 10;
+
+/// Bytes the server received once the sender stalled on a stream nobody reads.
+async fn received(stats: impl Fn() -> ConnectionStats) -> u64 {
+    let mut last = 0;
+    loop {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let bytes = stats().udp_rx.bytes;
+        if bytes == last {
+            return bytes;
+        }
+        last = bytes;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_receive_window() {
+    let (server, server_rx) = QuicEndpoint::new(MARGINS);
+    server.set_stream_receive_window(STREAM_WINDOW);
+    let (client, client_rx) = QuicEndpoint::new(MARGINS);
+    let (server, mut server_rx, client, _client_rx) = link(server.into(), server_rx, client.into(), client_rx);
+    handshake(&client).await;
+
+    let mut stream = client.open(addr(SERVER), None).await.unwrap();
+    tokio::spawn(async move { stream.write_all(&vec![7u8; 4 << 20]).await });
+    let peer = server_rx.stream.recv().await.unwrap();
+    let stats = || server.conn_stats(addr(CLIENT)).unwrap();
+
+    // 默认读出 256 KiB，加上 quinn-proto 的流窗口
+    let before = received(stats).await;
+    assert!((320 << 10..400 << 10).contains(&before), "{before}");
+
+    peer.set_receive_window(1 << 20);
+    let after = received(stats).await;
+    assert!((768 << 10..900 << 10).contains(&(after - before)), "{}", after - before);
+}
+
+#[test]
+fn local_stream_receive_window() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    tokio::task::LocalSet::new().block_on(&rt, async {
+        let (server, mut server_rx) = LocalQuicEndpoint::new(MARGINS);
+        server.set_stream_receive_window(STREAM_WINDOW);
+        let (client, mut client_rx) = LocalQuicEndpoint::new(MARGINS);
+        let (server, client) = (Rc::new(server), Rc::new(client));
+        let (to, mut server_packets) = (server.clone(), std::mem::replace(&mut server_rx.packet, mpsc::channel(1).1));
+        tokio::task::spawn_local(async move {
+            while let Some(packet) = client_rx.packet.recv().await {
+                let _ = to.send(addr(CLIENT), packet.payload).await;
+            }
+        });
+        let to = client.clone();
+        tokio::task::spawn_local(async move {
+            while let Some(packet) = server_packets.recv().await {
+                let _ = to.send(addr(SERVER), packet.payload).await;
+            }
+        });
+
+        let mut stream = client.open(addr(SERVER), None).await.unwrap();
+        tokio::task::spawn_local(async move { stream.write_all(&vec![7u8; 4 << 20]).await });
+        let peer = server_rx.stream.recv().await.unwrap();
+        let stats = || server.conn_stats(addr(CLIENT)).unwrap();
+
+        // 本地流默认不预读，只有 quinn-proto 的流窗口
+        let before = received(stats).await;
+        assert!((64 << 10..128 << 10).contains(&before), "{before}");
+
+        peer.set_receive_window(256 << 10);
+        let after = received(stats).await;
+        assert!((256 << 10..320 << 10).contains(&(after - before)), "{}", after - before);
+    });
+}